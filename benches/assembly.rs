@@ -0,0 +1,115 @@
+//! Image assembly timings: planning a layout and streaming a batch of device
+//! images from it through SHA-256, as `batch` does without the file writes.
+//! Each case runs with the shared firmware data and with a copy of every
+//! region's data, as planning did before regions held `Arc<[u8]>`.
+//! Run with `cargo bench --bench assembly`.
+
+use esp32_image_composer_rs::batch::{BatchBuilder, parse_devices};
+use esp32_image_composer_rs::image::layout::{FlashLayout, Region};
+use esp32_image_composer_rs::{Config, FirmwareBinary, ImageBuilder, PartitionGenerator, Result};
+use sha2::{Digest, Sha256};
+use std::hint::black_box;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const APP_SIZE: usize = 1536 * 1024;
+const LAYOUTS: u32 = 50;
+const DEVICES: usize = 200;
+
+fn firmware(name: &str, size: usize, prefix: u32) -> FirmwareBinary {
+    let mut data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+    data[0] = 0xE9;
+    FirmwareBinary::new(
+        name.to_string(),
+        PathBuf::from(format!("{}.bin", name)),
+        data,
+        prefix,
+    )
+}
+
+/// The layout with every region owning a copy of its data, like the
+/// `firmware.data.clone()` placement and layout clones used to
+fn deep_copy(layout: &FlashLayout) -> Result<FlashLayout> {
+    let mut copy = FlashLayout::new();
+    for region in layout.regions() {
+        copy.push(Region {
+            data: Arc::from(&region.data[..]),
+            ..region.clone()
+        })?;
+    }
+    Ok(copy)
+}
+
+/// Hashes what is written, like the batch writer over a sink
+struct Hashed(Sha256);
+
+impl Write for Hashed {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn report(name: &str, elapsed: Duration, runs: u32) {
+    println!(
+        "{:<24} {:>9.2?} total {:>9.2?} per run ({} runs)",
+        name,
+        elapsed,
+        elapsed / runs,
+        runs
+    );
+}
+
+fn main() -> Result<()> {
+    let firmwares = vec![
+        firmware("bootloader", 32 * 1024, 1),
+        firmware("factory_app", APP_SIZE, 2),
+        firmware("ota_app_a", APP_SIZE, 3),
+        firmware("ota_app_b", APP_SIZE, 4),
+    ];
+    let config = Config::default();
+    let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+
+    let start = Instant::now();
+    for _ in 0..LAYOUTS {
+        black_box(ImageBuilder::plan_layout(&firmwares, &table, &config)?);
+    }
+    report("plan_layout shared", start.elapsed(), LAYOUTS);
+
+    let start = Instant::now();
+    for _ in 0..LAYOUTS {
+        let layout = ImageBuilder::plan_layout(&firmwares, &table, &config)?;
+        black_box(deep_copy(&layout)?);
+    }
+    report("plan_layout copied", start.elapsed(), LAYOUTS);
+
+    let template = "device,namespace,,\nserial,data,string,${serial}\n".to_string();
+    let builder = BatchBuilder::new(&firmwares, &table, &config, template)?;
+    let rows: String = (0..DEVICES)
+        .map(|i| format!("unit-{i},SN-{i:06}\n"))
+        .collect();
+    let devices = parse_devices(&format!("id,serial\n{rows}"))?;
+
+    let start = Instant::now();
+    for device in &devices {
+        black_box(builder.write_device(device, &mut std::io::sink())?);
+    }
+    report("device images shared", start.elapsed(), DEVICES as u32);
+
+    let start = Instant::now();
+    for device in &devices {
+        let layout = deep_copy(&builder.device_layout(device)?)?;
+        let mut writer = Hashed(Sha256::new());
+        layout.write_to(&mut writer, None)?;
+        black_box(writer.0.finalize());
+    }
+    report("device images copied", start.elapsed(), DEVICES as u32);
+
+    Ok(())
+}
@@ -0,0 +1,326 @@
+//! Factory batches: one image per device, sharing a layout and differing only in NVS data
+
+use crate::Result;
+use crate::config::Config;
+use crate::firmware::FirmwareBinary;
+use crate::image::ImageBuilder;
+use crate::image::layout::{FlashLayout, Region, RegionRole, RegionSource};
+use crate::nvs::{self, crypt::NvsKeys};
+use crate::util::hex;
+use anyhow::anyhow;
+use esp_idf_part::PartitionTable;
+use log::info;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// File listing every image of a batch, written to the output directory
+pub const INDEX_FILE: &str = "index.csv";
+
+/// One row of the devices CSV; the first column is the device ID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    pub id: String,
+    /// Every column of the row by header name, including the ID column
+    pub values: HashMap<String, String>,
+}
+
+pub fn load_devices<P: AsRef<Path>>(path: P) -> Result<Vec<Device>> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read devices CSV {:?}: {}", path, e))?;
+    parse_devices(&text).map_err(|e| anyhow!("Devices CSV {:?}: {}", path, e))
+}
+
+/// Parse a devices CSV with a header row naming the template columns
+pub fn parse_devices(text: &str) -> Result<Vec<Device>> {
+    let mut rows = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'));
+
+    let fields = |index: usize, line: &str| -> Result<Vec<String>> {
+        let fields =
+            nvs::csv::split_fields(line).map_err(|e| anyhow!("Line {}: {}", index + 1, e))?;
+        Ok(fields.iter().map(|f| f.trim().to_string()).collect())
+    };
+
+    let (index, header) = rows.next().ok_or_else(|| anyhow!("no header row"))?;
+    let columns = fields(index, header)?;
+    if let Some(column) = columns.iter().find(|c| c.is_empty()) {
+        return Err(anyhow!(
+            "Line {}: empty column name {:?}",
+            index + 1,
+            column
+        ));
+    }
+
+    let mut devices = Vec::new();
+    let mut ids = HashSet::new();
+    for (index, line) in rows {
+        let values = fields(index, line)?;
+        if values.len() != columns.len() {
+            return Err(anyhow!(
+                "Line {}: expected {} columns, found {}",
+                index + 1,
+                columns.len(),
+                values.len()
+            ));
+        }
+
+        let id = values[0].clone();
+        if id.is_empty()
+            || !id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        {
+            return Err(anyhow!(
+                "Line {}: device ID '{}' must be non-empty and use only A-Z, a-z, 0-9, '.', '_' and '-'",
+                index + 1,
+                id
+            ));
+        }
+        if !ids.insert(id.clone()) {
+            return Err(anyhow!("Line {}: duplicate device ID '{}'", index + 1, id));
+        }
+
+        devices.push(Device {
+            id,
+            values: columns.iter().cloned().zip(values).collect(),
+        });
+    }
+
+    Ok(devices)
+}
+
+/// Image written for one device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchImage {
+    pub device_id: String,
+    pub file: PathBuf,
+    pub sha256: String,
+}
+
+/// Places the shared part of the image once and adds the NVS partition per device
+pub struct BatchBuilder<'a> {
+    partition_table: &'a PartitionTable,
+    base_layout: FlashLayout,
+    pad_to: Option<u32>,
+    template: String,
+    keys: Option<NvsKeys>,
+}
+
+impl<'a> BatchBuilder<'a> {
+    /// `template` is an NVS CSV whose fields may hold `${column}` placeholders
+    pub fn new(
+        firmwares: &[FirmwareBinary],
+        partition_table: &'a PartitionTable,
+        config: &Config,
+        template: String,
+    ) -> Result<Self> {
+        if config.nvs_csv.is_some() {
+            return Err(anyhow!(
+                "--nvs can't be combined with a batch; put the NVS data in the template"
+            ));
+        }
+
+        let base_layout = ImageBuilder::plan_layout(firmwares, partition_table, config)?;
+        let keys = match &config.nvs_keys {
+            Some(path) => Some(NvsKeys::load_from_file(path)?),
+            None => None,
+        };
+
+        Ok(Self {
+            partition_table,
+            base_layout,
+            pad_to: ImageBuilder::pad_to(config),
+            template,
+            keys,
+        })
+    }
+
+    pub fn load_template<P: AsRef<Path>>(path: P) -> Result<String> {
+        let path = path.as_ref();
+        std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read NVS template {:?}: {}", path, e))
+    }
+
+    /// Layout of one device's image: the shared regions plus its NVS partition
+    pub fn device_layout(&self, device: &Device) -> Result<FlashLayout> {
+        let items = nvs::csv::parse_template(&self.template, &device.values)
+            .map_err(|e| anyhow!("Device '{}': NVS template: {}", device.id, e))?;
+        let (name, offset, data) =
+            ImageBuilder::nvs_partition(self.partition_table, &items, self.keys.as_ref())
+                .map_err(|e| anyhow!("Device '{}': {}", device.id, e))?;
+
+        let mut layout = self.base_layout.clone();
+        layout
+            .push(Region {
+                offset,
+                data: data.into(),
+                erased: 0,
+                role: RegionRole::Data,
+                name,
+                source: RegionSource::Generated,
+            })
+            .map_err(|e| anyhow!("Device '{}': {}", device.id, e))?;
+        Ok(layout)
+    }
+
+    /// Full flash image for one device
+    pub fn build_device(&self, device: &Device) -> Result<Vec<u8>> {
+        let mut image = Vec::new();
+        self.device_layout(device)?
+            .write_to(&mut image, self.pad_to)?;
+        Ok(image)
+    }
+
+    /// Stream one device's image to `writer`, returning its length and SHA-256
+    pub fn write_device<W: Write>(&self, device: &Device, writer: &mut W) -> Result<(u64, String)> {
+        let layout = self.device_layout(device)?;
+
+        // Hash while streaming so no device image is ever held whole
+        let mut writer = HashingWriter {
+            inner: writer,
+            hasher: Sha256::new(),
+        };
+        let len = layout.write_to(&mut writer, self.pad_to)?;
+        writer
+            .inner
+            .flush()
+            .map_err(|e| anyhow!("Failed to write image of device '{}': {}", device.id, e))?;
+        Ok((len, hex(&writer.hasher.finalize())))
+    }
+
+    /// Write `<device id>.bin` for every device and the index file
+    pub fn write_all(&self, devices: &[Device], output_dir: &Path) -> Result<Vec<BatchImage>> {
+        std::fs::create_dir_all(output_dir)
+            .map_err(|e| anyhow!("Failed to create {:?}: {}", output_dir, e))?;
+
+        let mut images = Vec::new();
+        for device in devices {
+            let file = PathBuf::from(format!("{}.bin", device.id));
+            let path = output_dir.join(&file);
+            let output = std::fs::File::create(&path)
+                .map_err(|e| anyhow!("Failed to create {:?}: {}", path, e))?;
+
+            let (len, sha256) = self.write_device(device, &mut BufWriter::new(output))?;
+            info!("Wrote {:?} ({} bytes)", path, len);
+
+            images.push(BatchImage {
+                device_id: device.id.clone(),
+                file,
+                sha256,
+            });
+        }
+
+        write_index(&images, &output_dir.join(INDEX_FILE))?;
+        Ok(images)
+    }
+}
+
+/// `device_id,file,sha256`, one row per image
+pub fn write_index(images: &[BatchImage], path: &Path) -> Result<()> {
+    let mut index = String::from("device_id,file,sha256\n");
+    for image in images {
+        index.push_str(&format!(
+            "{},{},{}\n",
+            image.device_id,
+            image.file.display(),
+            image.sha256
+        ));
+    }
+    std::fs::write(path, index).map_err(|e| anyhow!("Failed to write {:?}: {}", path, e))
+}
+
+/// Passes writes through while hashing them
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partition::PartitionGenerator;
+    use crate::test_support::create_test_firmware;
+    use crate::util::sha256_hex;
+
+    #[test]
+    fn test_parse_devices() -> Result<()> {
+        let devices =
+            parse_devices("device_id,serial\n# line 2\nunit-1,\"SN 1, A\"\nunit-2,SN2\n")?;
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].id, "unit-1");
+        assert_eq!(devices[0].values["serial"], "SN 1, A");
+        assert_eq!(devices[1].values["device_id"], "unit-2");
+
+        assert!(parse_devices("id,serial\nunit-1,a\nunit-1,b\n").is_err());
+        assert!(parse_devices("id,serial\n../escape,a\n").is_err());
+        assert!(parse_devices("id,serial\nunit-1\n").is_err());
+        assert!(parse_devices("").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_images_differ_only_in_nvs() -> Result<()> {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 100 * 1024, 2),
+        ];
+        let config = Config::default();
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+        let template = "device,namespace,,\nserial,data,string,${serial}\n".to_string();
+        let builder = BatchBuilder::new(&firmwares, &table, &config, template)?;
+
+        let devices = parse_devices("id,serial\nunit-1,SN-0001\nunit-2,SN-0002\n")?;
+        let temp_dir = tempfile::TempDir::new()?;
+        let images = builder.write_all(&devices, temp_dir.path())?;
+        assert_eq!(images.len(), 2);
+        assert_ne!(images[0].sha256, images[1].sha256);
+
+        let first = std::fs::read(temp_dir.path().join("unit-1.bin"))?;
+        let second = std::fs::read(temp_dir.path().join("unit-2.bin"))?;
+        assert_eq!(images[0].sha256, sha256_hex(&first));
+        assert_eq!(
+            builder.write_device(&devices[0], &mut std::io::sink())?,
+            (first.len() as u64, images[0].sha256.clone())
+        );
+        assert_eq!(first.len(), second.len());
+
+        let nvs = table.find("nvs").unwrap();
+        let range = nvs.offset() as usize..(nvs.offset() + nvs.size()) as usize;
+        let differing: Vec<usize> = (0..first.len())
+            .filter(|&i| first[i] != second[i])
+            .collect();
+        assert!(!differing.is_empty());
+        assert!(differing.iter().all(|i| range.contains(i)));
+
+        let entries = nvs::dump::decode(&second[range]);
+        assert!(entries.iter().flat_map(|p| &p.entries).any(|e| {
+            e.key == "serial" && e.value == nvs::dump::DumpValue::Text("SN-0002".to_string())
+        }));
+
+        let index = std::fs::read_to_string(temp_dir.path().join(INDEX_FILE))?;
+        assert_eq!(
+            index.lines().nth(2),
+            Some(format!("unit-2,unit-2.bin,{}", images[1].sha256).as_str())
+        );
+
+        Ok(())
+    }
+}
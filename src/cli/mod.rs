@@ -18,13 +18,109 @@ pub struct Args {
     pub output: PathBuf,
 
     /// Flash size
-    #[arg(long, default_value = "16MB", value_parser = ["8MB", "16MB", "32MB"])]
+    #[arg(long, default_value = "16MB", value_parser = ["8MB", "16MB", "32MB", "64MB", "128MB"])]
     pub flash_size: String,
 
     /// Maximum number of OTA partitions to create
     #[arg(long, default_value = "16")]
     pub max_ota_partitions: usize,
 
+    /// Number of empty OTA slots to reserve after the populated ones
+    #[arg(long, default_value = "0")]
+    pub extra_ota_slots: usize,
+
+    /// Size of each reserved empty OTA slot (e.g. 2MB, 0x200000)
+    #[arg(long, default_value = "4MB", value_parser = parse_size_arg)]
+    pub extra_ota_size: u32,
+
+    /// Grow a data partition over all remaining flash (name:subtype, e.g. storage:littlefs)
+    #[arg(long, value_name = "NAME:SUBTYPE")]
+    pub fill_partition: Option<crate::config::FillPartition>,
+
+    /// Partition table offset (CONFIG_PARTITION_TABLE_OFFSET); NVS, otadata and apps follow it
+    #[arg(long, default_value = "0x10000", value_parser = parse_size_arg)]
+    pub partition_table_offset: u32,
+
+    /// Omit the MD5 row from the partition table (for bootloaders older than ESP-IDF 4.x)
+    #[arg(long)]
+    pub no_partition_table_md5: bool,
+
+    /// Layout preset: factory-ota, ab, launcher or minimal
+    #[arg(long, default_value = "factory-ota")]
+    pub layout: crate::config::LayoutPreset,
+
+    /// Placement of unpinned partitions: sequential (stable) or packed (fills alignment gaps)
+    #[arg(long, default_value = "sequential")]
+    pub allocation: crate::config::AllocationStrategy,
+
+    /// JSON file with additional partitions (custom types/subtypes, raw content files)
+    #[arg(long, value_name = "FILE")]
+    pub partitions: Option<PathBuf>,
+
+    /// Lock file recording the partition layout; reused by later builds when present
+    #[arg(long, default_value = "composer.lock", value_name = "FILE")]
+    pub lock_file: PathBuf,
+
+    /// Ignore the lock file and regenerate the layout from scratch
+    #[arg(long)]
+    pub relayout: bool,
+
+    /// Write otadata so the image boots this slot first (factory or ota_N)
+    #[arg(long, value_name = "SLOT")]
+    pub boot_slot: Option<crate::otadata::BootSlot>,
+
+    /// Rollback state for --boot-slot: new, pending_verify, valid, invalid, aborted or undefined
+    #[arg(long, default_value = "undefined", requires = "boot_slot")]
+    pub boot_state: crate::otadata::OtaImageState,
+
+    /// NVS CSV (key,type,encoding,value) to generate the nvs partition from
+    #[arg(long, value_name = "CSV")]
+    pub nvs: Option<PathBuf>,
+
+    /// NVS encryption keys (nvs_keys partition image or 64 raw bytes); encrypts the NVS data
+    #[arg(long, value_name = "FILE")]
+    pub nvs_keys: Option<PathBuf>,
+
+    /// Directory packed into a SPIFFS image in the spiffs partition
+    #[arg(long, value_name = "DIR")]
+    pub spiffs: Option<PathBuf>,
+
+    /// SPIFFS page size (CONFIG_SPIFFS_PAGE_SIZE)
+    #[arg(long, default_value = "256", value_parser = parse_size_arg)]
+    pub spiffs_page_size: u32,
+
+    /// SPIFFS block size
+    #[arg(long, default_value = "4096", value_parser = parse_size_arg)]
+    pub spiffs_block_size: u32,
+
+    /// SPIFFS object name length including the terminator (CONFIG_SPIFFS_OBJ_NAME_LEN)
+    #[arg(long, default_value = "32")]
+    pub spiffs_obj_name_len: usize,
+
+    /// SPIFFS metadata length (CONFIG_SPIFFS_META_LENGTH)
+    #[arg(long, default_value = "4")]
+    pub spiffs_meta_len: usize,
+
+    /// Directory packed into a LittleFS image in the littlefs partition
+    #[arg(long, value_name = "DIR")]
+    pub littlefs: Option<PathBuf>,
+
+    /// Longest LittleFS file name (CONFIG_LITTLEFS_OBJ_NAME_LEN)
+    #[arg(long, default_value = "64")]
+    pub littlefs_name_max: u32,
+
+    /// LittleFS read/program size (CONFIG_LITTLEFS_PAGE_SIZE)
+    #[arg(long, default_value = "256", value_parser = parse_size_arg)]
+    pub littlefs_page_size: u32,
+
+    /// Directory packed into a FAT12/16 image in the fat partition
+    #[arg(long, value_name = "DIR")]
+    pub fat: Option<PathBuf>,
+
+    /// Wrap the FAT image in ESP-IDF wear levelling (for esp_vfs_fat_spiflash_mount_rw_wl)
+    #[arg(long)]
+    pub fat_wear_levelling: bool,
+
     /// Enable verbose logging
     #[arg(short, long)]
     pub verbose: bool,
@@ -49,6 +145,10 @@ pub enum Commands {
         /// Export as CSV format instead of binary
         #[arg(long)]
         csv: bool,
+
+        /// Generate a firmware-less template table for the layout instead of loading firmwares
+        #[arg(long)]
+        template: bool,
     },
     /// Validate firmware files and show partition layout
     Validate {
@@ -63,6 +163,20 @@ pub enum Commands {
         show_sizes: bool,
     },
 
+    /// Convert a partition table between CSV, binary and JSON (lock file format)
+    Convert {
+        /// Input: CSV, JSON lock file, binary table or flash image
+        input: PathBuf,
+
+        /// Output file; the table is printed to stdout when omitted
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Output format (default: from the output extension, otherwise csv)
+        #[arg(long, value_parser = ["csv", "bin", "json"])]
+        format: Option<String>,
+    },
+
     /// Inspect and analyze generated flash images
     Inspect {
         /// Flash image file to analyze
@@ -76,6 +190,65 @@ pub enum Commands {
         #[arg(long)]
         verify_checksums: bool,
     },
+
+    /// Build one image per device from an NVS template and a devices CSV
+    Batch {
+        /// Devices CSV with a header row; the first column is the device ID
+        #[arg(long, value_name = "CSV")]
+        devices: PathBuf,
+
+        /// NVS CSV whose fields may use ${column} placeholders from the devices CSV
+        #[arg(long, value_name = "CSV")]
+        template: PathBuf,
+
+        /// Directory for the images and their index.csv
+        #[arg(long, default_value = "batch")]
+        output_dir: PathBuf,
+    },
+
+    /// Decode the NVS partition of a flash image or flash dump
+    NvsDump {
+        /// Flash image or dump containing a partition table
+        image_file: PathBuf,
+
+        /// Name of the NVS partition to decode
+        #[arg(long, default_value = "nvs")]
+        partition: String,
+
+        /// NVS encryption keys to decrypt the partition with
+        #[arg(long, value_name = "FILE")]
+        keys: Option<PathBuf>,
+
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the files in the SPIFFS, LittleFS and FAT partitions of a flash image or flash dump
+    FsList {
+        /// Flash image or dump containing a partition table
+        image_file: PathBuf,
+
+        /// Only list this partition (default: every filesystem partition)
+        #[arg(long)]
+        partition: Option<String>,
+
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Extract the files in the SPIFFS, LittleFS and FAT partitions of a flash image or flash dump
+    FsExtract {
+        /// Flash image or dump containing a partition table
+        image_file: PathBuf,
+
+        /// Only extract this partition (default: every filesystem partition)
+        #[arg(long)]
+        partition: Option<String>,
+
+        /// Directory receiving one subdirectory per partition
+        #[arg(long, default_value = "extracted")]
+        output_dir: PathBuf,
+    },
 }
 
 impl Args {
@@ -84,7 +257,13 @@ impl Args {
             "8MB" => crate::config::FlashSize::Size8MB,
             "16MB" => crate::config::FlashSize::Size16MB,
             "32MB" => crate::config::FlashSize::Size32MB,
+            "64MB" => crate::config::FlashSize::Size64MB,
+            "128MB" => crate::config::FlashSize::Size128MB,
             _ => crate::config::FlashSize::Size16MB,
         }
     }
 }
+
+fn parse_size_arg(value: &str) -> Result<u32, String> {
+    crate::config::parse_size(value).map_err(|e| e.to_string())
+}
@@ -1,5 +1,11 @@
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use crate::filesystem::{fat::FatConfig, littlefs::LittlefsConfig, spiffs::SpiffsConfig};
+use crate::lock::LayoutLock;
+use crate::otadata::{BootSlot, OtaImageState};
+use anyhow::anyhow;
+use esp_idf_part::{AppType, DataType, Flags, SubType, Type};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -7,6 +13,34 @@ pub struct Config {
     pub firmware_dir: PathBuf,
     pub output_file: PathBuf,
     pub max_ota_partitions: usize,
+    pub extra_ota_slots: usize,
+    pub extra_ota_size: u32,
+    pub fill_partition: Option<FillPartition>,
+    pub partition_table_offset: u32,
+    /// Append the MD5 row (CONFIG_PARTITION_TABLE_MD5); pre-4.x bootloaders can't parse it
+    pub partition_table_md5: bool,
+    pub layout: LayoutPreset,
+    pub allocation: AllocationStrategy,
+    pub partitions: Vec<PartitionSpec>,
+    /// Layout from a previous build to keep partitions in place
+    pub layout_lock: Option<LayoutLock>,
+    /// App slot written to otadata; `None` leaves otadata erased
+    pub boot_slot: Option<BootSlot>,
+    /// Rollback state recorded with the boot slot
+    pub boot_state: OtaImageState,
+    /// `key,type,encoding,value` CSV written into the `nvs` partition
+    pub nvs_csv: Option<PathBuf>,
+    /// NVS encryption keys; adds an encrypted `nvs_keys` partition and encrypts the NVS data
+    pub nvs_keys: Option<PathBuf>,
+    /// Directory packed into the `spiffs` partition
+    pub spiffs_dir: Option<PathBuf>,
+    pub spiffs: SpiffsConfig,
+    /// Directory packed into the `littlefs` partition
+    pub littlefs_dir: Option<PathBuf>,
+    pub littlefs: LittlefsConfig,
+    /// Directory packed into the `fat` partition
+    pub fat_dir: Option<PathBuf>,
+    pub fat: FatConfig,
     pub verbose: bool,
     pub pad_flash: bool,
 }
@@ -20,6 +54,10 @@ pub enum FlashSize {
     Size16MB,
     #[serde(rename = "32MB")]
     Size32MB,
+    #[serde(rename = "64MB")]
+    Size64MB,
+    #[serde(rename = "128MB")]
+    Size128MB,
 }
 
 impl FlashSize {
@@ -28,10 +66,456 @@ impl FlashSize {
             FlashSize::Size8MB => 8 * 1024 * 1024,
             FlashSize::Size16MB => 16 * 1024 * 1024,
             FlashSize::Size32MB => 32 * 1024 * 1024,
+            FlashSize::Size64MB => 64 * 1024 * 1024,
+            FlashSize::Size128MB => 128 * 1024 * 1024,
         }
     }
 }
 
+/// Data partition grown to consume all flash left after the app partitions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FillPartition {
+    pub name: String,
+    pub subtype: DataType,
+}
+
+impl FromStr for FillPartition {
+    type Err = anyhow::Error;
+
+    /// Parse `name:subtype`, e.g. `storage:littlefs`
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        let (name, subtype) = value.split_once(':').ok_or_else(|| {
+            anyhow::anyhow!(
+                "Invalid fill partition '{}' (expected name:subtype, e.g. storage:littlefs)",
+                value
+            )
+        })?;
+
+        if name.is_empty() {
+            return Err(anyhow::anyhow!("Fill partition name must not be empty"));
+        }
+
+        let subtype = subtype
+            .parse::<DataType>()
+            .map_err(|_| anyhow::anyhow!("Unknown data partition subtype '{}'", subtype))?;
+
+        Ok(Self {
+            name: name.to_string(),
+            subtype,
+        })
+    }
+}
+
+/// Product shape deciding which app slots `PartitionGenerator` creates and how they're sized
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LayoutPreset {
+    /// Factory app plus one exactly sized OTA slot per further firmware
+    #[default]
+    FactoryOta,
+    /// Two uniform OTA slots and no factory app
+    Ab,
+    /// Small factory menu plus uniform OTA slots for the apps it launches
+    Launcher,
+    /// A single factory app, without otadata
+    Minimal,
+}
+
+impl LayoutPreset {
+    pub fn has_factory(self) -> bool {
+        self != Self::Ab
+    }
+
+    pub fn has_otadata(self) -> bool {
+        self != Self::Minimal
+    }
+
+    /// Whether all OTA slots share the size of the largest one
+    pub fn uniform_ota_slots(self) -> bool {
+        matches!(self, Self::Ab | Self::Launcher)
+    }
+
+    /// Whether the factory slot keeps `FACTORY_SIZE` of room to grow
+    pub fn factory_growth_room(self) -> bool {
+        self == Self::FactoryOta
+    }
+
+    /// Factory slot for an app of `app_size` bytes, or for a template without one;
+    /// the launcher's menu slot fits the menu exactly instead of a whole 64KB block
+    pub fn factory_size(self, app_size: Option<u32>) -> u32 {
+        match (self, app_size) {
+            (Self::Launcher, Some(size)) => {
+                size.div_ceil(defaults::DATA_ALIGNMENT) * defaults::DATA_ALIGNMENT
+            }
+            (Self::Launcher, None) => defaults::LAUNCHER_MENU_SIZE,
+            (_, Some(size)) => size.div_ceil(defaults::OTA_ALIGNMENT) * defaults::OTA_ALIGNMENT,
+            (_, None) => defaults::FACTORY_SIZE,
+        }
+    }
+
+    /// OTA slots the preset always provides, reserving empty ones if needed
+    pub fn min_ota_slots(self) -> usize {
+        match self {
+            Self::Ab => 2,
+            _ => 0,
+        }
+    }
+
+    pub fn max_ota_slots(self) -> Option<usize> {
+        match self {
+            Self::Ab => Some(2),
+            Self::Minimal => Some(0),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for LayoutPreset {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "factory-ota" => Ok(Self::FactoryOta),
+            "ab" => Ok(Self::Ab),
+            "launcher" => Ok(Self::Launcher),
+            "minimal" => Ok(Self::Minimal),
+            _ => Err(anyhow!(
+                "Unknown layout '{}' (expected factory-ota, ab, launcher or minimal)",
+                value
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for LayoutPreset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FactoryOta => write!(f, "factory-ota"),
+            Self::Ab => write!(f, "ab"),
+            Self::Launcher => write!(f, "launcher"),
+            Self::Minimal => write!(f, "minimal"),
+        }
+    }
+}
+
+/// How `PartitionGenerator` places partitions that aren't pinned
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AllocationStrategy {
+    /// In table order after the partition table, keeping room for the factory app to grow
+    #[default]
+    Sequential,
+    /// App slots largest first, then small data partitions into the gaps left by
+    /// alignment after the partition table; the factory app keeps its growth room
+    Packed,
+}
+
+impl FromStr for AllocationStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "sequential" => Ok(Self::Sequential),
+            "packed" => Ok(Self::Packed),
+            _ => Err(anyhow!(
+                "Unknown allocation strategy '{}' (expected sequential or packed)",
+                value
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for AllocationStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sequential => write!(f, "sequential"),
+            Self::Packed => write!(f, "packed"),
+        }
+    }
+}
+
+/// Partition entry in a `--partitions` JSON file
+///
+/// Entries with a `type` add a new partition; entries without one adjust the
+/// generated partition of the same name (e.g. flags on `nvs` or `ota_0`).
+/// An `offset` and/or `size` pins a partition so later layouts keep it in place.
+///
+/// ```json
+/// [
+///   { "name": "calib", "type": "0x40", "subtype": "0x00", "size": "64KB", "file": "calib.bin" },
+///   { "name": "test", "type": "app", "subtype": "test", "file": "factory-test.bin" },
+///   { "name": "ota_0", "flags": "encrypted" },
+///   { "name": "ota_1", "offset": "0x520000", "size": "4MB" }
+/// ]
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PartitionSpec {
+    pub name: String,
+    #[serde(
+        rename = "type",
+        default,
+        deserialize_with = "deserialize_partition_type",
+        serialize_with = "serialize_optional_display"
+    )]
+    pub ty: Option<Type>,
+    /// Subtype name or number, resolved against the type by [`PartitionSpec::resolve_subtype`]
+    #[serde(default)]
+    pub subtype: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_optional_size")]
+    pub offset: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_optional_size")]
+    pub size: Option<u32>,
+    /// Raw content written into the partition
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    /// `encrypted`, `readonly` or both, colon separated as in ESP-IDF CSV tables
+    #[serde(
+        default = "Flags::empty",
+        deserialize_with = "deserialize_flags",
+        serialize_with = "serialize_flags"
+    )]
+    pub flags: Flags,
+}
+
+impl PartitionSpec {
+    /// Load partition specs from a JSON file containing an array of entries;
+    /// relative `file` paths are resolved against the file's directory
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<PartitionSpec>> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read partitions file {:?}: {}", path, e))?;
+        let mut specs: Vec<PartitionSpec> = serde_json::from_str(&content)
+            .map_err(|e| anyhow!("Invalid partitions file {:?}: {}", path, e))?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        for spec in &mut specs {
+            if let Some(file) = &mut spec.file
+                && file.is_relative()
+            {
+                *file = base.join(&*file);
+            }
+        }
+
+        for spec in &specs {
+            match (spec.ty, &spec.subtype) {
+                (Some(_), Some(_)) => {
+                    spec.resolve_subtype()?;
+                }
+                (None, None) => {
+                    if spec.file.is_some() {
+                        return Err(anyhow!(
+                            "Partition '{}' has no type: only offset, size and flags can be set on generated partitions",
+                            spec.name
+                        ));
+                    }
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "Partition '{}' needs both type and subtype",
+                        spec.name
+                    ));
+                }
+            }
+        }
+
+        Ok(specs)
+    }
+
+    /// Whether this entry adds a new partition rather than adjusting a generated one
+    pub fn is_new_partition(&self) -> bool {
+        self.ty.is_some()
+    }
+
+    /// Whether this entry fixes the offset or size of its partition
+    pub fn is_pinned(&self) -> bool {
+        self.offset.is_some() || (!self.is_new_partition() && self.size.is_some())
+    }
+
+    /// Resolve the configured subtype for this partition's type
+    pub fn resolve_subtype(&self) -> anyhow::Result<SubType> {
+        let (Some(ty), Some(subtype)) = (self.ty, &self.subtype) else {
+            return Err(anyhow!(
+                "Partition '{}' needs both type and subtype",
+                self.name
+            ));
+        };
+
+        let value = subtype.trim();
+        let invalid = || {
+            anyhow!(
+                "Invalid subtype '{}' for partition '{}' of type {} (expected {})",
+                value,
+                self.name,
+                ty,
+                ty.subtype_hint()
+            )
+        };
+
+        match ty {
+            Type::App => value
+                .parse::<AppType>()
+                .map(SubType::App)
+                .map_err(|_| invalid()),
+            Type::Data => match value.parse::<DataType>() {
+                Ok(data_type) => Ok(SubType::Data(data_type)),
+                Err(_) => {
+                    let raw = parse_u8(value).ok_or_else(invalid)?;
+                    Ok(DataType::from_repr(raw as usize)
+                        .map(SubType::Data)
+                        .unwrap_or(SubType::Custom(raw)))
+                }
+            },
+            Type::Custom(_) => parse_u8(value)
+                .filter(|&raw| raw != 0xFF)
+                .map(SubType::Custom)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Invalid subtype '{}' for partition '{}' (expected 0x00 through 0xFE)",
+                        value,
+                        self.name
+                    )
+                }),
+        }
+    }
+}
+
+/// Parse a partition type: `app`, `data` or a user type from 0x40 to 0xFE
+pub fn parse_partition_type(value: &str) -> anyhow::Result<Type> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "app" => Ok(Type::App),
+        "data" => Ok(Type::Data),
+        other => match parse_u8(other) {
+            Some(0x00) => Ok(Type::App),
+            Some(0x01) => Ok(Type::Data),
+            Some(raw @ 0x40..=0xFE) => Ok(Type::Custom(raw)),
+            _ => Err(anyhow!(
+                "Invalid partition type '{}' (expected app, data or a user type 0x40 through 0xFE)",
+                value
+            )),
+        },
+    }
+}
+
+/// Parse partition flags as written in ESP-IDF CSV tables, e.g. `encrypted:readonly`
+pub fn parse_flags(value: &str) -> anyhow::Result<Flags> {
+    let mut flags = Flags::empty();
+    for flag in value.split(':').map(str::trim).filter(|f| !f.is_empty()) {
+        flags |= match flag.to_ascii_lowercase().as_str() {
+            "encrypted" => Flags::ENCRYPTED,
+            "readonly" => Flags::READONLY,
+            _ => {
+                return Err(anyhow!(
+                    "Unknown partition flag '{}' (expected encrypted or readonly)",
+                    flag
+                ));
+            }
+        };
+    }
+    Ok(flags)
+}
+
+/// Format partition flags the way ESP-IDF CSV tables write them
+pub fn format_flags(flags: Flags) -> String {
+    let mut names = Vec::new();
+    if flags.contains(Flags::ENCRYPTED) {
+        names.push("encrypted");
+    }
+    if flags.contains(Flags::READONLY) {
+        names.push("readonly");
+    }
+    names.join(":")
+}
+
+fn parse_u8(value: &str) -> Option<u8> {
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => value.parse::<u8>().ok(),
+    }
+}
+
+fn deserialize_partition_type<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Type>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_partition_type(&value)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_flags<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Flags, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_flags(&value).map_err(serde::de::Error::custom)
+}
+
+fn serialize_flags<S: Serializer>(flags: &Flags, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_flags(*flags))
+}
+
+fn deserialize_optional_size<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum SizeValue {
+        Bytes(u32),
+        Text(String),
+    }
+
+    match Option::<SizeValue>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(SizeValue::Bytes(bytes)) => Ok(Some(bytes)),
+        Some(SizeValue::Text(text)) => parse_size(&text)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+fn serialize_optional_display<T: std::fmt::Display, S: Serializer>(
+    value: &Option<T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.collect_str(value),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Parse a size given as bytes, hex (`0x400000`) or with a `K`/`KB`/`M`/`MB` suffix
+pub fn parse_size(value: &str) -> anyhow::Result<u32> {
+    let upper = value.trim().to_ascii_uppercase();
+
+    let parsed = if let Some(hex) = upper.strip_prefix("0X") {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        let (digits, multiplier) = if let Some(digits) =
+            upper.strip_suffix("MB").or_else(|| upper.strip_suffix('M'))
+        {
+            (digits, 1024 * 1024)
+        } else if let Some(digits) = upper.strip_suffix("KB").or_else(|| upper.strip_suffix('K')) {
+            (digits, 1024)
+        } else {
+            (upper.strip_suffix('B').unwrap_or(&upper), 1)
+        };
+
+        digits
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .and_then(|n| n.checked_mul(multiplier))
+    };
+
+    parsed.ok_or_else(|| {
+        anyhow::anyhow!(
+            "Invalid size '{}' (expected e.g. 4096, 0x1000, 64KB or 4MB)",
+            value
+        )
+    })
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
@@ -39,6 +523,25 @@ impl Default for Config {
             firmware_dir: PathBuf::from("firmwares"),
             output_file: PathBuf::from("combined-image.bin"),
             max_ota_partitions: 16,
+            extra_ota_slots: 0,
+            extra_ota_size: defaults::DEFAULT_OTA_SIZE,
+            fill_partition: None,
+            partition_table_offset: defaults::PARTITION_TABLE_OFFSET,
+            partition_table_md5: true,
+            layout: LayoutPreset::default(),
+            allocation: AllocationStrategy::default(),
+            partitions: Vec::new(),
+            layout_lock: None,
+            boot_slot: None,
+            boot_state: OtaImageState::default(),
+            nvs_csv: None,
+            nvs_keys: None,
+            spiffs_dir: None,
+            spiffs: SpiffsConfig::default(),
+            littlefs_dir: None,
+            littlefs: LittlefsConfig::default(),
+            fat_dir: None,
+            fat: FatConfig::default(),
             verbose: false,
             pad_flash: false,
         }
@@ -48,17 +551,188 @@ impl Default for Config {
 pub mod defaults {
 
     pub const BOOTLOADER_OFFSET: u32 = 0x2000; // ESP32-P4 bootloader at 0x2000 (from ESP-IDF flash_args)
-    pub const BOOTLOADER_SIZE: u32 = 24 * 1024; // 24KB (to fit between 0x2000 and 0x8000)
     pub const PARTITION_TABLE_OFFSET: u32 = 0x10000; // ESP32-P4 partition table at 0x10000 (from ESP-IDF flash_args)
     pub const PARTITION_TABLE_SIZE: u32 = 4 * 1024; // 4KB
-    pub const NVS_OFFSET: u32 = 0x9000; // NVS between bootloader and partition table
-    pub const NVS_SIZE: u32 = 4 * 1024; // 4KB (to avoid overlap with otadata)
-    pub const OTADATA_OFFSET: u32 = 0xA000; // Move earlier
-    pub const OTADATA_SIZE: u32 = 8 * 1024; // 8KB
-    pub const FACTORY_OFFSET: u32 = 0x20000; // ESP32-P4 factory app at 0x20000 (from ESP-IDF flash_args)
-    pub const FACTORY_SIZE: u32 = 1 * 1024 * 1024; // 1MB
+    pub const NVS_SIZE: u32 = 0x6000; // 24KB as in ESP-IDF, placed right after the partition table
+    pub const OTADATA_SIZE: u32 = 8 * 1024; // 8KB, placed right after NVS
+    pub const NVS_KEYS_SIZE: u32 = 4 * 1024; // 4KB, one flash-encryption sector for the NVS keys
+    pub const SPIFFS_SIZE: u32 = 1024 * 1024; // 1MB, added after the app slots for --spiffs
+    pub const LITTLEFS_SIZE: u32 = 1024 * 1024; // 1MB, added after the app slots for --littlefs
+    pub const FAT_SIZE: u32 = 1024 * 1024; // 1MB, added after the app slots for --fat
+    pub const FACTORY_OFFSET: u32 = 0x20000; // ESP32-P4 factory app at 0x20000 with the default partition table offset
+    pub const FACTORY_SIZE: u32 = 1024 * 1024; // 1MB
+    pub const LAUNCHER_MENU_SIZE: u32 = 256 * 1024; // 256KB, factory menu slot of a launcher template
 
     pub const OTA_ALIGNMENT: u32 = 64 * 1024; // 64KB
+    pub const DATA_ALIGNMENT: u32 = 4 * 1024; // 4KB
     pub const MIN_OTA_SIZE: u32 = 256 * 1024; // 256KB
     pub const DEFAULT_OTA_SIZE: u32 = 4 * 1024 * 1024; // 4MB
+    pub const MAX_OTA_SLOTS: usize = 16; // ota_0..ota_15
+    pub const MAX_PARTITION_NAME_LEN: usize = 16; // Name field in the binary table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("0x200000").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_size("64KB").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("64k").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("4MB").unwrap(), 4 * 1024 * 1024);
+        assert_eq!(parse_size("2M").unwrap(), 2 * 1024 * 1024);
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("8192MB").is_err()); // overflows u32
+    }
+
+    #[test]
+    fn test_parse_fill_partition() {
+        let fill: FillPartition = "storage:littlefs".parse().unwrap();
+        assert_eq!(fill.name, "storage");
+        assert_eq!(fill.subtype, DataType::Littlefs);
+
+        assert!("storage".parse::<FillPartition>().is_err());
+        assert!("storage:ext4".parse::<FillPartition>().is_err());
+        assert!(":fat".parse::<FillPartition>().is_err());
+    }
+
+    #[test]
+    fn test_parse_layout_preset() {
+        for preset in [
+            LayoutPreset::FactoryOta,
+            LayoutPreset::Ab,
+            LayoutPreset::Launcher,
+            LayoutPreset::Minimal,
+        ] {
+            assert_eq!(preset.to_string().parse::<LayoutPreset>().unwrap(), preset);
+        }
+        assert!(!LayoutPreset::Ab.has_factory());
+        assert!(!LayoutPreset::Minimal.has_otadata());
+        assert!("single".parse::<LayoutPreset>().is_err());
+    }
+
+    #[test]
+    fn test_parse_allocation_strategy() {
+        assert_eq!(
+            "packed".parse::<AllocationStrategy>().unwrap(),
+            AllocationStrategy::Packed
+        );
+        assert_eq!(
+            "Sequential".parse::<AllocationStrategy>().unwrap(),
+            AllocationStrategy::Sequential
+        );
+        assert!("dense".parse::<AllocationStrategy>().is_err());
+    }
+
+    #[test]
+    fn test_parse_partition_type() {
+        assert_eq!(parse_partition_type("app").unwrap(), Type::App);
+        assert_eq!(parse_partition_type("DATA").unwrap(), Type::Data);
+        assert_eq!(parse_partition_type("0x40").unwrap(), Type::Custom(0x40));
+        assert_eq!(parse_partition_type("254").unwrap(), Type::Custom(0xFE));
+        assert!(parse_partition_type("0x20").is_err()); // reserved by ESP-IDF
+        assert!(parse_partition_type("0xFF").is_err());
+        assert!(parse_partition_type("bogus").is_err());
+    }
+
+    #[test]
+    fn test_partition_spec_subtypes() {
+        let specs: Vec<PartitionSpec> = serde_json::from_str(
+            r#"[
+                { "name": "calib", "type": "0x40", "subtype": "0x01", "size": "64KB" },
+                { "name": "test", "type": "app", "subtype": "test", "size": 1048576 },
+                { "name": "assets", "type": "data", "subtype": "0x99", "size": "0x2000" },
+                { "name": "storage", "type": "data", "subtype": "spiffs" }
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(specs[0].ty, Some(Type::Custom(0x40)));
+        assert_eq!(specs[0].resolve_subtype().unwrap(), SubType::Custom(0x01));
+        assert_eq!(specs[0].size, Some(64 * 1024));
+        assert_eq!(
+            specs[1].resolve_subtype().unwrap(),
+            SubType::App(AppType::Test)
+        );
+        assert_eq!(specs[1].size, Some(1024 * 1024));
+        assert_eq!(specs[2].resolve_subtype().unwrap(), SubType::Custom(0x99));
+        assert_eq!(
+            specs[3].resolve_subtype().unwrap(),
+            SubType::Data(DataType::Spiffs)
+        );
+        assert_eq!(specs[3].size, None);
+
+        let bad = PartitionSpec {
+            subtype: Some("nvs".to_string()),
+            ..specs[1].clone()
+        };
+        assert!(bad.resolve_subtype().is_err());
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        assert_eq!(parse_flags("").unwrap(), Flags::empty());
+        assert_eq!(parse_flags("encrypted").unwrap(), Flags::ENCRYPTED);
+        assert_eq!(
+            parse_flags("encrypted:readonly").unwrap(),
+            Flags::ENCRYPTED | Flags::READONLY
+        );
+        assert!(parse_flags("secret").is_err());
+        assert_eq!(
+            format_flags(Flags::ENCRYPTED | Flags::READONLY),
+            "encrypted:readonly"
+        );
+    }
+
+    #[test]
+    fn test_partition_spec_flag_override() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("partitions.json");
+
+        std::fs::write(
+            &path,
+            r#"[{ "name": "ota_0", "flags": "encrypted" }, { "name": "nvs_keys", "type": "data", "subtype": "nvs_keys", "size": "4KB", "flags": "encrypted" }]"#,
+        )
+        .unwrap();
+        let specs = PartitionSpec::load_from_file(&path).unwrap();
+        assert!(!specs[0].is_new_partition());
+        assert_eq!(specs[0].flags, Flags::ENCRYPTED);
+        assert!(specs[1].is_new_partition());
+
+        std::fs::write(
+            &path,
+            r#"[{ "name": "ota_0", "offset": "0x120000", "size": "1MB" }]"#,
+        )
+        .unwrap();
+        let specs = PartitionSpec::load_from_file(&path).unwrap();
+        assert!(specs[0].is_pinned());
+        assert_eq!(specs[0].offset, Some(0x120000));
+        assert_eq!(specs[0].size, Some(1024 * 1024));
+
+        std::fs::write(&path, r#"[{ "name": "ota_0", "file": "ota.bin" }]"#).unwrap();
+        assert!(PartitionSpec::load_from_file(&path).is_err());
+
+        std::fs::write(&path, r#"[{ "name": "calib", "type": "0x40" }]"#).unwrap();
+        assert!(PartitionSpec::load_from_file(&path).is_err());
+    }
+
+    #[test]
+    fn test_partition_spec_file_relative_to_partitions_file() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("partitions.json");
+
+        std::fs::write(
+            &path,
+            r#"[{ "name": "calib", "type": "0x40", "subtype": "0x00", "file": "data/calib.bin" }, { "name": "test", "type": "app", "subtype": "test", "file": "/abs/test.bin" }]"#,
+        )
+        .unwrap();
+        let specs = PartitionSpec::load_from_file(&path).unwrap();
+        assert_eq!(
+            specs[0].file.as_deref(),
+            Some(temp_dir.path().join("data/calib.bin").as_path())
+        );
+        assert_eq!(specs[1].file.as_deref(), Some(Path::new("/abs/test.bin")));
+    }
 }
@@ -40,7 +40,7 @@ impl EspChecksum {
         // Process all 32-bit words in the image data
         // This includes headers and segment data, but NOT the checksum byte itself
         let data_len = data.len();
-        let num_words = (data_len + 3) / 4; // Round up to handle partial words
+        let num_words = data_len.div_ceil(4); // Round up to handle partial words
 
         for i in 0..num_words {
             let mut word_bytes = [0u8; 4];
@@ -74,7 +74,7 @@ impl EspChecksum {
     ///
     /// # Returns
     /// * `Result<(usize, usize)>` - (image_data_size, checksum_location) or error
-    fn parse_esp32_image_header(data: &[u8]) -> Result<(usize, usize)> {
+    pub fn parse_esp32_image_header(data: &[u8]) -> Result<(usize, usize)> {
         if data.len() < 24 {
             return Err(anyhow::anyhow!("Image too small for ESP32 header"));
         }
@@ -110,7 +110,7 @@ impl EspChecksum {
         pos += segment_count * 8;
 
         // Read segment data sizes and add them to image size
-        for i in 0..segment_count {
+        for _ in 0..segment_count {
             if pos + 8 <= data.len() {
                 let seg_size = u32::from_le_bytes([
                     data[pos + 4],
@@ -217,14 +217,14 @@ impl Esp32P4Processor {
     /// Standard write alignment (4 bytes)
     pub const WRITE_ALIGN: u32 = 4;
 
-    /// Process bootloader image and patch required checksums and headers
+    /// Validate bootloader image; its checksums and headers are kept as built
     ///
     /// # Arguments
-    /// * `bootloader_data` - Mutable bootloader binary data
+    /// * `bootloader_data` - Bootloader binary data
     ///
     /// # Returns
     /// * `Result<()>` - Success or error
-    pub fn process_bootloader_image(bootloader_data: &mut [u8]) -> Result<()> {
+    pub fn process_bootloader_image(bootloader_data: &[u8]) -> Result<()> {
         info!(
             "Processing ESP32-P4 bootloader image ({} bytes)",
             bootloader_data.len()
@@ -261,10 +261,6 @@ impl Esp32P4Processor {
             // Extended header already exists (all zeros)
             info!("Extended header already present in bootloader");
         } else {
-            // Add space for extended header by shifting data
-            let extended_header_size = 16;
-            let new_size = bootloader_data.len() + extended_header_size;
-
             // For now, let's not modify the bootloader structure to avoid corruption
             // Instead, we'll work with what we have
             info!("Using existing bootloader structure without modification");
@@ -278,15 +274,15 @@ impl Esp32P4Processor {
         Ok(())
     }
 
-    /// Process application image and patch required checksums and headers
+    /// Validate application image; its checksums and headers are kept as built
     ///
     /// # Arguments
-    /// * `app_data` - Mutable application binary data
+    /// * `app_data` - Application binary data
     /// * `encrypted` - Whether to use encrypted write alignment
     ///
     /// # Returns
     /// * `Result<()>` - Success or error
-    pub fn process_app_image(app_data: &mut [u8], encrypted: bool) -> Result<()> {
+    pub fn process_app_image(app_data: &[u8], encrypted: bool) -> Result<()> {
         info!(
             "Processing ESP32-P4 app image ({} bytes, encrypted={})",
             app_data.len(),
@@ -360,7 +356,7 @@ impl Esp32P4Processor {
             4 * 1024 // 4KB for data partitions
         };
 
-        if offset % required_alignment != 0 {
+        if !offset.is_multiple_of(required_alignment) {
             return Err(anyhow::anyhow!(
                 "Offset 0x{:X} not aligned to {} bytes for {} partition",
                 offset,
@@ -383,7 +379,7 @@ mod tests {
 
         // Manual calculation: 0xEF ^ 0x12 ^ 0x34 ^ 0x56 ^ 0x78
         let expected = 0xEF ^ 0x12 ^ 0x34 ^ 0x56 ^ 0x78;
-        let calculated = EspChecksum::calculate_checksum(&data, None);
+        let calculated = EspChecksum::calculate_checksum(&data).unwrap();
 
         assert_eq!(calculated, expected);
     }
@@ -391,30 +387,29 @@ mod tests {
     #[test]
     fn test_checksum_verification() {
         let data = vec![0x12, 0x34, 0x56, 0x78];
-        let checksum = EspChecksum::calculate_checksum(&data, None);
+        let checksum = EspChecksum::calculate_checksum(&data).unwrap();
+
+        let valid = [&data[..], &[checksum]].concat();
+        let invalid = [&data[..], &[checksum ^ 0x01]].concat();
 
-        assert!(EspChecksum::verify_checksum(&data, checksum, None));
-        assert!(!EspChecksum::verify_checksum(&data, checksum ^ 0xFF, None));
+        assert!(EspChecksum::verify_checksum(&valid).unwrap());
+        assert!(!EspChecksum::verify_checksum(&invalid).unwrap());
     }
 
     #[test]
     fn test_checksum_patching() {
         let mut data = vec![
-            0xE9, 0x07, 0x02, 0x4F, 0x00, 0x10, 0x20, 0x30, 0xEE, 0x12, 0x34, 0x56,
+            0xE9, 0x07, 0x02, 0x4F, 0x00, 0x10, 0x20, 0x30, 0x12, 0x34, 0x56, 0xEE, 0xFF, 0xFF,
         ];
-        let original_checksum_field = data[8];
-
-        // Patch the checksum
-        EspChecksum::patch_checksum(&mut data, 8, None).unwrap();
 
-        // Verify checksum was updated
-        assert_ne!(data[8], original_checksum_field);
+        // Patch the checksum into the last non-0xFF byte
+        let checksum = EspChecksum::calculate_and_patch_checksum(&mut data).unwrap();
 
-        // Verify the new checksum is correct
-        let checksum_data_without_checksum = [&data[..8], &data[9..]].concat();
-        let expected_checksum =
-            EspChecksum::calculate_checksum(&checksum_data_without_checksum, Some(0xEF));
-        assert_eq!(data[8], expected_checksum);
+        // Verify checksum was updated and is correct
+        let expected_checksum = EspChecksum::calculate_checksum(&data[..11]).unwrap();
+        assert_eq!(checksum, expected_checksum);
+        assert_eq!(data[11], expected_checksum);
+        assert!(EspChecksum::verify_checksum(&data).unwrap());
     }
 
     #[test]
@@ -445,7 +440,7 @@ mod tests {
             0x02, // Flash mode
             0x4F, // Flash size + frequency
             0x12, 0x34, 0x56, 0x78, // Entry point
-            0xFF, // Checksum (will be patched)
+            0xFF, // Checksum (preserved as-is)
             0x00, 0x00, 0x00, 0x00, // Padding
             0x12, 0x00, 0x00, 0x00, // Segment 1: RAM
             0x20, 0x00, 0x00, 0x00, // Segment 1: offset
@@ -456,14 +451,11 @@ mod tests {
         ];
         bootloader.extend(vec![0x42; 100]);
 
-        Esp32P4Processor::process_bootloader_image(&mut bootloader).unwrap();
+        let original = bootloader.clone();
 
-        // Verify checksum is no longer 0xFF
-        assert_ne!(bootloader[8], 0xFF);
+        Esp32P4Processor::process_bootloader_image(&bootloader).unwrap();
 
-        // Verify checksum is correct
-        let checksum_data = [&bootloader[..8], &bootloader[9..]].concat();
-        let expected = EspChecksum::calculate_checksum(&checksum_data, Some(0xEF));
-        assert_eq!(bootloader[8], expected);
+        // Original ESP-IDF checksum and header flags are preserved
+        assert_eq!(bootloader, original);
     }
 }
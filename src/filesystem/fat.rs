@@ -0,0 +1,520 @@
+//! FAT12/16 images, optionally inside ESP-IDF's wear-levelling layer
+//!
+//! Volumes use 4KB sectors and clusters, as `fatfsgen.py` and the ESP-IDF
+//! FATFS configuration do. With wear levelling the volume starts after the
+//! dummy sector and the partition ends with two copies of the `wl_state_t`
+//! sector and the `wl_config_t` sector, exactly as `WL_Flash` initializes a
+//! fresh partition, so the firmware mounts it without reformatting.
+
+use super::{HostFile, ImageContents, ImageFile};
+use crate::Result;
+use anyhow::anyhow;
+use fatfs::{FatType, FileSystem, FormatVolumeOptions, FsOptions};
+use serde::{Deserialize, Serialize};
+use std::io::{Cursor, Read, Write};
+
+/// CONFIG_WL_SECTOR_SIZE and the FAT sector size
+pub const SECTOR_SIZE: u32 = 4096;
+const VOLUME_LABEL: [u8; 11] = *b"Espressif  ";
+/// Fixed so images are reproducible
+const VOLUME_ID: u32 = 0x1234_5678;
+
+/// `WL_CURRENT_VERSION`
+const WL_VERSION: u32 = 2;
+/// `WL_DEFAULT_UPDATERATE`
+const WL_UPDATE_RATE: u32 = 16;
+/// `WL_DEFAULT_WRITE_SIZE`: one position record in the state sectors
+const WL_WRITE_SIZE: u32 = 16;
+/// `WL_DEFAULT_TEMP_BUFF_SIZE`
+const WL_TEMP_BUFF_SIZE: u32 = 32;
+/// `sizeof(wl_state_t)`
+const WL_STATE_LEN: usize = 64;
+/// `sizeof(wl_config_t)`
+const WL_CONFIG_LEN: usize = 36;
+
+/// Build options
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FatConfig {
+    /// Wrap the volume for `esp_vfs_fat_spiflash_mount_rw_wl`; without it the
+    /// image is for `esp_vfs_fat_spiflash_mount_ro`
+    pub wear_levelling: bool,
+}
+
+/// Where the wear-levelling layer puts its sectors in a partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct WlLayout {
+    state_size: u32,
+    cfg_size: u32,
+    /// Size of the FAT volume the layer exposes
+    flash_size: u32,
+}
+
+impl WlLayout {
+    /// `WL_Flash::config`
+    fn new(partition_size: u32) -> Result<Self> {
+        let sectors = partition_size / SECTOR_SIZE;
+        let state_size =
+            (WL_STATE_LEN as u32 + sectors * WL_WRITE_SIZE).next_multiple_of(SECTOR_SIZE);
+        let cfg_size = (WL_CONFIG_LEN as u32).next_multiple_of(SECTOR_SIZE);
+        let reserved = 2 * state_size + cfg_size + SECTOR_SIZE;
+        if partition_size < reserved + SECTOR_SIZE {
+            return Err(anyhow!(
+                "partition of {:#x} bytes is too small for wear levelling",
+                partition_size
+            ));
+        }
+
+        Ok(Self {
+            state_size,
+            cfg_size,
+            flash_size: partition_size - reserved,
+        })
+    }
+
+    fn state1(&self, partition_size: u32) -> usize {
+        (partition_size - 2 * self.state_size - self.cfg_size) as usize
+    }
+
+    fn state2(&self, partition_size: u32) -> usize {
+        (partition_size - self.state_size - self.cfg_size) as usize
+    }
+
+    fn config(&self, partition_size: u32) -> usize {
+        (partition_size - self.cfg_size) as usize
+    }
+}
+
+/// `crc32::crc32_le(WL_CFG_CRC_CONST, ...)`
+fn wl_crc(data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new_with_initial(0xFFFF_FFFF);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// `wl_state_t` of a freshly initialized partition: dummy sector first, no moves yet
+fn wl_state(layout: &WlLayout, device_id: u32) -> [u8; WL_STATE_LEN] {
+    let fields = [
+        0,                                   // pos
+        1 + layout.flash_size / SECTOR_SIZE, // max_pos
+        0,                                   // move_count
+        0,                                   // access_count
+        WL_UPDATE_RATE,                      // max_count
+        SECTOR_SIZE,                         // block_size
+        WL_VERSION,
+        device_id,
+    ];
+    let mut state = [0u8; WL_STATE_LEN];
+    for (chunk, field) in state.chunks_exact_mut(4).zip(fields) {
+        chunk.copy_from_slice(&field.to_le_bytes());
+    }
+    let crc = wl_crc(&state[..WL_STATE_LEN - 4]);
+    state[WL_STATE_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+    state
+}
+
+/// `wl_config_t` for the whole partition
+fn wl_config(partition_size: u32) -> [u8; WL_CONFIG_LEN] {
+    let fields = [
+        0, // start_addr, relative to the partition
+        partition_size,
+        SECTOR_SIZE, // page_size
+        SECTOR_SIZE, // sector_size
+        WL_UPDATE_RATE,
+        WL_WRITE_SIZE,
+        WL_VERSION,
+        WL_TEMP_BUFF_SIZE,
+    ];
+    let mut config = [0u8; WL_CONFIG_LEN];
+    for (chunk, field) in config.chunks_exact_mut(4).zip(fields) {
+        chunk.copy_from_slice(&field.to_le_bytes());
+    }
+    let crc = wl_crc(&config[..WL_CONFIG_LEN - 4]);
+    config[WL_CONFIG_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+    config
+}
+
+/// Image of `size` bytes holding `dirs` and `files`
+pub fn build(
+    files: &[HostFile],
+    dirs: &[String],
+    size: u32,
+    config: &FatConfig,
+) -> Result<Vec<u8>> {
+    if size == 0 || !size.is_multiple_of(SECTOR_SIZE) {
+        return Err(anyhow!(
+            "partition size {:#x} is not a multiple of the {} byte sector",
+            size,
+            SECTOR_SIZE
+        ));
+    }
+
+    let layout = if config.wear_levelling {
+        Some(WlLayout::new(size)?)
+    } else {
+        None
+    };
+    let volume_size = layout.map_or(size, |l| l.flash_size);
+    let volume = build_volume(files, dirs, volume_size)?;
+
+    let Some(layout) = layout else {
+        return Ok(volume);
+    };
+
+    let mut image = vec![0xFF; size as usize];
+    let start = SECTOR_SIZE as usize;
+    image[start..start + volume.len()].copy_from_slice(&volume);
+    // The device ID only tells partitions apart; derive it from the content
+    // rather than a random number to keep builds reproducible
+    let state = wl_state(&layout, wl_crc(&volume));
+    for offset in [layout.state1(size), layout.state2(size)] {
+        image[offset..offset + WL_STATE_LEN].copy_from_slice(&state);
+    }
+    let offset = layout.config(size);
+    image[offset..offset + WL_CONFIG_LEN].copy_from_slice(&wl_config(size));
+    Ok(image)
+}
+
+/// Formatted FAT volume of `size` bytes with the files copied in
+fn build_volume(files: &[HostFile], dirs: &[String], size: u32) -> Result<Vec<u8>> {
+    let mut volume = vec![0u8; size as usize];
+    let options = FormatVolumeOptions::new()
+        .bytes_per_sector(SECTOR_SIZE as u16)
+        .bytes_per_cluster(SECTOR_SIZE)
+        .total_sectors(size / SECTOR_SIZE)
+        .fats(1)
+        .volume_id(VOLUME_ID)
+        .volume_label(VOLUME_LABEL);
+    fatfs::format_volume(Cursor::new(volume.as_mut_slice()), options)
+        .map_err(|e| anyhow!("failed to format a {:#x} byte FAT volume: {}", size, e))?;
+
+    let fs = FileSystem::new(Cursor::new(volume.as_mut_slice()), FsOptions::new())
+        .map_err(|e| anyhow!("failed to mount the new FAT volume: {}", e))?;
+    if fs.fat_type() == FatType::Fat32 {
+        return Err(anyhow!(
+            "a {:#x} byte volume with 4KB clusters needs FAT32; only FAT12/16 images are built",
+            size
+        ));
+    }
+
+    {
+        let root = fs.root_dir();
+        for dir in dirs {
+            root.create_dir(dir)
+                .map_err(|e| anyhow!("failed to create directory '{}': {}", dir, e))?;
+        }
+        for file in files {
+            let data = file.read()?;
+            root.create_file(&file.path)
+                .and_then(|mut f| f.write_all(&data))
+                .map_err(|e| {
+                    anyhow!(
+                        "failed to write '{}' ({} bytes): {}",
+                        file.path,
+                        data.len(),
+                        e
+                    )
+                })?;
+        }
+    }
+    fs.unmount()
+        .map_err(|e| anyhow!("failed to finish the FAT volume: {}", e))?;
+
+    Ok(volume)
+}
+
+fn word(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+/// Whether `image` ends with a valid `wl_config_t` for its size
+pub fn has_wear_levelling(image: &[u8]) -> bool {
+    let Some(start) = image.len().checked_sub(SECTOR_SIZE as usize) else {
+        return false;
+    };
+    let config = &image[start..start + WL_CONFIG_LEN];
+    word(config, 4) as usize == image.len()
+        && word(config, WL_CONFIG_LEN - 4) == wl_crc(&config[..WL_CONFIG_LEN - 4])
+}
+
+/// The FAT volume behind the wear-levelling layer, in logical sector order
+///
+/// Follows `WL_Flash`: the valid state gives the move count, the position
+/// records after it give the dummy sector, and `calcAddr` maps the sectors.
+fn unwrap_wear_levelling(image: &[u8]) -> Result<Vec<u8>> {
+    let size = image.len() as u32;
+    let config = &image[(size - SECTOR_SIZE) as usize..];
+    if word(config, 12) != SECTOR_SIZE || word(config, 24) != WL_VERSION {
+        return Err(anyhow!(
+            "wear levelling with {} byte sectors and version {} isn't supported",
+            word(config, 12),
+            word(config, 24)
+        ));
+    }
+    let layout = WlLayout::new(size)?;
+    let state_valid = |at: usize| {
+        let state = &image[at..at + WL_STATE_LEN];
+        word(state, WL_STATE_LEN - 4) == wl_crc(&state[..WL_STATE_LEN - 4])
+    };
+    let state_at = [layout.state1(size), layout.state2(size)]
+        .into_iter()
+        .find(|&at| state_valid(at))
+        .ok_or_else(|| anyhow!("neither wear-levelling state sector is valid"))?;
+    let state = &image[state_at..state_at + WL_STATE_LEN];
+    let (max_pos, move_count, device_id) = (word(state, 4), word(state, 8), word(state, 28));
+
+    // `WL_Flash::recoverPos`: a record per dummy sector move, each four CRCs
+    // derived from the device ID
+    let record_ok = |pos: u32| {
+        let at = state_at + WL_STATE_LEN + (pos * WL_WRITE_SIZE) as usize;
+        (0..4).all(|i| {
+            let value = device_id.wrapping_add(pos * 4 + i);
+            word(image, at + 4 * i as usize) == wl_crc(&value.to_le_bytes())
+        })
+    };
+    let mut pos = (0..max_pos).take_while(|&p| record_ok(p)).count() as u32;
+    if pos == max_pos {
+        pos -= 1;
+    }
+
+    let flash_size = layout.flash_size;
+    let mut volume = Vec::with_capacity(flash_size as usize);
+    for sector in 0..flash_size / SECTOR_SIZE {
+        let mut addr = (flash_size - move_count * SECTOR_SIZE + sector * SECTOR_SIZE) % flash_size;
+        if addr >= pos * SECTOR_SIZE {
+            addr += SECTOR_SIZE;
+        }
+        volume.extend_from_slice(&image[addr as usize..(addr + SECTOR_SIZE) as usize]);
+    }
+    Ok(volume)
+}
+
+/// Files and directories of a FAT image, with or without wear levelling
+pub fn read(image: &[u8]) -> Result<ImageContents> {
+    let volume = if has_wear_levelling(image) {
+        unwrap_wear_levelling(image)?
+    } else {
+        image.to_vec()
+    };
+    let fs = FileSystem::new(Cursor::new(volume), FsOptions::new())
+        .map_err(|e| anyhow!("not a FAT volume: {}", e))?;
+
+    let mut contents = ImageContents::default();
+    let mut pending = vec![(fs.root_dir(), String::new())];
+    while let Some((dir, path)) = pending.pop() {
+        for entry in dir.iter() {
+            let entry = entry.map_err(|e| anyhow!("failed to read directory '{}': {}", path, e))?;
+            let name = entry.file_name();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let path = if path.is_empty() {
+                name
+            } else {
+                format!("{}/{}", path, name)
+            };
+
+            if entry.is_dir() {
+                contents.dirs.push(path.clone());
+                pending.push((entry.to_dir(), path));
+            } else {
+                let mut data = Vec::new();
+                entry
+                    .to_file()
+                    .read_to_end(&mut data)
+                    .map_err(|e| anyhow!("failed to read '{}': {}", path, e))?;
+                contents.files.push(ImageFile { path, data });
+            }
+        }
+    }
+
+    contents.dirs.sort();
+    contents.files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host_file(dir: &std::path::Path, path: &str, data: &[u8]) -> HostFile {
+        let source = dir.join(path.replace('/', "_"));
+        std::fs::write(&source, data).unwrap();
+        HostFile {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    fn read_file(volume: &mut [u8], path: &str) -> Vec<u8> {
+        let fs = FileSystem::new(Cursor::new(volume), FsOptions::new()).unwrap();
+        let mut data = Vec::new();
+        fs.root_dir()
+            .open_file(path)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn test_build_fat_volume() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let large: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        let files = vec![
+            host_file(temp_dir.path(), "config.json", b"{}"),
+            host_file(temp_dir.path(), "web/index.html", &large),
+        ];
+        let dirs = vec!["empty".to_string(), "web".to_string()];
+
+        let mut image = build(&files, &dirs, 0x10_0000, &FatConfig::default())?;
+        assert_eq!(image.len(), 0x10_0000);
+        assert_eq!(u16::from_le_bytes([image[11], image[12]]), 4096);
+
+        let fs = FileSystem::new(Cursor::new(image.as_mut_slice()), FsOptions::new())?;
+        assert_eq!(fs.fat_type(), FatType::Fat12);
+        assert_eq!(fs.volume_label(), "Espressif");
+        assert!(fs.root_dir().open_dir("empty").is_ok());
+        drop(fs);
+        assert_eq!(read_file(&mut image, "config.json"), b"{}");
+        assert_eq!(read_file(&mut image, "web/index.html"), large);
+
+        let again = build(&files, &dirs, 0x10_0000, &FatConfig::default())?;
+        assert_eq!(again, image);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_fat_wear_levelling() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let files = vec![host_file(temp_dir.path(), "log.txt", b"boot")];
+        let size = 0x8_0000;
+        let config = FatConfig {
+            wear_levelling: true,
+        };
+
+        let mut image = build(&files, &[], size, &config)?;
+        // Records for 128 sectors fit one state sector: dummy, 124 volume sectors, 2 states, config
+        let layout = WlLayout::new(size)?;
+        assert_eq!(layout.flash_size, 124 * SECTOR_SIZE);
+        assert!(image[..4096].iter().all(|&b| b == 0xFF));
+        assert_eq!(read_file(&mut image[4096..125 * 4096], "log.txt"), b"boot");
+
+        let word = |at: usize| u32::from_le_bytes(image[at..at + 4].try_into().unwrap());
+        let (state1, state2, cfg) = (0x7D000, 0x7E000, 0x7F000);
+        assert_eq!(image[state1..state1 + 4096], image[state2..state2 + 4096]);
+        assert_eq!(word(state1 + 4), 125); // max_pos
+        assert_eq!(word(state1 + 24), WL_VERSION);
+        assert_eq!(word(state1 + 60), wl_crc(&image[state1..state1 + 60]));
+        assert!(image[state1 + 64..state1 + 4096].iter().all(|&b| b == 0xFF));
+        assert_eq!(word(cfg + 4), size);
+        assert_eq!(word(cfg + 32), wl_crc(&image[cfg..cfg + 32]));
+
+        // From 1MB the records need a second sector per state copy
+        assert_eq!(WlLayout::new(0x10_0000)?.state_size, 2 * SECTOR_SIZE);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_fat_round_trip() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let large: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        let files = vec![
+            host_file(temp_dir.path(), "a long file name.txt", b"hello"),
+            host_file(temp_dir.path(), "logs/boot.log", &large),
+        ];
+        let dirs = vec!["empty".to_string(), "logs".to_string()];
+
+        for wear_levelling in [false, true] {
+            let config = FatConfig { wear_levelling };
+            let image = build(&files, &dirs, 0x8_0000, &config)?;
+            assert_eq!(has_wear_levelling(&image), wear_levelling);
+
+            let contents = read(&image)?;
+            assert_eq!(contents.dirs, dirs);
+            let paths: Vec<_> = contents.files.iter().map(|f| f.path.as_str()).collect();
+            assert_eq!(paths, ["a long file name.txt", "logs/boot.log"]);
+            assert_eq!(contents.files[1].data, large);
+        }
+
+        assert!(read(&vec![0xFF; 0x8_0000]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_fat_after_wear_levelling_moves() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let files = vec![host_file(temp_dir.path(), "log.txt", b"moved")];
+        let size = 0x8_0000;
+        let built = build(
+            &files,
+            &[],
+            size,
+            &FatConfig {
+                wear_levelling: true,
+            },
+        )?;
+        let layout = WlLayout::new(size)?;
+        let flash_size = layout.flash_size;
+
+        // The firmware has moved the dummy sector through the whole volume
+        // once and then two sectors further: move_count 1, pos 2
+        let (pos, move_count) = (2, 1);
+        let mut image = built.clone();
+        for sector in 0..flash_size / SECTOR_SIZE {
+            let logical = (SECTOR_SIZE + sector * SECTOR_SIZE) as usize;
+            let mut addr =
+                (flash_size - move_count * SECTOR_SIZE + sector * SECTOR_SIZE) % flash_size;
+            if addr >= pos * SECTOR_SIZE {
+                addr += SECTOR_SIZE;
+            }
+            image[addr as usize..(addr + SECTOR_SIZE) as usize]
+                .copy_from_slice(&built[logical..logical + SECTOR_SIZE as usize]);
+        }
+        for at in [layout.state1(size), layout.state2(size)] {
+            image[at + 8..at + 12].copy_from_slice(&move_count.to_le_bytes());
+            let crc = wl_crc(&image[at..at + WL_STATE_LEN - 4]);
+            image[at + WL_STATE_LEN - 4..at + WL_STATE_LEN].copy_from_slice(&crc.to_le_bytes());
+        }
+        let state1 = layout.state1(size);
+        let device_id = word(&image, state1 + 28);
+        for record in 0..pos {
+            for i in 0..4 {
+                let at = state1 + WL_STATE_LEN + (record * WL_WRITE_SIZE + 4 * i) as usize;
+                let crc = wl_crc(&device_id.wrapping_add(record * 4 + i).to_le_bytes());
+                image[at..at + 4].copy_from_slice(&crc.to_le_bytes());
+            }
+        }
+
+        assert_ne!(image, built);
+        let contents = read(&image)?;
+        assert_eq!(contents.files[0].path, "log.txt");
+        assert_eq!(contents.files[0].data, b"moved");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_fat_errors() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let big = host_file(temp_dir.path(), "big.bin", &vec![0xAB; 0x20000]);
+        let config = FatConfig::default();
+
+        assert!(build(&[], &[], 0x10_0800, &config).is_err());
+        let error = build(&[big], &[], 0x10000, &config).unwrap_err();
+        assert!(error.to_string().contains("big.bin"));
+        assert!(
+            build(
+                &[],
+                &[],
+                0x4000,
+                &FatConfig {
+                    wear_levelling: true
+                }
+            )
+            .is_err()
+        );
+    }
+}
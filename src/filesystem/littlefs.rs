@@ -0,0 +1,832 @@
+//! LittleFS v2 images, as `esp_littlefs` mounts them
+//!
+//! Every directory is a metadata pair holding one commit: a name and a struct
+//! tag per entry, then the tail to the next pair. All pairs are threaded on
+//! that tail list from the root pair in blocks 0 and 1, which is how LittleFS
+//! finds in-use blocks. File contents are CTZ skip-lists of whole blocks.
+
+use super::{HostFile, ImageContents, ImageFile};
+use crate::Result;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+pub const BLOCK_SIZE: u32 = 4096;
+/// On-disk version 2.0, readable by every LittleFS 2.x release
+const DISK_VERSION: u32 = 0x0002_0000;
+const FILE_MAX: u32 = 2_147_483_647;
+const ATTR_MAX: u32 = 1022;
+const NULL_BLOCK: u32 = 0xFFFF_FFFF;
+
+const TYPE_REG: u16 = 0x001;
+const TYPE_DIR: u16 = 0x002;
+const TYPE_SUPERBLOCK: u16 = 0x0FF;
+const TYPE_DIRSTRUCT: u16 = 0x200;
+const TYPE_INLINESTRUCT: u16 = 0x201;
+const TYPE_CTZSTRUCT: u16 = 0x202;
+const TYPE_CREATE: u16 = 0x401;
+const TYPE_DELETE: u16 = 0x4FF;
+const TYPE_CRC: u16 = 0x500;
+const TYPE_SOFTTAIL: u16 = 0x600;
+const TYPE_HARDTAIL: u16 = 0x601;
+/// Tag ID of tags that don't belong to an entry
+const NO_ID: u16 = 0x3FF;
+/// Largest tag data size; 0x3FF marks deleted tags
+const MAX_TAG_SIZE: usize = 0x3FE;
+/// Entries per metadata pair before LittleFS would split it
+const MAX_PAIR_ENTRIES: usize = 0xFF;
+
+/// Build options, matching the sdkconfig of the firmware that mounts the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LittlefsConfig {
+    /// CONFIG_LITTLEFS_OBJ_NAME_LEN, recorded as the superblock's name_max
+    pub name_max: u32,
+    /// CONFIG_LITTLEFS_PAGE_SIZE; commits are padded to it so the firmware can append
+    pub page_size: u32,
+}
+
+impl Default for LittlefsConfig {
+    fn default() -> Self {
+        Self {
+            name_max: 64,
+            page_size: 256,
+        }
+    }
+}
+
+/// `lfs_crc`: CRC-32 without the final inversion
+fn crc(crc: u32, data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new_with_initial(!crc);
+    hasher.update(data);
+    !hasher.finalize()
+}
+
+fn tag(ty: u16, id: u16, size: usize) -> u32 {
+    ((ty as u32) << 20) | ((id as u32) << 10) | size as u32
+}
+
+/// One commit in a fresh metadata block
+struct Commit {
+    data: Vec<u8>,
+    crc: u32,
+    ptag: u32,
+}
+
+impl Commit {
+    fn new(revision: u32) -> Self {
+        let mut commit = Self {
+            data: Vec::new(),
+            crc: 0xFFFF_FFFF,
+            ptag: 0xFFFF_FFFF,
+        };
+        commit.push(&revision.to_le_bytes());
+        commit
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.crc = crc(self.crc, bytes);
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Tags are stored big-endian, XORed with the previous tag
+    fn tag(&mut self, ty: u16, id: u16, data: &[u8]) {
+        let tag = tag(ty, id, data.len());
+        self.push(&(tag ^ self.ptag).to_be_bytes());
+        self.push(data);
+        self.ptag = tag;
+    }
+
+    /// Close the commit with CRC tags padding it to the program size, like
+    /// `lfs_dir_commitcrc` on erased flash
+    fn finish(mut self, page_size: usize) -> Vec<u8> {
+        let end = (self.data.len() + 8).next_multiple_of(page_size);
+        while self.data.len() < end {
+            let off = self.data.len() + 4;
+            let mut noff = (end - off).min(MAX_TAG_SIZE) + off;
+            if noff < end {
+                noff = noff.min(end - 8);
+            }
+
+            let tag = tag(TYPE_CRC, NO_ID, noff - off);
+            self.push(&(tag ^ self.ptag).to_be_bytes());
+            self.data.extend_from_slice(&self.crc.to_le_bytes());
+            self.data.resize(noff, 0xFF);
+            self.ptag = tag;
+            self.crc = 0xFFFF_FFFF;
+        }
+        self.data
+    }
+}
+
+enum EntryKind {
+    File { source: usize },
+    Dir { path: String },
+}
+
+struct Entry {
+    name: String,
+    kind: EntryKind,
+}
+
+/// Metadata tag bytes an entry takes: name tag plus an 8-byte struct tag
+fn entry_size(entry: &Entry) -> usize {
+    4 + entry.name.len() + 4 + 8
+}
+
+/// Build a `size` byte LittleFS image holding `files` and the (possibly empty) `dirs`
+pub fn build(
+    files: &[HostFile],
+    dirs: &[String],
+    size: u32,
+    config: &LittlefsConfig,
+) -> Result<Vec<u8>> {
+    if size < 2 * BLOCK_SIZE || !size.is_multiple_of(BLOCK_SIZE) {
+        return Err(anyhow!(
+            "partition size {} is not a multiple of the {} byte LittleFS block holding at least 2 blocks",
+            size,
+            BLOCK_SIZE
+        ));
+    }
+    let page_size = config.page_size as usize;
+    if page_size == 0 || !(BLOCK_SIZE as usize).is_multiple_of(page_size) {
+        return Err(anyhow!(
+            "LittleFS page size {} must divide the block size {}",
+            page_size,
+            BLOCK_SIZE
+        ));
+    }
+    let block_count = size / BLOCK_SIZE;
+
+    // Directory tree, every directory listing its entries in LittleFS name order
+    let mut all_dirs: BTreeSet<String> = dirs.iter().cloned().collect();
+    all_dirs.insert(String::new());
+    for file in files {
+        let mut parent = file.path.as_str();
+        while let Some((dir, _)) = parent.rsplit_once('/') {
+            all_dirs.insert(dir.to_string());
+            parent = dir;
+        }
+    }
+    let parent_and_name = |path: &str| match path.rsplit_once('/') {
+        Some((parent, name)) => (parent.to_string(), name.to_string()),
+        None => (String::new(), path.to_string()),
+    };
+
+    let mut tree: BTreeMap<String, Vec<Entry>> =
+        all_dirs.iter().map(|d| (d.clone(), Vec::new())).collect();
+    for dir in all_dirs.iter().filter(|d| !d.is_empty()) {
+        let (parent, name) = parent_and_name(dir);
+        tree.get_mut(&parent).unwrap().push(Entry {
+            name,
+            kind: EntryKind::Dir { path: dir.clone() },
+        });
+    }
+    for (source, file) in files.iter().enumerate() {
+        let (parent, name) = parent_and_name(&file.path);
+        tree.get_mut(&parent).unwrap().push(Entry {
+            name,
+            kind: EntryKind::File { source },
+        });
+    }
+    for entries in tree.values_mut() {
+        entries.sort_by(|a, b| a.name.as_bytes().cmp(b.name.as_bytes()));
+        if let Some(entry) = entries
+            .iter()
+            .find(|e| e.name.len() > config.name_max as usize)
+        {
+            return Err(anyhow!(
+                "name '{}' is longer than the name_max of {} bytes",
+                entry.name,
+                config.name_max
+            ));
+        }
+    }
+
+    // Split directories over several pairs when a commit would fill more than
+    // half a block, as LittleFS compaction does
+    let budget = BLOCK_SIZE as usize / 2;
+    let mut pairs: Vec<(String, std::ops::Range<usize>)> = Vec::new();
+    let mut order = vec![String::new()];
+    while let Some(dir) = order.pop() {
+        let entries = &tree[&dir];
+        // Revision, tail and CRC tags, plus the superblock in the root
+        let overhead = 4 + 12 + 8 + if dir.is_empty() { 12 + 28 } else { 0 };
+        let mut start = 0;
+        let mut used = overhead;
+        for (index, entry) in entries.iter().enumerate() {
+            if index > start
+                && (used + entry_size(entry) > budget || index - start >= MAX_PAIR_ENTRIES)
+            {
+                pairs.push((dir.clone(), start..index));
+                start = index;
+                used = overhead;
+            }
+            used += entry_size(entry);
+        }
+        pairs.push((dir.clone(), start..entries.len()));
+
+        // Depth first, subdirectories in name order
+        for entry in entries.iter().rev() {
+            if let EntryKind::Dir { path } = &entry.kind {
+                order.push(path.clone());
+            }
+        }
+    }
+
+    let mut contents = Vec::with_capacity(files.len());
+    let mut needed = 2 * pairs.len() as u32;
+    for file in files {
+        let data = file.read()?;
+        needed += ctz_blocks(data.len());
+        contents.push(data);
+    }
+    if needed > block_count {
+        return Err(anyhow!(
+            "{} files and {} directories need {} blocks of {} bytes but the partition holds {}",
+            files.len(),
+            all_dirs.len() - 1,
+            needed,
+            BLOCK_SIZE,
+            block_count
+        ));
+    }
+
+    // Root pair in blocks 0 and 1, other pairs and file blocks after it
+    let mut image = vec![0xFF; size as usize];
+    let pair_blocks: Vec<[u32; 2]> = (0..pairs.len() as u32)
+        .map(|i| [2 * i, 2 * i + 1])
+        .collect();
+    let mut next_block = 2 * pairs.len() as u32;
+    let first_pair: BTreeMap<&str, [u32; 2]> = pairs
+        .iter()
+        .zip(&pair_blocks)
+        .rev()
+        .map(|((dir, _), blocks)| (dir.as_str(), *blocks))
+        .collect();
+
+    let mut file_structs = Vec::with_capacity(contents.len());
+    for data in &contents {
+        if data.is_empty() {
+            file_structs.push(None);
+            continue;
+        }
+        let head = write_ctz(&mut image, data, &mut next_block);
+        file_structs.push(Some((head, data.len() as u32)));
+    }
+
+    for (index, (dir, range)) in pairs.iter().enumerate() {
+        let mut commit = Commit::new(1);
+        let mut id = 0u16;
+        if dir.is_empty() && range.start == 0 {
+            commit.tag(TYPE_SUPERBLOCK, 0, b"littlefs");
+            let superblock: Vec<u8> = [
+                DISK_VERSION,
+                BLOCK_SIZE,
+                block_count,
+                config.name_max,
+                FILE_MAX,
+                ATTR_MAX,
+            ]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+            commit.tag(TYPE_INLINESTRUCT, 0, &superblock);
+            id = 1;
+        }
+
+        for entry in &tree[dir][range.clone()] {
+            match &entry.kind {
+                EntryKind::Dir { path } => {
+                    let blocks = first_pair[path.as_str()];
+                    commit.tag(TYPE_DIR, id, entry.name.as_bytes());
+                    commit.tag(TYPE_DIRSTRUCT, id, &pair_bytes(blocks));
+                }
+                EntryKind::File { source } => {
+                    commit.tag(TYPE_REG, id, entry.name.as_bytes());
+                    match file_structs[*source] {
+                        Some((head, size)) => {
+                            commit.tag(TYPE_CTZSTRUCT, id, &pair_bytes([head, size]))
+                        }
+                        None => commit.tag(TYPE_INLINESTRUCT, id, &[]),
+                    }
+                }
+            }
+            id += 1;
+        }
+
+        // Pairs of one directory are joined by hard tails, directories by soft tails
+        if let Some((next_dir, _)) = pairs.get(index + 1) {
+            let ty = if next_dir == dir {
+                TYPE_HARDTAIL
+            } else {
+                TYPE_SOFTTAIL
+            };
+            commit.tag(ty, NO_ID, &pair_bytes(pair_blocks[index + 1]));
+        }
+
+        let block = commit.finish(page_size);
+        let start = (pair_blocks[index][0] * BLOCK_SIZE) as usize;
+        image[start..start + block.len()].copy_from_slice(&block);
+    }
+
+    Ok(image)
+}
+
+fn pair_bytes(pair: [u32; 2]) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&pair[0].to_le_bytes());
+    bytes[4..].copy_from_slice(&pair[1].to_le_bytes());
+    bytes
+}
+
+/// Pointers at the start of block `index` of a CTZ list: to blocks
+/// `index - 1`, `index - 2`, `index - 4`, ... `index - 2^ctz(index)`
+fn ctz_pointers(index: u32) -> usize {
+    if index == 0 {
+        0
+    } else {
+        index.trailing_zeros() as usize + 1
+    }
+}
+
+fn ctz_blocks(size: usize) -> u32 {
+    let mut remaining = size;
+    let mut index = 0;
+    while remaining > 0 {
+        remaining = remaining.saturating_sub(BLOCK_SIZE as usize - 4 * ctz_pointers(index));
+        index += 1;
+    }
+    index
+}
+
+/// Write `data` as a CTZ skip-list and return the head (last) block
+fn write_ctz(image: &mut [u8], data: &[u8], next_block: &mut u32) -> u32 {
+    let mut blocks: Vec<u32> = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let index = blocks.len() as u32;
+        let block = *next_block;
+        *next_block += 1;
+
+        let start = (block * BLOCK_SIZE) as usize;
+        let pointers = ctz_pointers(index);
+        for i in 0..pointers {
+            let target = blocks[index as usize - (1 << i)];
+            image[start + 4 * i..start + 4 * i + 4].copy_from_slice(&target.to_le_bytes());
+        }
+        let chunk = rest.len().min(BLOCK_SIZE as usize - 4 * pointers);
+        image[start + 4 * pointers..start + 4 * pointers + chunk].copy_from_slice(&rest[..chunk]);
+        rest = &rest[chunk..];
+        blocks.push(block);
+    }
+    *blocks.last().unwrap_or(&NULL_BLOCK)
+}
+
+/// An ID of a metadata pair, as the commits so far leave it
+#[derive(Debug, Clone, Default)]
+struct PairEntry {
+    /// Name tag type: regular file, directory or superblock
+    ty: Option<u16>,
+    name: Vec<u8>,
+    structure: Option<(u16, Vec<u8>)>,
+}
+
+/// A metadata pair after its last valid commit
+#[derive(Debug, Clone, Default)]
+struct PairState {
+    entries: Vec<PairEntry>,
+    /// Next pair, and whether it continues this directory (hard tail)
+    tail: Option<([u32; 2], bool)>,
+}
+
+impl PairState {
+    fn entry_mut(&mut self, id: usize) -> &mut PairEntry {
+        if self.entries.len() <= id {
+            self.entries.resize(id + 1, PairEntry::default());
+        }
+        &mut self.entries[id]
+    }
+
+    fn apply(&mut self, ty: u16, id: usize, data: &[u8]) {
+        match ty & 0x700 {
+            0x000 => {
+                let entry = self.entry_mut(id);
+                entry.ty = Some(ty);
+                entry.name = data.to_vec();
+            }
+            0x200 => self.entry_mut(id).structure = Some((ty, data.to_vec())),
+            0x400 if ty == TYPE_CREATE => {
+                let at = id.min(self.entries.len());
+                self.entries.insert(at, PairEntry::default());
+            }
+            0x400 if ty == TYPE_DELETE && id < self.entries.len() => {
+                self.entries.remove(id);
+            }
+            0x600 if data.len() == 8 => {
+                let pair = [word(data, 0), word(data, 4)];
+                self.tail = Some((pair, ty & 1 == 1));
+            }
+            // User attributes and global state don't affect the tree
+            _ => {}
+        }
+    }
+}
+
+fn word(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+/// State after the last commit of `block` whose CRC matches, as `lfs_dir_fetch` finds it
+fn read_block(block: &[u8]) -> Option<PairState> {
+    let mut state = PairState::default();
+    let mut valid = None;
+    let mut crc_value = crc(0xFFFF_FFFF, &block[..4]);
+    let mut ptag = 0xFFFF_FFFFu32;
+    let mut off = 4;
+
+    while off + 4 <= block.len() {
+        let raw = &block[off..off + 4];
+        crc_value = crc(crc_value, raw);
+        let tag = u32::from_be_bytes(raw.try_into().unwrap()) ^ ptag;
+        if tag & 0x8000_0000 != 0 {
+            break;
+        }
+        let ty = ((tag >> 20) & 0x7FF) as u16;
+        let id = ((tag >> 10) & 0x3FF) as usize;
+        let size = (tag & 0x3FF) as usize;
+        // Deleted tags have no data
+        let len = if size == 0x3FF { 0 } else { size };
+        if off + 4 + len > block.len() {
+            break;
+        }
+        let data = &block[off + 4..off + 4 + len];
+        ptag = tag;
+        off += 4 + len;
+
+        if ty & 0x780 == TYPE_CRC {
+            if len < 4 || crc_value != word(data, 0) {
+                break;
+            }
+            // The chunk bit flips the valid bit expected of the next commit
+            ptag ^= ((ty & 1) as u32) << 31;
+            crc_value = 0xFFFF_FFFF;
+            valid = Some(state.clone());
+            continue;
+        }
+
+        crc_value = crc(crc_value, data);
+        if size != 0x3FF {
+            state.apply(ty, id, data);
+        }
+    }
+
+    valid
+}
+
+/// Read the newer block of a pair that holds a valid commit
+fn read_pair(image: &[u8], pair: [u32; 2]) -> Result<PairState> {
+    let block = |index: u32| {
+        let start = index as usize * BLOCK_SIZE as usize;
+        image
+            .get(start..start + BLOCK_SIZE as usize)
+            .ok_or_else(|| anyhow!("metadata block {} is outside the image", index))
+    };
+    let blocks = [block(pair[0])?, block(pair[1])?];
+    let revision = |b: &[u8]| word(b, 0);
+    let newer = if (revision(blocks[1]).wrapping_sub(revision(blocks[0])) as i32) > 0 {
+        1
+    } else {
+        0
+    };
+
+    read_block(blocks[newer])
+        .or_else(|| read_block(blocks[1 - newer]))
+        .ok_or_else(|| {
+            anyhow!(
+                "metadata pair {{{}, {}}} has no valid commit",
+                pair[0],
+                pair[1]
+            )
+        })
+}
+
+/// Follow the pointers back from the head of a CTZ list and read it
+fn read_ctz(image: &[u8], head: u32, size: usize) -> Result<Vec<u8>> {
+    let count = ctz_blocks(size) as usize;
+    let mut blocks = vec![head; count];
+    for index in (1..count).rev() {
+        let start = blocks[index] as usize * BLOCK_SIZE as usize;
+        let pointer = image
+            .get(start..start + 4)
+            .ok_or_else(|| anyhow!("file block {} is outside the image", blocks[index]))?;
+        blocks[index - 1] = word(pointer, 0);
+    }
+
+    let mut data = Vec::with_capacity(size);
+    for (index, block) in blocks.into_iter().enumerate() {
+        let start = block as usize * BLOCK_SIZE as usize + 4 * ctz_pointers(index as u32);
+        let chunk = (size - data.len()).min(BLOCK_SIZE as usize - 4 * ctz_pointers(index as u32));
+        let bytes = image
+            .get(start..start + chunk)
+            .ok_or_else(|| anyhow!("file block {} is outside the image", block))?;
+        data.extend_from_slice(bytes);
+    }
+    Ok(data)
+}
+
+/// Files and directories of a LittleFS image with 4KB blocks, including
+/// images the firmware has written to since
+pub fn read(image: &[u8]) -> Result<ImageContents> {
+    if image.len() < 2 * BLOCK_SIZE as usize {
+        return Err(anyhow!(
+            "image of {} bytes is too small for LittleFS",
+            image.len()
+        ));
+    }
+    let root = read_pair(image, [0, 1])?;
+    let superblock = root
+        .entries
+        .first()
+        .filter(|e| e.ty == Some(TYPE_SUPERBLOCK) && e.name == b"littlefs")
+        .ok_or_else(|| anyhow!("no LittleFS superblock in blocks 0 and 1"))?;
+    if let Some((TYPE_INLINESTRUCT, data)) = &superblock.structure
+        && data.len() >= 8
+        && word(data, 4) != BLOCK_SIZE
+    {
+        return Err(anyhow!(
+            "LittleFS block size {} isn't the {} bytes ESP-IDF uses",
+            word(data, 4),
+            BLOCK_SIZE
+        ));
+    }
+
+    let mut contents = ImageContents::default();
+    let mut visited = HashSet::new();
+    let mut pending = vec![([0, 1], String::new())];
+    while let Some((mut pair, dir)) = pending.pop() {
+        loop {
+            if !visited.insert(pair) {
+                return Err(anyhow!(
+                    "metadata pair {{{}, {}}} is linked twice",
+                    pair[0],
+                    pair[1]
+                ));
+            }
+            let state = read_pair(image, pair)?;
+            for entry in &state.entries {
+                let name = String::from_utf8_lossy(&entry.name);
+                let path = if dir.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("{}/{}", dir, name)
+                };
+                match (entry.ty, &entry.structure) {
+                    (Some(TYPE_DIR), Some((TYPE_DIRSTRUCT, data))) if data.len() == 8 => {
+                        contents.dirs.push(path.clone());
+                        pending.push(([word(data, 0), word(data, 4)], path));
+                    }
+                    (Some(TYPE_REG), Some((TYPE_INLINESTRUCT, data))) => {
+                        contents.files.push(ImageFile {
+                            path,
+                            data: data.clone(),
+                        });
+                    }
+                    (Some(TYPE_REG), Some((TYPE_CTZSTRUCT, data))) if data.len() == 8 => {
+                        let data = read_ctz(image, word(data, 0), word(data, 4) as usize)
+                            .map_err(|e| anyhow!("'{}': {}", path, e))?;
+                        contents.files.push(ImageFile { path, data });
+                    }
+                    // The superblock, and entries of an interrupted create
+                    _ => {}
+                }
+            }
+            match state.tail {
+                Some((next, true)) => pair = next,
+                _ => break,
+            }
+        }
+    }
+
+    contents.dirs.sort();
+    contents.files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host_file(dir: &std::path::Path, path: &str, data: &[u8]) -> HostFile {
+        let source = dir.join(path.replace('/', "_"));
+        std::fs::write(&source, data).unwrap();
+        HostFile {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    /// Tags of the first commit of a metadata block, checking every CRC
+    fn read_commit(block: &[u8]) -> Vec<(u16, u16, Vec<u8>)> {
+        let mut tags = Vec::new();
+        let mut crc_value = crc(0xFFFF_FFFF, &block[..4]);
+        let mut ptag = 0xFFFF_FFFFu32;
+        let mut off = 4;
+        loop {
+            let raw = &block[off..off + 4];
+            crc_value = crc(crc_value, raw);
+            let tag = u32::from_be_bytes(raw.try_into().unwrap()) ^ ptag;
+            assert_eq!(tag >> 31, 0, "invalid tag at {}", off);
+            let ty = ((tag >> 20) & 0x7FF) as u16;
+            let id = ((tag >> 10) & 0x3FF) as u16;
+            let size = (tag & 0x3FF) as usize;
+            let data = &block[off + 4..off + 4 + size];
+            if ty == TYPE_CRC {
+                assert_eq!(u32::from_le_bytes(data[..4].try_into().unwrap()), crc_value);
+                assert!((off + 4 + size).is_multiple_of(256));
+                return tags;
+            }
+            crc_value = crc(crc_value, data);
+            tags.push((ty, id, data.to_vec()));
+            ptag = tag;
+            off += 4 + size;
+        }
+    }
+
+    #[test]
+    fn test_crc_matches_lfs_crc() {
+        // lfs_crc(0xffffffff, "123456789") is the unfinalized CRC-32 check value
+        assert_eq!(crc(0xFFFF_FFFF, b"123456789"), !0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_build_littlefs_tree() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let big: Vec<u8> = (0..9000u32).map(|i| (i % 251) as u8).collect();
+        let files = vec![
+            host_file(temp_dir.path(), "empty.txt", b""),
+            host_file(temp_dir.path(), "img/logo.bin", &big),
+            host_file(temp_dir.path(), "a.txt", b"hello"),
+        ];
+        let image = build(
+            &files,
+            &["fonts".to_string()],
+            0x10000,
+            &LittlefsConfig::default(),
+        )?;
+
+        // Root: superblock, then a.txt, empty.txt, fonts, img in name order
+        let root = read_commit(&image[..4096]);
+        assert_eq!(root[0], (TYPE_SUPERBLOCK, 0, b"littlefs".to_vec()));
+        let superblock = &root[1].2;
+        assert_eq!(word(superblock, 0), DISK_VERSION);
+        assert_eq!(word(superblock, 4), BLOCK_SIZE);
+        assert_eq!(word(superblock, 8), 16);
+        assert_eq!(word(superblock, 12), 64);
+
+        let names: Vec<_> = root
+            .iter()
+            .filter(|(ty, _, _)| *ty == TYPE_REG || *ty == TYPE_DIR)
+            .map(|(_, id, name)| (*id, String::from_utf8(name.clone()).unwrap()))
+            .collect();
+        assert_eq!(
+            names,
+            [(1, "a.txt"), (2, "empty.txt"), (3, "fonts"), (4, "img")]
+                .map(|(id, name)| (id, name.to_string()))
+        );
+        assert!(root.contains(&(TYPE_INLINESTRUCT, 2, Vec::new())));
+        assert!(root.contains(&(TYPE_DIRSTRUCT, 3, pair_bytes([2, 3]).to_vec())));
+        assert!(root.contains(&(TYPE_DIRSTRUCT, 4, pair_bytes([4, 5]).to_vec())));
+        assert!(root.contains(&(TYPE_SOFTTAIL, NO_ID, pair_bytes([2, 3]).to_vec())));
+
+        // fonts is empty and links on to img, the last pair in the chain
+        let fonts = read_commit(&image[2 * 4096..3 * 4096]);
+        assert_eq!(fonts, [(TYPE_SOFTTAIL, NO_ID, pair_bytes([4, 5]).to_vec())]);
+        let img = read_commit(&image[4 * 4096..5 * 4096]);
+        assert_eq!(img[0], (TYPE_REG, 0, b"logo.bin".to_vec()));
+        assert_eq!(img.len(), 2);
+
+        // logo.bin: 4096 bytes in block 6, 4092 in block 7 (-> 6), 812 in block 8 (-> 7, 6)
+        let (head, size) = (word(&img[1].2, 0), word(&img[1].2, 4));
+        assert_eq!((head, size), (8, 9000));
+        assert_eq!(&image[6 * 4096..7 * 4096], &big[..4096]);
+        assert_eq!(word(&image, 7 * 4096), 6);
+        assert_eq!(&image[7 * 4096 + 4..8 * 4096], &big[4096..8188]);
+        assert_eq!((word(&image, 8 * 4096), word(&image, 8 * 4096 + 4)), (7, 6));
+        assert_eq!(&image[8 * 4096 + 8..8 * 4096 + 8 + 812], &big[8188..]);
+
+        // a.txt follows logo.bin's blocks (files are written in input order)
+        let a = root
+            .iter()
+            .find(|(ty, id, _)| *ty == TYPE_CTZSTRUCT && *id == 1)
+            .unwrap();
+        assert_eq!((word(&a.2, 0), word(&a.2, 4)), (9, 5));
+        assert_eq!(&image[9 * 4096..9 * 4096 + 5], b"hello");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_littlefs_splits_large_directories() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let files: Vec<_> = (0..100)
+            .map(|i| host_file(temp_dir.path(), &format!("log/entry-{:03}.txt", i), b""))
+            .collect();
+        let image = build(&files, &[], 0x10000, &LittlefsConfig::default())?;
+
+        let first = read_commit(&image[2 * 4096..3 * 4096]);
+        let (ty, _, data) = first.last().unwrap();
+        assert_eq!(
+            (*ty, data.as_slice()),
+            (TYPE_HARDTAIL, &pair_bytes([4, 5])[..])
+        );
+        let second = read_commit(&image[4 * 4096..5 * 4096]);
+        let count = |tags: &[(u16, u16, Vec<u8>)]| tags.iter().filter(|t| t.0 == TYPE_REG).count();
+        assert_eq!(count(&first) + count(&second), 100);
+        assert_eq!(
+            second[0],
+            (
+                TYPE_REG,
+                0,
+                format!("entry-{:03}.txt", count(&first)).into_bytes()
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_littlefs_round_trip() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let big: Vec<u8> = (0..20_000u32).map(|i| (i % 253) as u8).collect();
+        let mut files = vec![
+            host_file(temp_dir.path(), "empty.txt", b""),
+            host_file(temp_dir.path(), "img/logo.bin", &big),
+        ];
+        files.extend(
+            (0..100).map(|i| host_file(temp_dir.path(), &format!("log/{:03}.txt", i), b"x")),
+        );
+        let image = build(
+            &files,
+            &["fonts".to_string()],
+            0x10_0000,
+            &LittlefsConfig::default(),
+        )?;
+
+        let contents = read(&image)?;
+        assert_eq!(contents.dirs, ["fonts", "img", "log"]);
+        assert_eq!(contents.files.len(), 102);
+        assert_eq!(contents.files[0].path, "empty.txt");
+        assert!(contents.files[0].data.is_empty());
+        assert_eq!(contents.files[1].path, "img/logo.bin");
+        assert_eq!(contents.files[1].data, big);
+        assert_eq!(contents.files[101].path, "log/099.txt");
+
+        assert!(read(&vec![0xFF; 0x10000]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_littlefs_later_commits() -> Result<()> {
+        let mut image = build(&[], &[], 0x10000, &LittlefsConfig::default())?;
+        assert!(read(&image)?.files.is_empty());
+
+        // A newer revision of the root in block 1, as the firmware writes on
+        // compaction, that creates two files and deletes the first again
+        let mut commit = Commit::new(2);
+        commit.tag(TYPE_SUPERBLOCK, 0, b"littlefs");
+        commit.tag(TYPE_REG, 1, b"kept.txt");
+        commit.tag(TYPE_INLINESTRUCT, 1, b"kept");
+        commit.tag(TYPE_CREATE, 1, &[]);
+        commit.tag(TYPE_REG, 1, b"gone.txt");
+        commit.tag(TYPE_INLINESTRUCT, 1, b"gone");
+        commit.tag(TYPE_DELETE, 1, &[]);
+        let block = commit.finish(256);
+        image[4096..4096 + block.len()].copy_from_slice(&block);
+
+        let contents = read(&image)?;
+        let paths: Vec<_> = contents.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["kept.txt"]);
+        assert_eq!(contents.files[0].data, b"kept");
+
+        // A bad CRC in the newer block falls back to the older one
+        image[4096 + 10] ^= 0xFF;
+        assert!(read(&image)?.files.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_littlefs_errors() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config = LittlefsConfig::default();
+        let big = host_file(temp_dir.path(), "big.bin", &vec![0; 3 * 4096]);
+        assert!(build(std::slice::from_ref(&big), &[], 0x4000, &config).is_err());
+        assert!(build(std::slice::from_ref(&big), &[], 0x5000, &config).is_err());
+        assert!(build(&[big], &[], 0x6000, &config).is_ok());
+
+        let long = host_file(temp_dir.path(), &"n".repeat(65), b"x");
+        assert!(build(&[long], &[], 0x4000, &config).is_err());
+        assert!(build(&[], &[], 0x4800, &config).is_err());
+    }
+}
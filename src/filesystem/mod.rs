@@ -0,0 +1,342 @@
+//! Filesystem images built from host directories for data partitions, and
+//! read back from images and flash dumps
+
+pub mod fat;
+pub mod littlefs;
+pub mod spiffs;
+
+use crate::Result;
+use crate::config::Config;
+use crate::util::sha256_hex;
+use anyhow::anyhow;
+use esp_idf_part::{DataType, Partition, PartitionTable, SubType};
+use log::info;
+use serde::Serialize;
+use spiffs::SpiffsConfig;
+use std::path::{Component, Path, PathBuf};
+use walkdir::WalkDir;
+
+/// A regular file below the source directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostFile {
+    /// Path inside the filesystem, `/`-separated and without a leading `/`
+    pub path: String,
+    pub source: PathBuf,
+}
+
+impl HostFile {
+    pub fn read(&self) -> Result<Vec<u8>> {
+        std::fs::read(&self.source).map_err(|e| anyhow!("Failed to read {:?}: {}", self.source, e))
+    }
+}
+
+/// A file read back from a filesystem image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageFile {
+    /// Path inside the filesystem, `/`-separated and without a leading `/`
+    pub path: String,
+    pub data: Vec<u8>,
+}
+
+/// Directories and files found in a filesystem image, each sorted by path
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImageContents {
+    pub dirs: Vec<String>,
+    pub files: Vec<ImageFile>,
+}
+
+impl ImageContents {
+    /// Recreate the tree below `dir`; paths that would leave it are refused
+    pub fn extract(&self, dir: &Path) -> Result<()> {
+        let target = |path: &str| -> Result<PathBuf> {
+            let relative = Path::new(path);
+            if path.is_empty()
+                || !relative
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_)))
+            {
+                return Err(anyhow!("Refusing to extract unsafe path '{}'", path));
+            }
+            Ok(dir.join(relative))
+        };
+        let create_dir = |path: &Path| {
+            std::fs::create_dir_all(path).map_err(|e| anyhow!("Failed to create {:?}: {}", path, e))
+        };
+
+        create_dir(dir)?;
+        for path in &self.dirs {
+            create_dir(&target(path)?)?;
+        }
+        for file in &self.files {
+            let path = target(&file.path)?;
+            if let Some(parent) = path.parent() {
+                create_dir(parent)?;
+            }
+            std::fs::write(&path, &file.data)
+                .map_err(|e| anyhow!("Failed to write {:?}: {}", path, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Listing of one filesystem partition, as `fs-list --json` prints it
+#[derive(Debug, Clone, Serialize)]
+pub struct FsListing {
+    pub partition: String,
+    pub filesystem: String,
+    pub offset: u32,
+    pub size: u32,
+    pub dirs: Vec<String>,
+    pub files: Vec<ListedFile>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListedFile {
+    pub path: String,
+    pub size: usize,
+    pub sha256: String,
+}
+
+impl FsListing {
+    pub fn new(partition: &Partition, contents: &ImageContents) -> Self {
+        Self {
+            partition: partition.name(),
+            filesystem: partition.subtype().to_string(),
+            offset: partition.offset(),
+            size: partition.size(),
+            dirs: contents.dirs.clone(),
+            files: contents
+                .files
+                .iter()
+                .map(|file| ListedFile {
+                    path: file.path.clone(),
+                    size: file.data.len(),
+                    sha256: sha256_hex(&file.data),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Whether `read_partition` understands the partition's subtype
+pub fn is_readable(partition: &Partition) -> bool {
+    matches!(
+        partition.subtype(),
+        SubType::Data(DataType::Spiffs | DataType::Littlefs | DataType::Fat)
+    )
+}
+
+/// Contents of a filesystem partition; `data` is the whole partition
+pub fn read_partition(
+    partition: &Partition,
+    data: &[u8],
+    spiffs_config: &SpiffsConfig,
+) -> Result<ImageContents> {
+    let contents = match partition.subtype() {
+        SubType::Data(DataType::Spiffs) => spiffs::read(data, spiffs_config),
+        SubType::Data(DataType::Littlefs) => littlefs::read(data),
+        SubType::Data(DataType::Fat) => fat::read(data),
+        other => {
+            return Err(anyhow!(
+                "Partition '{}' is {}, not a spiffs, littlefs or fat partition",
+                partition.name(),
+                other
+            ));
+        }
+    };
+    contents.map_err(|e| anyhow!("Partition '{}': {}", partition.name(), e))
+}
+
+/// Every regular file below `dir`, sorted by path so images are reproducible
+pub fn collect_files(dir: &Path) -> Result<Vec<HostFile>> {
+    Ok(walk(dir, |entry| entry.file_type().is_file())?
+        .into_iter()
+        .map(|(path, source)| HostFile { path, source })
+        .collect())
+}
+
+/// Every directory below `dir`, including empty ones, sorted by path
+pub fn collect_dirs(dir: &Path) -> Result<Vec<String>> {
+    Ok(
+        walk(dir, |entry| entry.file_type().is_dir() && entry.depth() > 0)?
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect(),
+    )
+}
+
+/// `/`-separated relative path and host path of the entries below `dir` that match
+fn walk(
+    dir: &Path,
+    include: impl Fn(&walkdir::DirEntry) -> bool,
+) -> Result<Vec<(String, PathBuf)>> {
+    if !dir.is_dir() {
+        return Err(anyhow!("Filesystem source {:?} is not a directory", dir));
+    }
+
+    let mut entries = Vec::new();
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry.map_err(|e| anyhow!("Failed to read {:?}: {}", dir, e))?;
+        if !include(&entry) {
+            continue;
+        }
+
+        let relative = entry
+            .path()
+            .strip_prefix(dir)
+            .expect("walkdir yields paths below its root");
+        let path = relative
+            .components()
+            .map(|c| {
+                c.as_os_str()
+                    .to_str()
+                    .ok_or_else(|| anyhow!("File name {:?} is not UTF-8", entry.path()))
+            })
+            .collect::<Result<Vec<_>>>()?
+            .join("/");
+        entries.push((path, entry.path().to_path_buf()));
+    }
+
+    Ok(entries)
+}
+
+/// First data partition of the given subtype
+fn find_partition(partition_table: &PartitionTable, subtype: DataType) -> Option<&Partition> {
+    partition_table
+        .partitions()
+        .iter()
+        .find(|p| p.subtype() == SubType::Data(subtype))
+}
+
+/// Name, offset and content of every filesystem partition built from a directory
+pub fn build_images(
+    partition_table: &PartitionTable,
+    config: &Config,
+) -> Result<Vec<(String, u32, Vec<u8>)>> {
+    let mut images = Vec::new();
+
+    if let Some(dir) = &config.spiffs_dir {
+        let partition = find_partition(partition_table, DataType::Spiffs)
+            .ok_or_else(|| anyhow!("--spiffs needs a spiffs partition in the table"))?;
+        let files = collect_files(dir)?;
+        let data = spiffs::build(&files, partition.size(), &config.spiffs).map_err(|e| {
+            anyhow!(
+                "SPIFFS image of {:?} for partition '{}': {}",
+                dir,
+                partition.name(),
+                e
+            )
+        })?;
+        info!(
+            "Built SPIFFS image of {} files for partition '{}'",
+            files.len(),
+            partition.name()
+        );
+        images.push((partition.name(), partition.offset(), data));
+    }
+
+    if let Some(dir) = &config.littlefs_dir {
+        let partition = find_partition(partition_table, DataType::Littlefs)
+            .ok_or_else(|| anyhow!("--littlefs needs a littlefs partition in the table"))?;
+        let files = collect_files(dir)?;
+        let dirs = collect_dirs(dir)?;
+        let data =
+            littlefs::build(&files, &dirs, partition.size(), &config.littlefs).map_err(|e| {
+                anyhow!(
+                    "LittleFS image of {:?} for partition '{}': {}",
+                    dir,
+                    partition.name(),
+                    e
+                )
+            })?;
+        info!(
+            "Built LittleFS image of {} files for partition '{}'",
+            files.len(),
+            partition.name()
+        );
+        images.push((partition.name(), partition.offset(), data));
+    }
+
+    if let Some(dir) = &config.fat_dir {
+        let partition = find_partition(partition_table, DataType::Fat)
+            .ok_or_else(|| anyhow!("--fat needs a fat partition in the table"))?;
+        let files = collect_files(dir)?;
+        let dirs = collect_dirs(dir)?;
+        let data = fat::build(&files, &dirs, partition.size(), &config.fat).map_err(|e| {
+            anyhow!(
+                "FAT image of {:?} for partition '{}': {}",
+                dir,
+                partition.name(),
+                e
+            )
+        })?;
+        info!(
+            "Built FAT image of {} files for partition '{}'{}",
+            files.len(),
+            partition.name(),
+            if config.fat.wear_levelling {
+                " with wear levelling"
+            } else {
+                ""
+            }
+        );
+        images.push((partition.name(), partition.offset(), data));
+    }
+
+    Ok(images)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_files_sorted() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        std::fs::create_dir_all(temp_dir.path().join("web/img"))?;
+        std::fs::create_dir(temp_dir.path().join("empty"))?;
+        std::fs::write(temp_dir.path().join("web/index.html"), "<html>")?;
+        std::fs::write(temp_dir.path().join("web/img/logo.png"), [0x89])?;
+        std::fs::write(temp_dir.path().join("config.json"), "{}")?;
+
+        let files = collect_files(temp_dir.path())?;
+        let paths: Vec<_> = files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["config.json", "web/img/logo.png", "web/index.html"]);
+        assert_eq!(files[1].read()?, vec![0x89]);
+        assert_eq!(collect_dirs(temp_dir.path())?, ["empty", "web", "web/img"]);
+
+        assert!(collect_files(&temp_dir.path().join("missing")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_contents() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let contents = ImageContents {
+            dirs: vec!["empty".to_string()],
+            files: vec![ImageFile {
+                path: "web/index.html".to_string(),
+                data: b"<html>".to_vec(),
+            }],
+        };
+        let target = temp_dir.path().join("storage");
+        contents.extract(&target)?;
+        assert!(target.join("empty").is_dir());
+        assert_eq!(std::fs::read(target.join("web/index.html"))?, b"<html>");
+
+        for path in ["../escape", "/etc/passwd", "a/../../b", ""] {
+            let unsafe_contents = ImageContents {
+                dirs: Vec::new(),
+                files: vec![ImageFile {
+                    path: path.to_string(),
+                    data: Vec::new(),
+                }],
+            };
+            assert!(unsafe_contents.extract(&target).is_err(), "{}", path);
+        }
+        assert!(!temp_dir.path().join("escape").exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,554 @@
+//! SPIFFS images in the layout of ESP-IDF's `spiffsgen.py`
+//!
+//! Each block starts with object lookup pages (one object ID per page of the
+//! block) followed by object index and data pages. Files are written in path
+//! order, each as its index page followed by the data pages it lists.
+
+use super::{HostFile, ImageContents, ImageFile};
+use crate::Result;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// `spiffs_obj_id`, `spiffs_span_ix` and `spiffs_page_ix` are 16 bits in ESP-IDF
+const ID_LEN: usize = 2;
+/// `spiffs_page_header`: object ID, span index and flags, packed
+const PAGE_HEADER_LEN: usize = 2 * ID_LEN + 1;
+/// Index page headers pad the page header to 4 bytes
+const PAGE_HEADER_LEN_ALIGNED: usize = 8;
+const FLAG_USED_FINAL_INDEX: u8 = 0xF8;
+const FLAG_USED_FINAL: u8 = 0xFC;
+/// Page header flag bits; SPIFFS clears them as a page goes through its states
+const FLAG_USED: u8 = 1 << 0;
+const FLAG_FINAL: u8 = 1 << 1;
+const FLAG_INDEX: u8 = 1 << 2;
+const FLAG_IXDELE: u8 = 1 << 6;
+const FLAG_DELETED: u8 = 1 << 7;
+const OBJ_ID_FREE: u16 = 0xFFFF;
+const OBJ_ID_DELETED: u16 = 0x0000;
+const TYPE_FILE: u8 = 1;
+/// Set in the object ID of index pages
+const OBJ_ID_INDEX_FLAG: u16 = 0x8000;
+const MAGIC: u32 = 0x2014_0529;
+
+/// Build options, matching the sdkconfig of the firmware that mounts the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpiffsConfig {
+    /// CONFIG_SPIFFS_PAGE_SIZE
+    pub page_size: u32,
+    /// Logical block size; ESP-IDF uses the 4KB flash sector
+    pub block_size: u32,
+    /// CONFIG_SPIFFS_OBJ_NAME_LEN, including the terminating NUL
+    pub obj_name_len: usize,
+    /// CONFIG_SPIFFS_META_LENGTH
+    pub meta_len: usize,
+}
+
+impl Default for SpiffsConfig {
+    fn default() -> Self {
+        Self {
+            page_size: 256,
+            block_size: 4096,
+            obj_name_len: 32,
+            meta_len: 4,
+        }
+    }
+}
+
+/// Page counts derived from a `SpiffsConfig`
+#[derive(Debug, Clone, Copy)]
+struct Geometry {
+    page_size: usize,
+    pages_per_block: usize,
+    lookup_pages: usize,
+    /// Object index header: page header, size, type, name and metadata
+    index_header_len: usize,
+    /// Data page indices in the first index page of a file
+    head_entries: usize,
+    /// Data page indices in every further index page
+    entries: usize,
+}
+
+impl Geometry {
+    fn new(config: &SpiffsConfig) -> Result<Self> {
+        let page_size = config.page_size as usize;
+        let block_size = config.block_size as usize;
+        if !page_size.is_power_of_two() || page_size < 64 {
+            return Err(anyhow!(
+                "SPIFFS page size {} must be a power of two of at least 64",
+                page_size
+            ));
+        }
+        if block_size < 2 * page_size || !block_size.is_multiple_of(page_size) {
+            return Err(anyhow!(
+                "SPIFFS block size {} must be a multiple of the page size {} holding at least 2 pages",
+                block_size,
+                page_size
+            ));
+        }
+
+        let pages_per_block = block_size / page_size;
+        let index_header_len =
+            PAGE_HEADER_LEN_ALIGNED + 4 + 1 + config.obj_name_len + config.meta_len;
+        if index_header_len + ID_LEN > page_size {
+            return Err(anyhow!(
+                "SPIFFS object name ({}) and metadata ({}) lengths leave no room in a {} byte page",
+                config.obj_name_len,
+                config.meta_len,
+                page_size
+            ));
+        }
+
+        Ok(Self {
+            page_size,
+            pages_per_block,
+            lookup_pages: (pages_per_block * ID_LEN).div_ceil(page_size),
+            index_header_len,
+            head_entries: (page_size - index_header_len) / ID_LEN,
+            entries: (page_size - PAGE_HEADER_LEN_ALIGNED) / ID_LEN,
+        })
+    }
+
+    fn usable_pages_per_block(&self) -> usize {
+        self.pages_per_block - self.lookup_pages
+    }
+
+    fn data_len(&self) -> usize {
+        self.page_size - PAGE_HEADER_LEN
+    }
+
+    /// Index and data pages of a file
+    fn file_pages(&self, size: usize) -> (usize, usize) {
+        let data_pages = size.div_ceil(self.data_len());
+        let index_pages = 1 + data_pages
+            .saturating_sub(self.head_entries)
+            .div_ceil(self.entries);
+        (index_pages, data_pages)
+    }
+}
+
+/// Allocates usable pages in order and records their object IDs for the lookup pages
+struct PageWriter {
+    geometry: Geometry,
+    image: Vec<u8>,
+    /// Object IDs of the used pages of each block
+    lookup: Vec<Vec<u16>>,
+    next: usize,
+}
+
+impl PageWriter {
+    /// Take the next free page for `obj_id` and return its page index
+    fn allocate(&mut self, obj_id: u16) -> usize {
+        let usable = self.geometry.usable_pages_per_block();
+        let block = self.next / usable;
+        let page =
+            block * self.geometry.pages_per_block + self.geometry.lookup_pages + self.next % usable;
+        self.lookup[block].push(obj_id);
+        self.next += 1;
+        page
+    }
+
+    fn page_mut(&mut self, page: usize) -> &mut [u8] {
+        let start = page * self.geometry.page_size;
+        &mut self.image[start..start + self.geometry.page_size]
+    }
+
+    fn write_header(&mut self, page: usize, obj_id: u16, span: usize, flags: u8) {
+        let bytes = self.page_mut(page);
+        bytes[0..2].copy_from_slice(&obj_id.to_le_bytes());
+        bytes[2..4].copy_from_slice(&(span as u16).to_le_bytes());
+        bytes[4] = flags;
+    }
+
+    /// Lookup pages of every block, with the magic in the second to last
+    /// entry (the last one holds the erase count, left erased)
+    fn finish(mut self) -> Vec<u8> {
+        let block_count = self.lookup.len();
+        let entries = self.geometry.lookup_pages * self.geometry.page_size / ID_LEN;
+        let block_size = self.geometry.pages_per_block * self.geometry.page_size;
+
+        for (block, ids) in std::mem::take(&mut self.lookup).into_iter().enumerate() {
+            let start = block * block_size;
+            for (slot, id) in ids.iter().enumerate() {
+                let at = start + slot * ID_LEN;
+                self.image[at..at + ID_LEN].copy_from_slice(&id.to_le_bytes());
+            }
+            if ids.len() + 2 <= entries {
+                let magic = MAGIC ^ self.geometry.page_size as u32 ^ (block_count - block) as u32;
+                let at = start + (entries - 2) * ID_LEN;
+                self.image[at..at + ID_LEN].copy_from_slice(&(magic as u16).to_le_bytes());
+            }
+        }
+
+        self.image
+    }
+}
+
+/// Build a `size` byte SPIFFS image holding `files` as `/<path>`
+pub fn build(files: &[HostFile], size: u32, config: &SpiffsConfig) -> Result<Vec<u8>> {
+    let geometry = Geometry::new(config)?;
+    if size == 0 || !size.is_multiple_of(config.block_size) {
+        return Err(anyhow!(
+            "partition size {} is not a multiple of the SPIFFS block size {}",
+            size,
+            config.block_size
+        ));
+    }
+    let block_count = (size / config.block_size) as usize;
+
+    let mut contents = Vec::with_capacity(files.len());
+    let mut needed = 0;
+    for file in files {
+        let name = format!("/{}", file.path);
+        if name.len() >= config.obj_name_len {
+            return Err(anyhow!(
+                "name '{}' is {} bytes, longer than the object name length {} allows (the terminator takes a byte)",
+                name,
+                name.len(),
+                config.obj_name_len
+            ));
+        }
+        let data = file.read()?;
+        let (index_pages, data_pages) = geometry.file_pages(data.len());
+        needed += index_pages + data_pages;
+        contents.push((name, data));
+    }
+    if contents.len() >= OBJ_ID_INDEX_FLAG as usize {
+        return Err(anyhow!(
+            "{} files exceed the SPIFFS object IDs",
+            contents.len()
+        ));
+    }
+
+    let available = block_count * geometry.usable_pages_per_block();
+    if needed > available {
+        return Err(anyhow!(
+            "{} files need {} pages of {} bytes but the partition holds {}",
+            files.len(),
+            needed,
+            geometry.page_size,
+            available
+        ));
+    }
+
+    let mut writer = PageWriter {
+        geometry,
+        image: vec![0xFF; size as usize],
+        lookup: vec![Vec::new(); block_count],
+        next: 0,
+    };
+
+    for (obj_id, (name, data)) in (1u16..).zip(&contents) {
+        let mut chunks = data.chunks(geometry.data_len()).enumerate().peekable();
+        let mut index_span = 0;
+
+        loop {
+            let index_page = writer.allocate(obj_id | OBJ_ID_INDEX_FLAG);
+            let limit = if index_span == 0 {
+                geometry.head_entries
+            } else {
+                geometry.entries
+            };
+
+            let mut data_pages = Vec::new();
+            while data_pages.len() < limit
+                && let Some((span, chunk)) = chunks.next()
+            {
+                let page = writer.allocate(obj_id);
+                writer.write_header(page, obj_id, span, FLAG_USED_FINAL);
+                writer.page_mut(page)[PAGE_HEADER_LEN..PAGE_HEADER_LEN + chunk.len()]
+                    .copy_from_slice(chunk);
+                data_pages.push(page as u16);
+            }
+
+            writer.write_header(
+                index_page,
+                obj_id | OBJ_ID_INDEX_FLAG,
+                index_span,
+                FLAG_USED_FINAL_INDEX,
+            );
+            let bytes = writer.page_mut(index_page);
+            let mut at = PAGE_HEADER_LEN_ALIGNED;
+            if index_span == 0 {
+                bytes[at..at + 4].copy_from_slice(&(data.len() as u32).to_le_bytes());
+                bytes[at + 4] = TYPE_FILE;
+                at += 5;
+                let name_and_meta = &mut bytes[at..geometry.index_header_len];
+                name_and_meta.fill(0);
+                name_and_meta[..name.len()].copy_from_slice(name.as_bytes());
+                at = geometry.index_header_len;
+            }
+            for page in data_pages {
+                bytes[at..at + ID_LEN].copy_from_slice(&page.to_le_bytes());
+                at += ID_LEN;
+            }
+
+            index_span += 1;
+            if chunks.peek().is_none() {
+                break;
+            }
+        }
+    }
+
+    Ok(writer.finish())
+}
+
+/// Files of a SPIFFS image, found through the object index pages
+///
+/// Works on images written by the firmware too: deleted pages are skipped and
+/// data pages are taken from the index, so pages left over by an interrupted
+/// write are ignored. `config` must match the one the image was made with.
+pub fn read(image: &[u8], config: &SpiffsConfig) -> Result<ImageContents> {
+    let geometry = Geometry::new(config)?;
+    let block_size = config.block_size as usize;
+    if image.is_empty() || !image.len().is_multiple_of(block_size) {
+        return Err(anyhow!(
+            "image of {} bytes is not a multiple of the SPIFFS block size {}",
+            image.len(),
+            block_size
+        ));
+    }
+    let block_count = image.len() / block_size;
+    let entries = geometry.lookup_pages * geometry.page_size / ID_LEN;
+    let page = |index: usize| &image[index * geometry.page_size..(index + 1) * geometry.page_size];
+    let word = |bytes: &[u8], at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+
+    let magic = (MAGIC ^ geometry.page_size as u32 ^ block_count as u32) as u16;
+    if word(image, (entries - 2) * ID_LEN) != magic {
+        return Err(anyhow!(
+            "no SPIFFS magic for {} byte pages and {} byte blocks in the first block",
+            config.page_size,
+            config.block_size
+        ));
+    }
+
+    // Index pages by object ID and span, and every live data page
+    let mut index_pages: HashMap<(u16, u16), usize> = HashMap::new();
+    let mut data_pages: HashMap<usize, (u16, u16)> = HashMap::new();
+    for block in 0..block_count {
+        let lookup = &image[block * block_size..];
+        for slot in 0..geometry.usable_pages_per_block() {
+            let obj_id = word(lookup, slot * ID_LEN);
+            if obj_id == OBJ_ID_FREE || obj_id == OBJ_ID_DELETED {
+                continue;
+            }
+            let index = block * geometry.pages_per_block + geometry.lookup_pages + slot;
+            let bytes = page(index);
+            let (header_id, span, flags) = (word(bytes, 0), word(bytes, 2), bytes[4]);
+            if header_id != obj_id
+                || flags & (FLAG_USED | FLAG_FINAL) != 0
+                || flags & FLAG_DELETED == 0
+            {
+                continue;
+            }
+            if flags & FLAG_INDEX == 0 {
+                if flags & FLAG_IXDELE != 0 {
+                    index_pages.insert((obj_id & !OBJ_ID_INDEX_FLAG, span), index);
+                }
+            } else {
+                data_pages.insert(index, (obj_id, span));
+            }
+        }
+    }
+
+    let mut heads: Vec<(u16, usize)> = index_pages
+        .iter()
+        .filter(|((_, span), _)| *span == 0)
+        .map(|((obj_id, _), index)| (*obj_id, *index))
+        .collect();
+    heads.sort();
+
+    let mut contents = ImageContents::default();
+    for (obj_id, head) in heads {
+        let header = &page(head)[PAGE_HEADER_LEN_ALIGNED..geometry.index_header_len];
+        let name_bytes = &header[5..5 + config.obj_name_len];
+        let name_len = name_bytes
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(name_bytes.len());
+        let name = String::from_utf8_lossy(&name_bytes[..name_len]);
+        let path = name.trim_start_matches('/').to_string();
+        // Files created but never written keep the erased size
+        let size = match u32::from_le_bytes(header[..4].try_into().unwrap()) {
+            u32::MAX => 0,
+            size => size as usize,
+        };
+
+        let mut data = Vec::with_capacity(size);
+        let mut span = 0;
+        while data.len() < size {
+            let (index_span, entry) = if span < geometry.head_entries {
+                (0, geometry.index_header_len + span * ID_LEN)
+            } else {
+                let rest = span - geometry.head_entries;
+                (
+                    1 + rest / geometry.entries,
+                    PAGE_HEADER_LEN_ALIGNED + rest % geometry.entries * ID_LEN,
+                )
+            };
+            let index = index_pages
+                .get(&(obj_id, index_span as u16))
+                .ok_or_else(|| anyhow!("'{}' is missing index page {}", path, index_span))?;
+            let data_page = word(page(*index), entry) as usize;
+            if data_pages.get(&data_page) != Some(&(obj_id, span as u16)) {
+                return Err(anyhow!(
+                    "'{}' is missing data page {} of {} bytes",
+                    path,
+                    span,
+                    size
+                ));
+            }
+            let chunk = (size - data.len()).min(geometry.data_len());
+            data.extend_from_slice(&page(data_page)[PAGE_HEADER_LEN..PAGE_HEADER_LEN + chunk]);
+            span += 1;
+        }
+
+        contents.files.push(ImageFile { path, data });
+    }
+
+    contents.files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host_file(dir: &std::path::Path, path: &str, data: &[u8]) -> HostFile {
+        let source = dir.join(path.replace('/', "_"));
+        std::fs::write(&source, data).unwrap();
+        HostFile {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    fn word(image: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([image[at], image[at + 1]])
+    }
+
+    #[test]
+    fn test_build_spiffs_layout() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let big: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let files = vec![
+            host_file(temp_dir.path(), "a.txt", b"hello"),
+            host_file(temp_dir.path(), "web/big.bin", &big),
+        ];
+        let image = build(&files, 0x4000, &SpiffsConfig::default())?;
+        assert_eq!(image.len(), 0x4000);
+
+        // Lookup page: index + data for a.txt, index + 2 data pages for big.bin
+        assert_eq!(word(&image, 0), 0x8001);
+        assert_eq!(word(&image, 2), 0x0001);
+        assert_eq!(word(&image, 4), 0x8002);
+        assert_eq!(word(&image, 6), 0x0002);
+        assert_eq!(word(&image, 8), 0x0002);
+        assert_eq!(word(&image, 10), 0xFFFF);
+
+        // Magic in the second to last lookup entry of every block, erase count erased
+        for block in 0..4 {
+            let magic = (MAGIC ^ 256 ^ (4 - block) as u32) as u16;
+            assert_eq!(word(&image, block * 4096 + 252), magic);
+            assert_eq!(word(&image, block * 4096 + 254), 0xFFFF);
+        }
+
+        // Object index header of a.txt in page 1, data in page 2
+        let index = &image[256..512];
+        assert_eq!(
+            &index[..5],
+            &[0x01, 0x80, 0x00, 0x00, FLAG_USED_FINAL_INDEX]
+        );
+        assert_eq!(&index[8..13], &[5, 0, 0, 0, TYPE_FILE]);
+        assert_eq!(&index[13..19], b"/a.txt");
+        assert!(index[19..13 + 32 + 4].iter().all(|&b| b == 0));
+        assert_eq!(word(index, 49), 2);
+        assert_eq!(&image[512..517], &[0x01, 0x00, 0x00, 0x00, FLAG_USED_FINAL]);
+        assert_eq!(&image[517..522], b"hello");
+        assert_eq!(image[522], 0xFF);
+
+        // big.bin spans two data pages, the second with span index 1
+        assert_eq!(word(&image[768..], 49), 4);
+        assert_eq!(word(&image[768..], 51), 5);
+        assert_eq!(
+            &image[5 * 256..5 * 256 + 5],
+            &[0x02, 0x00, 0x01, 0x00, FLAG_USED_FINAL]
+        );
+        assert_eq!(&image[5 * 256 + 5..5 * 256 + 54], &big[251..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_spiffs_spans_blocks_and_index_pages() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let config = SpiffsConfig::default();
+        let geometry = Geometry::new(&config)?;
+        assert_eq!(geometry.lookup_pages, 1);
+        assert_eq!(geometry.head_entries, 103);
+        assert_eq!(geometry.entries, 124);
+
+        // 110 data pages need a second index page and cross several blocks
+        let data = vec![0x5A; 110 * geometry.data_len()];
+        let files = vec![host_file(temp_dir.path(), "log.bin", &data)];
+        let image = build(&files, 0x10000, &config)?;
+
+        // The second index page follows the first 103 data pages: usable page 104
+        let second_index = 6 * 16 + 1 + 104 % 15;
+        let page = &image[second_index * 256..(second_index + 1) * 256];
+        assert_eq!(&page[..5], &[0x01, 0x80, 0x01, 0x00, FLAG_USED_FINAL_INDEX]);
+        assert_eq!(word(page, 8), (7 * 16 + 1) as u16);
+
+        assert!(build(&files, 0x7000, &config).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_spiffs_round_trip() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let config = SpiffsConfig::default();
+        let large: Vec<u8> = (0..110 * 251).map(|i| (i % 249) as u8).collect();
+        let files = vec![
+            host_file(temp_dir.path(), "a.txt", b"hello"),
+            host_file(temp_dir.path(), "empty", b""),
+            host_file(temp_dir.path(), "web/large.bin", &large),
+        ];
+        let mut image = build(&files, 0x10000, &config)?;
+
+        let contents = read(&image, &config)?;
+        assert!(contents.dirs.is_empty());
+        let paths: Vec<_> = contents.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["a.txt", "empty", "web/large.bin"]);
+        assert_eq!(contents.files[0].data, b"hello");
+        assert_eq!(contents.files[2].data, large);
+
+        // Deleting a.txt's index page, as SPIFFS does, drops the file
+        image[256 + 4] &= !FLAG_DELETED;
+        let contents = read(&image, &config)?;
+        assert_eq!(contents.files.len(), 2);
+
+        // Another page size doesn't match the magic
+        let other = SpiffsConfig {
+            page_size: 512,
+            ..config
+        };
+        assert!(read(&image, &other).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_spiffs_errors() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let long = host_file(temp_dir.path(), &"n".repeat(31), b"x");
+        assert!(build(&[long], 0x4000, &SpiffsConfig::default()).is_err());
+        assert!(build(&[], 0x4800, &SpiffsConfig::default()).is_err());
+
+        let config = SpiffsConfig {
+            page_size: 100,
+            ..Default::default()
+        };
+        assert!(build(&[], 0x4000, &config).is_err());
+    }
+}
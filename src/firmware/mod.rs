@@ -1,20 +1,24 @@
 use anyhow::{Result, anyhow};
 use std::collections::BTreeMap;
-use std::fs;
+use std::fs::File;
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use walkdir::WalkDir;
 
 #[derive(Debug, Clone)]
 pub struct FirmwareBinary {
     pub name: String,
     pub path: PathBuf,
-    pub data: Vec<u8>,
+    /// Shared with every region placed from it; processing never copies it
+    pub data: Arc<[u8]>,
     pub size: u32,
     pub prefix: u32,
 }
 
 impl FirmwareBinary {
-    pub fn new(name: String, path: PathBuf, data: Vec<u8>, prefix: u32) -> Self {
+    pub fn new(name: String, path: PathBuf, data: impl Into<Arc<[u8]>>, prefix: u32) -> Self {
+        let data = data.into();
         let size = data.len() as u32;
         Self {
             name,
@@ -42,7 +46,7 @@ impl FirmwareLoader {
             .into_iter()
             .filter_map(|e| e.ok())
             .filter(|e| {
-                e.file_type().is_file() && e.path().extension().map_or(false, |ext| ext == "bin")
+                e.file_type().is_file() && e.path().extension().is_some_and(|ext| ext == "bin")
             })
         {
             let path = entry.path();
@@ -51,12 +55,12 @@ impl FirmwareLoader {
                 .and_then(|n| n.to_str())
                 .ok_or_else(|| anyhow!("Invalid filename: {:?}", path))?;
 
-            if let Some(prefix) = Self::extract_prefix(filename)? {
-                if let Ok(data) = fs::read(path) {
-                    let name = Self::extract_name(filename)?;
-                    let firmware = FirmwareBinary::new(name, path.to_path_buf(), data, prefix);
-                    firmware_map.insert(prefix, firmware);
-                }
+            if let Some(prefix) = Self::extract_prefix(filename)?
+                && let Ok(data) = Self::read_shared(path)
+            {
+                let name = Self::extract_name(filename)?;
+                let firmware = FirmwareBinary::new(name, path.to_path_buf(), data, prefix);
+                firmware_map.insert(prefix, firmware);
             }
         }
 
@@ -84,6 +88,16 @@ impl FirmwareLoader {
         Ok(firmwares)
     }
 
+    /// Read a file straight into the shared buffer the layouts use, so
+    /// loading doesn't copy it out of a `Vec`
+    fn read_shared(path: &Path) -> std::io::Result<Arc<[u8]>> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        let mut data: Arc<[u8]> = std::iter::repeat_n(0, len).collect();
+        file.read_exact(Arc::get_mut(&mut data).expect("the buffer is not shared yet"))?;
+        Ok(data)
+    }
+
     fn extract_prefix(filename: &str) -> Result<Option<u32>> {
         // Extract numerical prefix from filename (e.g., "01-bootloader.bin" -> 1)
         let parts: Vec<&str> = filename.split('-').collect();
@@ -92,10 +106,10 @@ impl FirmwareLoader {
         }
 
         let first_part = parts[0];
-        if first_part.len() >= 2 {
-            if let Ok(prefix) = u32::from_str_radix(first_part, 10) {
-                return Ok(Some(prefix));
-            }
+        if first_part.len() >= 2
+            && let Ok(prefix) = first_part.parse::<u32>()
+        {
+            return Ok(Some(prefix));
         }
 
         Ok(None)
@@ -178,6 +192,8 @@ mod tests {
         assert_eq!(firmwares[1].prefix, 2);
         assert_eq!(firmwares[2].name, "final");
         assert_eq!(firmwares[2].prefix, 10);
+        assert_eq!(&firmwares[0].data[..], b"bootloader_data");
+        assert_eq!(firmwares[1].size, 8);
 
         Ok(())
     }
@@ -0,0 +1,384 @@
+//! Sparse model of the flash: the regions placement produces, and renderers
+//! that turn them into image bytes
+
+use crate::Result;
+use anyhow::anyhow;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Erased flash, written a block at a time to fill the gaps between regions
+static ERASED: [u8; 64 * 1024] = [0xFF; 64 * 1024];
+
+/// Flash sector, the unit `trim_erased` drops
+const SECTOR_SIZE: usize = 0x1000;
+
+/// What a region holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionRole {
+    Bootloader,
+    PartitionTable,
+    /// Firmware in a factory or OTA slot, or app content from the partitions file
+    App,
+    /// Content of a data partition: raw files, NVS, filesystems, otadata
+    Data,
+}
+
+/// Where the bytes of a region come from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegionSource {
+    /// A firmware or partition content file; app images are processed first
+    File(PathBuf),
+    /// Built from the configuration
+    Generated,
+}
+
+/// Bytes at a fixed flash offset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub offset: u32,
+    /// Shared with the firmware or content it came from; cloning a layout
+    /// doesn't copy it
+    pub data: Arc<[u8]>,
+    /// Erased bytes after `data` that still belong to the region, so a
+    /// mostly empty partition isn't held in memory
+    pub erased: u32,
+    pub role: RegionRole,
+    /// Partition name, or `bootloader` / `partition table`
+    pub name: String,
+    pub source: RegionSource,
+}
+
+impl Region {
+    /// End of the region; `FlashLayout::push` only accepts regions that
+    /// end within the 32-bit address space
+    pub fn end(&self) -> u32 {
+        self.checked_end().expect("region ends beyond 4GB")
+    }
+
+    fn checked_end(&self) -> Option<u32> {
+        let len = u32::try_from(self.data.len())
+            .ok()?
+            .checked_add(self.erased)?;
+        self.offset.checked_add(len)
+    }
+}
+
+/// Split the trailing erased sectors off generated partition content;
+/// returns the bytes to keep and the length of erased flash after them
+pub fn trim_erased(mut data: Vec<u8>) -> (Vec<u8>, u32) {
+    let used = data.iter().rposition(|&b| b != 0xFF).map_or(0, |i| i + 1);
+    let keep = used.next_multiple_of(SECTOR_SIZE).min(data.len());
+    let erased = (data.len() - keep) as u32;
+    data.truncate(keep);
+    data.shrink_to_fit();
+    (data, erased)
+}
+
+/// Regions of one flash image, kept in offset order and never overlapping;
+/// everything between them is erased flash
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FlashLayout {
+    regions: Vec<Region>,
+}
+
+impl FlashLayout {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a region; regions may arrive in any order but must not overlap.
+    /// Empty regions are rejected, so the neighbours of the insertion point
+    /// are the only regions that can overlap a new one.
+    pub fn push(&mut self, region: Region) -> Result<()> {
+        if region.checked_end().is_none() {
+            return Err(anyhow!(
+                "{} at 0x{:X} ends beyond the 32-bit address space",
+                region.name,
+                region.offset
+            ));
+        }
+        if region.end() == region.offset {
+            return Err(anyhow!("{} at 0x{:X} is empty", region.name, region.offset));
+        }
+        let index = self.regions.partition_point(|r| r.offset < region.offset);
+        let neighbours = [index.checked_sub(1), Some(index)];
+        for other in neighbours
+            .into_iter()
+            .flatten()
+            .filter_map(|i| self.regions.get(i))
+        {
+            if region.offset < other.end() && other.offset < region.end() {
+                return Err(anyhow!(
+                    "{} at 0x{:X}..0x{:X} overlaps {} at 0x{:X}..0x{:X}",
+                    region.name,
+                    region.offset,
+                    region.end(),
+                    other.name,
+                    other.offset,
+                    other.end()
+                ));
+            }
+        }
+        self.regions.insert(index, region);
+        Ok(())
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// End of the last region, the length of a minimal image
+    pub fn end(&self) -> u32 {
+        self.regions.last().map_or(0, Region::end)
+    }
+
+    /// Image up to the end of the last region
+    pub fn render_minimal(&self) -> Vec<u8> {
+        let mut image = vec![0xFF; self.end() as usize];
+        for region in &self.regions {
+            write_to_flash(&mut image, region.offset, &region.data)
+                .expect("the image spans every region");
+        }
+        image
+    }
+
+    /// Image of the whole flash, erased where no region is
+    pub fn render_padded(&self, flash_size: u32) -> Result<Vec<u8>> {
+        let mut image = vec![0xFF; flash_size as usize];
+        for region in &self.regions {
+            write_to_flash(&mut image, region.offset, &region.data)
+                .map_err(|e| anyhow!("{}: {}", region.name, e))?;
+        }
+        Ok(image)
+    }
+
+    /// Stream the image to `writer` in offset order, producing the gaps as
+    /// it goes, so only the regions are ever in memory; `pad_to` extends it
+    /// to the flash size like `render_padded`. Returns the image length.
+    pub fn write_to<W: Write>(&self, writer: &mut W, pad_to: Option<u32>) -> Result<u64> {
+        let end = match pad_to {
+            Some(flash_size) if self.end() > flash_size => {
+                let region = self
+                    .regions
+                    .last()
+                    .expect("a layout with an end has regions");
+                return Err(anyhow!(
+                    "{}: Write exceeds flash image bounds: offset={}, size={}, image_size={}",
+                    region.name,
+                    region.offset,
+                    region.end() - region.offset,
+                    flash_size
+                ));
+            }
+            Some(flash_size) => flash_size,
+            None => self.end(),
+        };
+
+        let mut position = 0;
+        for region in &self.regions {
+            write_erased(writer, region.offset - position)?;
+            writer
+                .write_all(&region.data)
+                .map_err(|e| anyhow!("Failed to write {}: {}", region.name, e))?;
+            write_erased(writer, region.erased)?;
+            position = region.end();
+        }
+        write_erased(writer, end - position)?;
+        Ok(end as u64)
+    }
+}
+
+fn write_erased<W: Write>(writer: &mut W, mut len: u32) -> Result<()> {
+    while len > 0 {
+        let chunk = len.min(ERASED.len() as u32);
+        writer
+            .write_all(&ERASED[..chunk as usize])
+            .map_err(|e| anyhow!("Failed to write erased flash: {}", e))?;
+        len -= chunk;
+    }
+    Ok(())
+}
+
+fn write_to_flash(flash_image: &mut [u8], offset: u32, data: &[u8]) -> Result<()> {
+    let start = offset as usize;
+    let end = start + data.len();
+
+    if end > flash_image.len() {
+        return Err(anyhow!(
+            "Write exceeds flash image bounds: offset={}, size={}, image_size={}",
+            offset,
+            data.len(),
+            flash_image.len()
+        ));
+    }
+
+    flash_image[start..end].copy_from_slice(data);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(name: &str, offset: u32, data: Vec<u8>) -> Region {
+        Region {
+            offset,
+            data: data.into(),
+            erased: 0,
+            role: RegionRole::Data,
+            name: name.to_string(),
+            source: RegionSource::Generated,
+        }
+    }
+
+    #[test]
+    fn test_write_to_flash() -> Result<()> {
+        let mut flash_image = vec![0xFF; 1024];
+        let data = vec![0x42, 0x43, 0x44];
+
+        write_to_flash(&mut flash_image, 10, &data)?;
+
+        assert_eq!(flash_image[8..13], [0xFF, 0xFF, 0x42, 0x43, 0x44]);
+        assert_eq!(flash_image[0..8], [0xFF; 8]);
+        assert_eq!(flash_image[13..], [0xFF; 1024 - 13]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_to_flash_overflow() {
+        let mut flash_image = vec![0xFF; 100];
+        let data = vec![0x42; 10];
+
+        let result = write_to_flash(&mut flash_image, 95, &data);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_flash_layout_renderers() -> Result<()> {
+        let mut layout = FlashLayout::new();
+        layout.push(region("b", 0x20, vec![2; 4]))?;
+        layout.push(region("a", 0x10, vec![1; 4]))?;
+        assert_eq!(layout.regions()[0].name, "a");
+        assert_eq!(layout.end(), 0x24);
+
+        let minimal = layout.render_minimal();
+        assert_eq!(minimal.len(), 0x24);
+        assert_eq!(minimal[0x10..0x14], [1; 4]);
+        assert_eq!(minimal[0x14..0x20], [0xFF; 12]);
+
+        let padded = layout.render_padded(0x40)?;
+        assert_eq!(&padded[..0x24], &minimal[..]);
+        assert_eq!(padded[0x24..], [0xFF; 0x1C]);
+        assert!(layout.render_padded(0x22).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flash_layout_streams_like_renderers() -> Result<()> {
+        let mut layout = FlashLayout::new();
+        layout.push(region("a", 0x1000, vec![1; 0x100]))?;
+        layout.push(region("b", 0x30000, vec![2; 0x20000]))?;
+
+        let mut minimal = Vec::new();
+        assert_eq!(layout.write_to(&mut minimal, None)?, 0x50000);
+        assert_eq!(minimal, layout.render_minimal());
+
+        let mut padded = Vec::new();
+        assert_eq!(layout.write_to(&mut padded, Some(0x80000))?, 0x80000);
+        assert_eq!(padded, layout.render_padded(0x80000)?);
+
+        // Nothing is written when the regions don't fit the flash
+        let mut short = Vec::new();
+        assert!(layout.write_to(&mut short, Some(0x40000)).is_err());
+        assert!(short.is_empty());
+
+        // The padding of a large flash never needs a buffer of its size
+        let size = layout.write_to(&mut std::io::sink(), Some(128 * 1024 * 1024))?;
+        assert_eq!(size, 128 * 1024 * 1024);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flash_layout_erased_tail() -> Result<()> {
+        let mut content = vec![0xFF; 0x10000];
+        content[..0x1800].fill(7);
+        let (data, erased) = trim_erased(content.clone());
+        assert_eq!((data.len(), erased), (0x2000, 0xE000));
+        assert_eq!(trim_erased(vec![0xFF; 0x3000]), (Vec::new(), 0x3000));
+        assert_eq!(trim_erased(vec![1; 0x1800]), (vec![1; 0x1800], 0));
+
+        let mut layout = FlashLayout::new();
+        layout.push(Region {
+            erased,
+            ..region("fs", 0x10000, data)
+        })?;
+        assert_eq!(layout.end(), 0x20000);
+        assert!(layout.push(region("b", 0x1F000, vec![0; 4])).is_err());
+
+        let mut streamed = Vec::new();
+        layout.write_to(&mut streamed, None)?;
+        assert_eq!(streamed, layout.render_minimal());
+        assert_eq!(&streamed[0x10000..], &content[..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flash_layout_rejects_overlaps() -> Result<()> {
+        let mut layout = FlashLayout::new();
+        layout.push(region("a", 0x10, vec![0; 0x10]))?;
+        layout.push(region("c", 0x30, vec![0; 0x10]))?;
+
+        let error = layout.push(region("b", 0x1C, vec![0; 8])).unwrap_err();
+        assert!(error.to_string().contains("overlaps a"));
+        assert!(layout.push(region("d", 0x28, vec![0; 9])).is_err());
+        layout.push(region("e", 0x20, vec![0; 0x10]))?;
+        assert_eq!(layout.regions().len(), 3);
+
+        // A second region at the same offset
+        assert!(layout.push(region("f", 0x10, vec![0; 4])).is_err());
+        assert_eq!(layout.regions().len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flash_layout_rejects_empty_regions() -> Result<()> {
+        let mut layout = FlashLayout::new();
+        layout.push(region("a", 0x10, vec![0; 0x10]))?;
+        layout.push(region("c", 0x40, vec![0; 0x10]))?;
+
+        let error = layout.push(region("b", 0x20, Vec::new())).unwrap_err();
+        assert!(error.to_string().contains("empty"));
+
+        // Without the empty region at 0x20, one starting there still can't
+        // reach over c
+        assert!(layout.push(region("d", 0x20, vec![0; 0x30])).is_err());
+        assert!(layout.write_to(&mut std::io::sink(), None).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flash_layout_rejects_regions_past_4gb() -> Result<()> {
+        let mut layout = FlashLayout::new();
+        layout.push(region("a", 0x10, vec![0; 0x10]))?;
+
+        let error = layout
+            .push(region("b", u32::MAX - 0x10, vec![0; 0x20]))
+            .unwrap_err();
+        assert!(error.to_string().contains("32-bit"));
+        let tail = Region {
+            erased: u32::MAX,
+            ..region("c", 0x1000, vec![0; 4])
+        };
+        assert!(layout.push(tail).is_err());
+        assert_eq!(layout.end(), 0x20);
+
+        Ok(())
+    }
+}
@@ -1,10 +1,17 @@
+pub mod layout;
+
 use crate::Result;
 use crate::config::Config;
 use crate::esp32::Esp32P4Processor;
+use crate::filesystem;
 use crate::firmware::FirmwareBinary;
-use crate::partition::PartitionGenerator;
-use esp_idf_part::PartitionTable;
+use crate::nvs::{self, NvsItem, crypt::NvsKeys};
+use crate::otadata;
+use crate::partition::{PartitionGenerator, binary};
+use esp_idf_part::{DataType, Flags, PartitionTable, SubType, Type};
+use layout::{FlashLayout, Region, RegionRole, RegionSource};
 use log::info;
+use std::io::Write;
 
 pub struct ImageBuilder;
 
@@ -14,35 +21,26 @@ impl ImageBuilder {
 
         // Generate partition table
         let partition_table = PartitionGenerator::generate_table(firmwares, config)?;
+        Self::build_flash_image_with_table(firmwares, &partition_table, config)
+    }
 
-        if config.pad_flash {
-            // Create full flash-size buffer with 0xFF padding
-            let flash_size = config.flash_size.size_bytes();
-            let mut flash_image = vec![0xFF; flash_size as usize];
-
-            // Write components to the full buffer
-            Self::write_components_to_buffer(
-                &mut flash_image,
-                firmwares,
-                &partition_table,
-                config,
-            )?;
+    /// Build the flash image for an already generated partition table
+    pub fn build_flash_image_with_table(
+        firmwares: &[FirmwareBinary],
+        partition_table: &PartitionTable,
+        config: &Config,
+    ) -> Result<Vec<u8>> {
+        let layout = Self::plan_layout(firmwares, partition_table, config)?;
 
+        if config.pad_flash {
+            let flash_image = layout.render_padded(config.flash_size.size_bytes())?;
             info!(
                 "Flash image built successfully: {} bytes (full flash size)",
                 flash_image.len()
             );
             Ok(flash_image)
         } else {
-            // Create minimal buffer that grows as needed
-            let mut flash_image = Vec::new();
-            Self::write_components_to_minimal_buffer(
-                &mut flash_image,
-                firmwares,
-                &partition_table,
-                config,
-            )?;
-
+            let flash_image = layout.render_minimal();
             info!(
                 "Flash image built successfully: {} bytes (minimal size)",
                 flash_image.len()
@@ -51,253 +49,266 @@ impl ImageBuilder {
         }
     }
 
-    /// Write components to a pre-allocated full-size flash buffer
-    fn write_components_to_buffer(
-        flash_image: &mut Vec<u8>,
+    /// Stream the flash image for a generated partition table to `writer`,
+    /// with memory bounded by the components rather than the flash size;
+    /// returns the image length
+    pub fn write_flash_image<W: Write>(
         firmwares: &[FirmwareBinary],
         partition_table: &PartitionTable,
         config: &Config,
-    ) -> Result<()> {
-        // Process and write bootloader (first firmware)
-        if !firmwares.is_empty() {
-            let bootloader = &firmwares[0];
-            info!("Processing bootloader: {} bytes", bootloader.size);
+        writer: &mut W,
+    ) -> Result<u64> {
+        let layout = Self::plan_layout(firmwares, partition_table, config)?;
+        let len = layout.write_to(writer, Self::pad_to(config))?;
+        info!(
+            "Flash image written: {} bytes ({})",
+            len,
+            if config.pad_flash {
+                "full flash size"
+            } else {
+                "minimal size"
+            }
+        );
+        Ok(len)
+    }
 
-            let mut bootloader_data = bootloader.data.clone();
-            Esp32P4Processor::process_bootloader_image(&mut bootloader_data)?;
+    /// Flash size the image is padded to, if `--pad-flash` is set
+    pub fn pad_to(config: &Config) -> Option<u32> {
+        config.pad_flash.then(|| config.flash_size.size_bytes())
+    }
 
+    /// Place every component of the image: bootloader, partition table, apps
+    /// and partition contents
+    pub fn plan_layout(
+        firmwares: &[FirmwareBinary],
+        partition_table: &PartitionTable,
+        config: &Config,
+    ) -> Result<FlashLayout> {
+        let populated = PartitionGenerator::app_assignments(firmwares, config.layout).len();
+        let app_slots = partition_table
+            .partitions()
+            .iter()
+            .filter(|p| p.name() == "factory" || p.name().starts_with("ota_"))
+            .count();
+        if app_slots > populated {
             info!(
-                "Writing processed bootloader: {} bytes",
-                bootloader_data.len()
+                "Leaving {} reserved OTA partition(s) erased",
+                app_slots - populated
             );
-            Self::write_to_flash(
-                flash_image,
-                crate::config::defaults::BOOTLOADER_OFFSET,
-                &bootloader_data,
-            )?;
         }
 
-        // Write partition table
-        info!("Writing partition table");
-        let partition_table_data = Self::serialize_partition_table(partition_table)?;
-        Self::write_to_flash(
-            flash_image,
-            crate::config::defaults::PARTITION_TABLE_OFFSET,
-            &partition_table_data,
-        )?;
+        let mut layout = FlashLayout::new();
 
-        // Process and write factory app (second firmware)
-        if firmwares.len() >= 2 {
-            let factory_app = &firmwares[1];
-            info!("Processing factory app: {} bytes", factory_app.size);
+        // Process the bootloader (first firmware)
+        if let Some(bootloader) = firmwares.first() {
+            info!("Processing bootloader: {} bytes", bootloader.size);
 
-            let mut factory_app_data = factory_app.data.clone();
-            Esp32P4Processor::process_app_image(&mut factory_app_data, false)?;
-            Esp32P4Processor::verify_alignment(crate::config::defaults::FACTORY_OFFSET, true)?;
+            Esp32P4Processor::process_bootloader_image(&bootloader.data)?;
 
-            info!(
-                "Writing processed factory app: {} bytes",
-                factory_app_data.len()
-            );
-            Self::write_to_flash(
-                flash_image,
-                crate::config::defaults::FACTORY_OFFSET,
-                &factory_app_data,
-            )?;
+            layout.push(Region {
+                offset: crate::config::defaults::BOOTLOADER_OFFSET,
+                data: bootloader.data.clone(),
+                erased: 0,
+                role: RegionRole::Bootloader,
+                name: "bootloader".to_string(),
+                source: RegionSource::File(bootloader.path.clone()),
+            })?;
         }
 
-        // Process and write OTA partitions (remaining firmwares)
-        for (i, firmware) in firmwares.iter().skip(2).enumerate() {
-            let ota_name = format!("ota_{}", i);
-            if let Some(partition) = partition_table.find(&ota_name) {
-                info!("Processing OTA partition {}: {} bytes", i, firmware.size);
-
-                let mut ota_app_data = firmware.data.clone();
-                Esp32P4Processor::process_app_image(&mut ota_app_data, false)?;
+        layout.push(Region {
+            offset: config.partition_table_offset,
+            data: Self::serialize_partition_table(partition_table, config)?.into(),
+            erased: 0,
+            role: RegionRole::PartitionTable,
+            name: "partition table".to_string(),
+            source: RegionSource::Generated,
+        })?;
+
+        // Process app firmwares into the factory and OTA slots
+        for (name, firmware) in PartitionGenerator::app_assignments(firmwares, config.layout) {
+            if let Some(partition) = partition_table.find(&name) {
+                info!("Processing app '{}': {} bytes", name, firmware.size);
+
+                Esp32P4Processor::process_app_image(&firmware.data, false)?;
                 Esp32P4Processor::verify_alignment(partition.offset(), true)?;
 
-                info!(
-                    "Writing processed OTA partition {}: {} bytes at 0x{:X}",
-                    i,
-                    ota_app_data.len(),
-                    partition.offset()
-                );
-                Self::write_to_flash(flash_image, partition.offset(), &ota_app_data)?;
+                layout.push(Region {
+                    offset: partition.offset(),
+                    data: firmware.data.clone(),
+                    erased: 0,
+                    role: RegionRole::App,
+                    name,
+                    source: RegionSource::File(firmware.path.clone()),
+                })?;
             }
         }
 
-        Ok(())
-    }
-
-    /// Write components to a minimal buffer that grows as needed
-    fn write_components_to_minimal_buffer(
-        flash_image: &mut Vec<u8>,
-        firmwares: &[FirmwareBinary],
-        partition_table: &PartitionTable,
-        config: &Config,
-    ) -> Result<()> {
-        let mut end_offset = 0u32;
-
-        // Process and write bootloader (first firmware)
-        if !firmwares.is_empty() {
-            let bootloader = &firmwares[0];
-            info!("Processing bootloader: {} bytes", bootloader.size);
-
-            let mut bootloader_data = bootloader.data.clone();
-            Esp32P4Processor::process_bootloader_image(&mut bootloader_data)?;
-
-            let bootloader_offset = crate::config::defaults::BOOTLOADER_OFFSET;
-            let bootloader_end = bootloader_offset + bootloader_data.len() as u32;
-
-            // Ensure buffer is large enough
-            if flash_image.len() < bootloader_end as usize {
-                flash_image.resize(bootloader_end as usize, 0xFF);
-            }
-
-            // Write bootloader data
-            let start = bootloader_offset as usize;
-            let end = start + bootloader_data.len();
-            flash_image[start..end].copy_from_slice(&bootloader_data);
+        for region in Self::load_partition_contents(partition_table, config)? {
+            layout.push(region)?;
+        }
 
-            end_offset = end_offset.max(bootloader_end);
+        for region in layout.regions() {
             info!(
-                "Written processed bootloader: {} bytes at 0x{:X}",
-                bootloader_data.len(),
-                bootloader_offset
+                "Placed {} ({:?}): {} bytes at 0x{:X}",
+                region.name,
+                region.role,
+                region.end() - region.offset,
+                region.offset
             );
         }
+        Ok(layout)
+    }
 
-        // Write partition table
-        info!("Writing partition table");
-        let partition_table_data = Self::serialize_partition_table(partition_table)?;
-        let pt_offset = crate::config::defaults::PARTITION_TABLE_OFFSET;
-        let pt_end = pt_offset + partition_table_data.len() as u32;
-
-        // Ensure buffer is large enough
-        if flash_image.len() < pt_end as usize {
-            flash_image.resize(pt_end as usize, 0xFF);
-        }
-
-        // Write partition table data
-        let start = pt_offset as usize;
-        let end = start + partition_table_data.len();
-        flash_image[start..end].copy_from_slice(&partition_table_data);
-
-        end_offset = end_offset.max(pt_end);
-
-        // Process and write factory app (second firmware)
-        if firmwares.len() >= 2 {
-            let factory_app = &firmwares[1];
-            info!("Processing factory app: {} bytes", factory_app.size);
-
-            let mut factory_app_data = factory_app.data.clone();
-            Esp32P4Processor::process_app_image(&mut factory_app_data, false)?;
-            Esp32P4Processor::verify_alignment(crate::config::defaults::FACTORY_OFFSET, true)?;
-
-            let factory_offset = crate::config::defaults::FACTORY_OFFSET;
-            let factory_end = factory_offset + factory_app_data.len() as u32;
+    /// Serialize exactly the partition table `build_flash_image` embeds for these firmwares
+    pub fn build_partition_table_only(
+        firmwares: &[FirmwareBinary],
+        config: &Config,
+    ) -> Result<Vec<u8>> {
+        let partition_table = PartitionGenerator::generate_table(firmwares, config)?;
+        Self::serialize_partition_table(&partition_table, config)
+    }
 
-            // Ensure buffer is large enough
-            if flash_image.len() < factory_end as usize {
-                flash_image.resize(factory_end as usize, 0xFF);
+    /// Content of data partitions and of user-defined partitions that reference a file
+    fn load_partition_contents(
+        partition_table: &PartitionTable,
+        config: &Config,
+    ) -> Result<Vec<Region>> {
+        let mut contents = Vec::new();
+        let generated = |(name, offset, data): (String, u32, Vec<u8>)| {
+            let (data, erased) = layout::trim_erased(data);
+            Region {
+                offset,
+                data: data.into(),
+                erased,
+                role: RegionRole::Data,
+                name,
+                source: RegionSource::Generated,
             }
+        };
 
-            // Write factory app data
-            let start = factory_offset as usize;
-            let end = start + factory_app_data.len();
-            flash_image[start..end].copy_from_slice(&factory_app_data);
-
-            end_offset = end_offset.max(factory_end);
-            info!(
-                "Written processed factory app: {} bytes at 0x{:X}",
-                factory_app_data.len(),
-                factory_offset
-            );
-        }
-
-        // Process and write OTA partitions (remaining firmwares)
-        for (i, firmware) in firmwares.iter().skip(2).enumerate() {
-            let ota_name = format!("ota_{}", i);
-            if let Some(partition) = partition_table.find(&ota_name) {
-                info!("Processing OTA partition {}: {} bytes", i, firmware.size);
+        for spec in &config.partitions {
+            let Some(file) = &spec.file else {
+                continue;
+            };
+
+            let partition = partition_table
+                .find(&spec.name)
+                .ok_or_else(|| anyhow::anyhow!("Partition '{}' not found in table", spec.name))?;
+
+            let data = std::fs::read(file).map_err(|e| {
+                anyhow::anyhow!(
+                    "Failed to read content {:?} for partition '{}': {}",
+                    file,
+                    spec.name,
+                    e
+                )
+            })?;
+
+            // An empty file leaves the partition erased
+            if data.is_empty() {
+                continue;
+            }
+            if data.len() > partition.size() as usize {
+                return Err(anyhow::anyhow!(
+                    "Content for partition '{}' ({} bytes) exceeds its size ({} bytes)",
+                    spec.name,
+                    data.len(),
+                    partition.size()
+                ));
+            }
 
-                let mut ota_app_data = firmware.data.clone();
-                Esp32P4Processor::process_app_image(&mut ota_app_data, false)?;
+            let role = if partition.ty() == Type::App {
+                Esp32P4Processor::process_app_image(&data, false)?;
                 Esp32P4Processor::verify_alignment(partition.offset(), true)?;
+                RegionRole::App
+            } else {
+                RegionRole::Data
+            };
+
+            contents.push(Region {
+                offset: partition.offset(),
+                data: data.into(),
+                erased: 0,
+                role,
+                name: spec.name.clone(),
+                source: RegionSource::File(file.clone()),
+            });
+        }
 
-                let ota_end = partition.offset() + ota_app_data.len() as u32;
-
-                // Ensure buffer is large enough
-                if flash_image.len() < ota_end as usize {
-                    flash_image.resize(ota_end as usize, 0xFF);
-                }
-
-                // Write OTA app data
-                let start = partition.offset() as usize;
-                let end = start + ota_app_data.len();
-                flash_image[start..end].copy_from_slice(&ota_app_data);
-
-                end_offset = end_offset.max(ota_end);
-                info!(
-                    "Written processed OTA partition {}: {} bytes at 0x{:X}",
-                    i,
-                    ota_app_data.len(),
-                    partition.offset()
-                );
+        let keys = match &config.nvs_keys {
+            Some(path) => Some(NvsKeys::load_from_file(path)?),
+            None => None,
+        };
+        if let Some(keys) = &keys {
+            let partition = partition_table
+                .partitions()
+                .iter()
+                .find(|p| p.subtype() == SubType::Data(DataType::NvsKeys))
+                .ok_or_else(|| anyhow::anyhow!("--nvs-keys needs an nvs_keys partition"))?;
+            if !partition.flags().contains(Flags::ENCRYPTED) {
+                return Err(anyhow::anyhow!(
+                    "Partition '{}' holds the NVS keys and must have the encrypted flag",
+                    partition.name()
+                ));
             }
+            contents.push(generated((
+                partition.name(),
+                partition.offset(),
+                keys.to_partition(partition.size()),
+            )));
         }
 
-        info!(
-            "Minimal image ends at offset 0x{:X} ({} bytes)",
-            end_offset,
-            flash_image.len()
-        );
-        Ok(())
-    }
-
-    pub fn build_partition_table_only(config: &Config) -> Result<Vec<u8>> {
-        // Create a minimal firmware set just for partition table generation
-        // We need at least bootloader to generate a valid table
-        let dummy_bootloader = FirmwareBinary::new(
-            "bootloader".to_string(),
-            config.firmware_dir.join("dummy-bootloader.bin"),
-            vec![0; 32 * 1024],
-            1,
-        );
+        if let Some(csv) = &config.nvs_csv {
+            let items = nvs::load_csv(csv)?;
+            contents.push(generated(Self::nvs_partition(
+                partition_table,
+                &items,
+                keys.as_ref(),
+            )?));
+            info!("Generated NVS partition from {:?}", csv);
+        }
 
-        let dummy_factory = FirmwareBinary::new(
-            "factory".to_string(),
-            config.firmware_dir.join("dummy-factory.bin"),
-            vec![0; 1 * 1024 * 1024],
-            2,
+        contents.extend(
+            filesystem::build_images(partition_table, config)?
+                .into_iter()
+                .map(generated),
         );
 
-        let dummy_firmwares = vec![dummy_bootloader, dummy_factory];
-        let partition_table = PartitionGenerator::generate_table(&dummy_firmwares, config)?;
-        Self::serialize_partition_table(&partition_table)
-    }
+        if let Some(slot) = config.boot_slot {
+            let data = otadata::build_otadata(partition_table, slot, config.boot_state)?;
+            let partition = otadata::find_otadata(partition_table)
+                .expect("build_otadata checked for an otadata partition");
+            info!("otadata selects '{}' (state {})", slot, config.boot_state);
+            contents.push(generated((partition.name(), partition.offset(), data)));
+        }
 
-    fn serialize_partition_table(table: &PartitionTable) -> Result<Vec<u8>> {
-        // Use the esp_idf_part crate to serialize to binary format
-        let data = table.to_bin()?;
-        Ok(data)
+        Ok(contents)
     }
 
-    fn write_to_flash(flash_image: &mut [u8], offset: u32, data: &[u8]) -> Result<()> {
-        let start = offset as usize;
-        let end = start + data.len();
-
-        if end > flash_image.len() {
-            return Err(anyhow::anyhow!(
-                "Write exceeds flash image bounds: offset={}, size={}, image_size={}",
-                offset,
-                data.len(),
-                flash_image.len()
-            ));
+    /// Name, offset and content of the `nvs` partition holding `items`,
+    /// encrypted when NVS keys are given
+    pub fn nvs_partition(
+        partition_table: &PartitionTable,
+        items: &[NvsItem],
+        keys: Option<&NvsKeys>,
+    ) -> Result<(String, u32, Vec<u8>)> {
+        let partition = partition_table
+            .find("nvs")
+            .ok_or_else(|| anyhow::anyhow!("NVS data needs an 'nvs' partition in the table"))?;
+        let mut data = nvs::build_partition(items, partition.size()).map_err(|e| {
+            anyhow::anyhow!(
+                "{} (resize 'nvs' with a partitions file entry such as {{ \"name\": \"nvs\", \"size\": \"24KB\" }})",
+                e
+            )
+        })?;
+        if let Some(keys) = keys {
+            keys.encrypt_partition(&mut data);
         }
+        Ok((partition.name(), partition.offset(), data))
+    }
 
-        flash_image[start..end].copy_from_slice(data);
-        Ok(())
+    fn serialize_partition_table(table: &PartitionTable, config: &Config) -> Result<Vec<u8>> {
+        binary::to_bytes(table, config.partition_table_md5)
     }
 }
 
@@ -305,41 +316,9 @@ impl ImageBuilder {
 mod tests {
     use super::*;
     use crate::config::FlashSize;
+    use crate::test_support::{create_test_firmware, nvs_spec};
     use std::path::PathBuf;
 
-    fn create_test_firmware(name: &str, size: usize, prefix: u32) -> FirmwareBinary {
-        let data: Vec<u8> = (0..size).map(|i| (i % 256) as u8).collect();
-        FirmwareBinary::new(
-            name.to_string(),
-            PathBuf::from(format!("{}.bin", name)),
-            data,
-            prefix,
-        )
-    }
-
-    #[test]
-    fn test_write_to_flash() -> Result<()> {
-        let mut flash_image = vec![0xFF; 1024];
-        let data = vec![0x42, 0x43, 0x44];
-
-        ImageBuilder::write_to_flash(&mut flash_image, 10, &data)?;
-
-        assert_eq!(flash_image[8..13], [0xFF, 0xFF, 0x42, 0x43, 0x44]);
-        assert_eq!(flash_image[0..8], [0xFF; 8]);
-        assert_eq!(flash_image[13..], [0xFF; 1024 - 13]);
-
-        Ok(())
-    }
-
-    #[test]
-    fn test_write_to_flash_overflow() {
-        let mut flash_image = vec![0xFF; 100];
-        let data = vec![0x42; 10];
-
-        let result = ImageBuilder::write_to_flash(&mut flash_image, 95, &data);
-        assert!(result.is_err());
-    }
-
     #[test]
     fn test_build_partition_table_only() -> Result<()> {
         let config = Config {
@@ -347,13 +326,27 @@ mod tests {
             ..Default::default()
         };
 
-        let partition_table_data = ImageBuilder::build_partition_table_only(&config)?;
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 100 * 1024, 2),
+            create_test_firmware("ota_app", 200 * 1024, 3),
+        ];
+
+        let partition_table_data = ImageBuilder::build_partition_table_only(&firmwares, &config)?;
 
         // Should have some data (partition tables are typically a few KB)
         assert!(!partition_table_data.is_empty());
         assert!(partition_table_data.len() > 100);
         assert!(partition_table_data.len() < 10 * 1024); // Shouldn't be too large
 
+        // Identical to the table embedded in the flash image
+        let flash_image = ImageBuilder::build_flash_image(&firmwares, &config)?;
+        let offset = config.partition_table_offset as usize;
+        assert_eq!(
+            &flash_image[offset..offset + partition_table_data.len()],
+            &partition_table_data[..]
+        );
+
         Ok(())
     }
 
@@ -377,14 +370,18 @@ mod tests {
         assert!(flash_image.len() < 16 * 1024 * 1024);
         assert!(flash_image.len() > 100 * 1024); // Should contain the firmware
 
-        // Check bootloader at offset 0
-        assert_eq!(&flash_image[0..10], &(0..10).collect::<Vec<_>>());
+        // Check bootloader at BOOTLOADER_OFFSET
+        let bootloader_offset = crate::config::defaults::BOOTLOADER_OFFSET as usize;
+        assert_eq!(
+            &flash_image[bootloader_offset..bootloader_offset + 10],
+            &firmwares[0].data[..10]
+        );
 
         // Check factory app at FACTORY_OFFSET
         let factory_offset = crate::config::defaults::FACTORY_OFFSET as usize;
         assert_eq!(
             &flash_image[factory_offset..factory_offset + 10],
-            &(0..10).collect::<Vec<_>>()
+            &firmwares[1].data[..10]
         );
 
         Ok(())
@@ -409,8 +406,12 @@ mod tests {
         // Should be exactly full flash size
         assert_eq!(flash_image.len(), 16 * 1024 * 1024);
 
-        // Check bootloader at offset 0
-        assert_eq!(&flash_image[0..10], &(0..10).collect::<Vec<_>>());
+        // Check bootloader at BOOTLOADER_OFFSET
+        let bootloader_offset = crate::config::defaults::BOOTLOADER_OFFSET as usize;
+        assert_eq!(
+            &flash_image[bootloader_offset..bootloader_offset + 10],
+            &firmwares[0].data[..10]
+        );
 
         // Check that most of the flash is still 0xFF (empty)
         let ff_count = flash_image.iter().filter(|&&b| b == 0xFF).count();
@@ -445,8 +446,431 @@ mod tests {
         assert!(flash_image.len() > 32 * 1024); // Bootloader
         assert!(flash_image.len() < 16 * 1024 * 1024); // Minimal size
 
-        // Check bootloader at offset 0
-        assert_eq!(&flash_image[0..10], &(0..10).collect::<Vec<_>>());
+        // Check bootloader at BOOTLOADER_OFFSET
+        let bootloader_offset = crate::config::defaults::BOOTLOADER_OFFSET as usize;
+        assert_eq!(
+            &flash_image[bootloader_offset..bootloader_offset + 10],
+            &firmwares[0].data[..10]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_flash_image_reserved_ota_slots_erased() -> Result<()> {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 100 * 1024, 2),
+            create_test_firmware("ota_app", 100 * 1024, 3),
+        ];
+
+        let config = Config {
+            flash_size: FlashSize::Size16MB,
+            extra_ota_slots: 1,
+            extra_ota_size: 1024 * 1024,
+            pad_flash: true,
+            ..Default::default()
+        };
+
+        let flash_image = ImageBuilder::build_flash_image(&firmwares, &config)?;
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+
+        let ota_0 = table.find("ota_0").unwrap();
+        let start = ota_0.offset() as usize;
+        assert_eq!(&flash_image[start..start + 10], &firmwares[2].data[..10]);
+
+        let ota_1 = table.find("ota_1").unwrap();
+        let start = ota_1.offset() as usize;
+        let end = start + ota_1.size() as usize;
+        assert!(flash_image[start..end].iter().all(|&b| b == 0xFF));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_flash_image_boot_slot() -> Result<()> {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 100 * 1024, 2),
+            create_test_firmware("ota_app", 100 * 1024, 3),
+        ];
+
+        let config = Config {
+            extra_ota_slots: 1,
+            boot_slot: Some(otadata::BootSlot::Ota(1)),
+            boot_state: otadata::OtaImageState::PendingVerify,
+            ..Default::default()
+        };
+
+        let flash_image = ImageBuilder::build_flash_image(&firmwares, &config)?;
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+        let start = table.find("otadata").unwrap().offset() as usize;
+
+        assert_eq!(&flash_image[start..start + 4], &2u32.to_le_bytes());
+        assert_eq!(&flash_image[start + 24..start + 28], &1u32.to_le_bytes());
+        assert_eq!(
+            &flash_image[start + 28..start + 32],
+            &otadata::seq_crc(2).to_le_bytes()
+        );
+
+        let config = Config {
+            boot_slot: Some(otadata::BootSlot::Ota(3)),
+            ..config
+        };
+        assert!(ImageBuilder::build_flash_image(&firmwares, &config).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_flash_image_nvs_from_csv() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let csv = temp_dir.path().join("nvs.csv");
+        std::fs::write(
+            &csv,
+            "key,type,encoding,value\nconfig,namespace,,\nid,data,u8,7\n",
+        )?;
+
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 100 * 1024, 2),
+        ];
+        let mut config = Config {
+            nvs_csv: Some(csv),
+            ..Default::default()
+        };
+
+        // The default nvs partition holds the data without a partitions file
+        let flash_image = ImageBuilder::build_flash_image(&firmwares, &config)?;
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+        let nvs = table.find("nvs").unwrap();
+        let start = nvs.offset() as usize;
+        let expected = nvs::build_partition(
+            &[nvs::NvsItem {
+                namespace: "config".to_string(),
+                key: "id".to_string(),
+                value: nvs::NvsValue::U8(7),
+            }],
+            nvs.size(),
+        )?;
+        assert_eq!(&flash_image[start..start + expected.len()], &expected[..]);
+
+        // A 4KB nvs partition is too small for NVS
+        config.partitions = vec![nvs_spec(0x1000, None)];
+        assert!(ImageBuilder::build_flash_image(&firmwares, &config).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_flash_image_encrypted_nvs() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let csv = temp_dir.path().join("nvs.csv");
+        std::fs::write(&csv, "config,namespace,,\nid,data,u8,7\n")?;
+        let key_file = temp_dir.path().join("keys.bin");
+        let key_bytes: Vec<u8> = (0..64).map(|i| i as u8 ^ 0x5A).collect();
+        std::fs::write(&key_file, &key_bytes)?;
+
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 100 * 1024, 2),
+        ];
+        let config = Config {
+            nvs_csv: Some(csv.clone()),
+            nvs_keys: Some(key_file),
+            partitions: vec![nvs_spec(0x3000, None)],
+            ..Default::default()
+        };
+
+        let flash_image = ImageBuilder::build_flash_image(&firmwares, &config)?;
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+
+        let keys_partition = table.find("nvs_keys").unwrap();
+        assert_eq!(keys_partition.subtype(), SubType::Data(DataType::NvsKeys));
+        assert!(keys_partition.flags().contains(Flags::ENCRYPTED));
+        let keys = NvsKeys::from_bytes(&key_bytes)?;
+        let start = keys_partition.offset() as usize;
+        assert_eq!(
+            &flash_image[start..start + 0x1000],
+            &keys.to_partition(0x1000)[..]
+        );
+
+        let nvs = table.find("nvs").unwrap();
+        let start = nvs.offset() as usize;
+        let mut data = flash_image[start..start + 0x3000].to_vec();
+        let plain = nvs::build_from_csv(&csv, 0x3000)?;
+        assert_ne!(data, plain);
+        keys.decrypt_partition(&mut data);
+        assert_eq!(data, plain);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_flash_image_spiffs() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        std::fs::write(temp_dir.path().join("index.html"), "<html></html>")?;
+
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 100 * 1024, 2),
+        ];
+        let config = Config {
+            spiffs_dir: Some(temp_dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+        let spiffs = table.find("spiffs").expect("spiffs partition added");
+        assert_eq!(spiffs.size(), crate::config::defaults::SPIFFS_SIZE);
+        assert!(spiffs.offset() > table.find("factory").unwrap().offset());
+
+        let flash_image = ImageBuilder::build_flash_image_with_table(&firmwares, &table, &config)?;
+        let start = spiffs.offset() as usize;
+        assert_eq!(flash_image.len(), start + spiffs.size() as usize);
+        assert_eq!(
+            &flash_image[start + 256 + 13..start + 256 + 24],
+            b"/index.html"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_flash_image_littlefs_fill_partition() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        std::fs::create_dir(temp_dir.path().join("fonts"))?;
+        std::fs::write(temp_dir.path().join("fonts/ui.bin"), [0xAA; 100])?;
+
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 100 * 1024, 2),
+        ];
+        let config = Config {
+            flash_size: FlashSize::Size8MB,
+            fill_partition: Some("storage:littlefs".parse()?),
+            littlefs_dir: Some(temp_dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+        assert!(table.find("littlefs").is_none());
+        let storage = table.find("storage").unwrap();
+
+        let flash_image = ImageBuilder::build_flash_image_with_table(&firmwares, &table, &config)?;
+        let start = storage.offset() as usize;
+        assert_eq!(flash_image.len(), start + storage.size() as usize);
+        assert_eq!(&flash_image[start + 8..start + 16], b"littlefs");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_flash_image_fat_wear_levelling() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        std::fs::write(temp_dir.path().join("readme.txt"), "hello")?;
+
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 100 * 1024, 2),
+        ];
+        let config = Config {
+            fat_dir: Some(temp_dir.path().to_path_buf()),
+            fat: crate::filesystem::fat::FatConfig {
+                wear_levelling: true,
+            },
+            ..Default::default()
+        };
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+        let fat = table.find("fat").expect("fat partition added");
+        assert_eq!(fat.subtype(), SubType::Data(DataType::Fat));
+
+        let flash_image = ImageBuilder::build_flash_image_with_table(&firmwares, &table, &config)?;
+        let start = fat.offset() as usize;
+        assert_eq!(flash_image.len(), start + fat.size() as usize);
+        // Dummy sector, then the boot sector of the volume
+        assert!(flash_image[start..start + 4096].iter().all(|&b| b == 0xFF));
+        assert_eq!(
+            &flash_image[start + 4096 + 0x36..start + 4096 + 0x3B],
+            b"FAT12"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_flash_image_ab_layout() -> Result<()> {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("app_a", 100 * 1024, 2),
+            create_test_firmware("app_b", 200 * 1024, 3),
+        ];
+
+        let config = Config {
+            layout: crate::config::LayoutPreset::Ab,
+            ..Default::default()
+        };
+
+        let flash_image = ImageBuilder::build_flash_image(&firmwares, &config)?;
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+
+        for (name, firmware) in [("ota_0", &firmwares[1]), ("ota_1", &firmwares[2])] {
+            let start = table.find(name).unwrap().offset() as usize;
+            assert_eq!(&flash_image[start..start + 10], &firmware.data[..10]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_flash_image_raw_partition_content() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let calib_file = temp_dir.path().join("calib.bin");
+        std::fs::write(&calib_file, [0x5A; 100])?;
+
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 100 * 1024, 2),
+        ];
+
+        let config = Config {
+            partitions: vec![crate::config::PartitionSpec {
+                name: "calib".to_string(),
+                ty: Some(esp_idf_part::Type::Custom(0x40)),
+                subtype: Some("0x00".to_string()),
+                offset: None,
+                size: Some(4096),
+                file: Some(calib_file),
+                flags: esp_idf_part::Flags::empty(),
+            }],
+            ..Default::default()
+        };
+
+        let flash_image = ImageBuilder::build_flash_image(&firmwares, &config)?;
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+        let calib = table.find("calib").unwrap();
+
+        let start = calib.offset() as usize;
+        assert_eq!(flash_image.len(), start + 100);
+        assert!(flash_image[start..].iter().all(|&b| b == 0x5A));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_flash_image_matches_build() -> Result<()> {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 100 * 1024, 2),
+        ];
+
+        for pad_flash in [false, true] {
+            let config = Config {
+                flash_size: FlashSize::Size8MB,
+                pad_flash,
+                ..Default::default()
+            };
+            let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+
+            let mut streamed = Vec::new();
+            let len = ImageBuilder::write_flash_image(&firmwares, &table, &config, &mut streamed)?;
+            assert_eq!(len, streamed.len() as u64);
+            assert_eq!(
+                streamed,
+                ImageBuilder::build_flash_image_with_table(&firmwares, &table, &config)?
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_layout_regions() -> Result<()> {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 100 * 1024, 2),
+        ];
+        let config = Config {
+            boot_slot: Some(otadata::BootSlot::Factory),
+            ..Default::default()
+        };
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+
+        let layout = ImageBuilder::plan_layout(&firmwares, &table, &config)?;
+        let summary: Vec<_> = layout
+            .regions()
+            .iter()
+            .map(|r| (r.name.as_str(), r.role))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("bootloader", RegionRole::Bootloader),
+                ("partition table", RegionRole::PartitionTable),
+                ("otadata", RegionRole::Data),
+                ("factory", RegionRole::App),
+            ]
+        );
+        assert_eq!(
+            layout.regions()[0].source,
+            RegionSource::File(PathBuf::from("bootloader.bin"))
+        );
+        assert_eq!(layout.regions()[2].source, RegionSource::Generated);
+
+        // Both renderers come from the same placement
+        let minimal = layout.render_minimal();
+        let padded = layout.render_padded(config.flash_size.size_bytes())?;
+        assert_eq!(&padded[..minimal.len()], &minimal[..]);
+
+        // Two contents for one partition are an error rather than one overwriting the other
+        let temp_dir = tempfile::TempDir::new()?;
+        let csv = temp_dir.path().join("nvs.csv");
+        let raw = temp_dir.path().join("nvs.bin");
+        std::fs::write(&csv, "config,namespace,,\nmode,data,u8,1\n")?;
+        std::fs::write(&raw, [0u8; 32])?;
+        let config = Config {
+            nvs_csv: Some(csv),
+            partitions: vec![nvs_spec(0x6000, Some(raw))],
+            ..Default::default()
+        };
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+        let error = ImageBuilder::plan_layout(&firmwares, &table, &config).unwrap_err();
+        assert!(error.to_string().contains("nvs at"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_flash_image_128mb_fill_filesystem() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        std::fs::write(temp_dir.path().join("config.json"), "{}")?;
+
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 100 * 1024, 2),
+        ];
+        let config = Config {
+            flash_size: FlashSize::Size128MB,
+            pad_flash: true,
+            fill_partition: Some("storage:littlefs".parse()?),
+            littlefs_dir: Some(temp_dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+        let storage = table.find("storage").unwrap();
+        assert!(storage.size() > 100 * 1024 * 1024);
+
+        // Only the used blocks of the filesystem stay in memory
+        let layout = ImageBuilder::plan_layout(&firmwares, &table, &config)?;
+        let region = layout
+            .regions()
+            .iter()
+            .find(|r| r.name == "storage")
+            .unwrap();
+        assert!(region.data.len() <= 64 * 1024);
+        assert_eq!(region.end(), storage.offset() + storage.size());
+
+        let len =
+            ImageBuilder::write_flash_image(&firmwares, &table, &config, &mut std::io::sink())?;
+        assert_eq!(len, 128 * 1024 * 1024);
 
         Ok(())
     }
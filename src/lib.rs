@@ -1,14 +1,23 @@
+pub mod batch;
 pub mod cli;
 pub mod config;
 pub mod esp32;
+pub mod filesystem;
 pub mod firmware;
 pub mod image;
+pub mod lock;
+pub mod nvs;
+pub mod otadata;
 pub mod partition;
+#[cfg(test)]
+mod test_support;
+pub mod util;
 
 pub use config::Config;
 pub use esp32::{Esp32P4Processor, EspChecksum};
 pub use firmware::{FirmwareBinary, FirmwareLoader};
 pub use image::ImageBuilder;
+pub use lock::LayoutLock;
 pub use partition::PartitionGenerator;
 
 pub type Result<T> = anyhow::Result<T>;
@@ -0,0 +1,199 @@
+use crate::config::{PartitionSpec, format_flags, parse_flags, parse_partition_type, parse_size};
+use anyhow::anyhow;
+use esp_idf_part::{Flags, Partition, PartitionTable};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::path::Path;
+
+pub const LOCK_FILE_VERSION: u32 = 1;
+
+/// Partition layout recorded after a successful build (`composer.lock`)
+///
+/// Later builds keep every locked partition at its offset and size and only
+/// append new partitions, so tables stay identical across releases.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LayoutLock {
+    pub version: u32,
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub partition_table_offset: u32,
+    pub partitions: Vec<LockedPartition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LockedPartition {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub subtype: String,
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub offset: u32,
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub size: u32,
+    #[serde(
+        default = "Flags::empty",
+        serialize_with = "serialize_flags",
+        deserialize_with = "deserialize_flags"
+    )]
+    pub flags: Flags,
+}
+
+impl LayoutLock {
+    /// Record the layout of a generated partition table
+    pub fn from_table(table: &PartitionTable, partition_table_offset: u32) -> Self {
+        Self {
+            version: LOCK_FILE_VERSION,
+            partition_table_offset,
+            partitions: table
+                .partitions()
+                .iter()
+                .map(|p| LockedPartition {
+                    name: p.name(),
+                    ty: p.ty().to_string(),
+                    subtype: p.subtype().to_string(),
+                    offset: p.offset(),
+                    size: p.size(),
+                    flags: p.flags(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read lock file {:?}: {}", path, e))?;
+        let lock: LayoutLock = serde_json::from_str(&content)
+            .map_err(|e| anyhow!("Invalid lock file {:?}: {}", path, e))?;
+
+        if lock.version != LOCK_FILE_VERSION {
+            return Err(anyhow!(
+                "Unsupported lock file version {} in {:?} (expected {})",
+                lock.version,
+                path,
+                LOCK_FILE_VERSION
+            ));
+        }
+
+        Ok(lock)
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let mut content = serde_json::to_string_pretty(self)?;
+        content.push('\n');
+        std::fs::write(path, content)
+            .map_err(|e| anyhow!("Failed to write lock file {:?}: {}", path, e))
+    }
+
+    /// Rebuild the partition table the lock file describes
+    pub fn to_table(&self) -> anyhow::Result<PartitionTable> {
+        let partitions = self
+            .partitions
+            .iter()
+            .map(|locked| {
+                if locked.offset.checked_add(locked.size).is_none() {
+                    return Err(anyhow!(
+                        "Partition '{}' ends beyond the 32-bit address space",
+                        locked.name
+                    ));
+                }
+                let ty = parse_partition_type(&locked.ty)?;
+                let subtype = PartitionSpec {
+                    name: locked.name.clone(),
+                    ty: Some(ty),
+                    subtype: Some(locked.subtype.clone()),
+                    offset: None,
+                    size: None,
+                    file: None,
+                    flags: Flags::empty(),
+                }
+                .resolve_subtype()?;
+                Ok(Partition::new(
+                    locked.name.clone(),
+                    ty,
+                    subtype,
+                    locked.offset,
+                    locked.size,
+                    locked.flags,
+                ))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(PartitionTable::new(partitions))
+    }
+
+    pub fn find(&self, name: &str) -> Option<&LockedPartition> {
+        self.partitions.iter().find(|p| p.name == name)
+    }
+}
+
+fn serialize_hex<S: Serializer>(value: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("0x{:X}", value))
+}
+
+fn deserialize_hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    parse_size(&text).map_err(serde::de::Error::custom)
+}
+
+fn serialize_flags<S: Serializer>(flags: &Flags, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_flags(*flags))
+}
+
+fn deserialize_flags<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Flags, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    parse_flags(&text).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use esp_idf_part::{AppType, DataType, SubType, Type};
+
+    #[test]
+    fn test_lock_round_trip() -> anyhow::Result<()> {
+        let table = PartitionTable::new(vec![
+            Partition::new(
+                "nvs",
+                Type::Data,
+                SubType::Data(DataType::Nvs),
+                0x11000,
+                0x1000,
+                Flags::empty(),
+            ),
+            Partition::new(
+                "ota_0",
+                Type::App,
+                SubType::App(AppType::Ota_0),
+                0x120000,
+                0x50000,
+                Flags::ENCRYPTED,
+            ),
+        ]);
+        let lock = LayoutLock::from_table(&table, 0x10000);
+
+        let temp_dir = tempfile::TempDir::new()?;
+        let path = temp_dir.path().join("composer.lock");
+        lock.save_to_file(&path)?;
+
+        let content = std::fs::read_to_string(&path)?;
+        assert!(content.contains("\"offset\": \"0x120000\""));
+        assert!(content.contains("\"subtype\": \"ota_0\""));
+        assert!(content.contains("\"flags\": \"encrypted\""));
+
+        let loaded = LayoutLock::load_from_file(&path)?;
+        assert_eq!(loaded, lock);
+        assert_eq!(loaded.find("ota_0").unwrap().size, 0x50000);
+        assert_eq!(loaded.to_table()?.partitions(), table.partitions());
+
+        let mut bogus = lock.clone();
+        bogus.partitions[1].offset = 0xFFFF_0000;
+        assert!(bogus.to_table().is_err());
+
+        std::fs::write(&path, content.replace("\"version\": 1", "\"version\": 9"))?;
+        assert!(LayoutLock::load_from_file(&path).is_err());
+
+        Ok(())
+    }
+}
@@ -1,11 +1,24 @@
 use clap::Parser;
 use colored::*;
+use esp_idf_part::{AppType, Partition, PartitionTable, SubType};
 use esp32_image_composer_rs::{
-    cli::Args, config::Config, firmware::FirmwareLoader, image::ImageBuilder,
+    batch::{self, BatchBuilder},
+    cli::Args,
+    config::{AllocationStrategy, Config, FlashSize, PartitionSpec, defaults, format_flags},
+    filesystem::{self, fat::FatConfig, littlefs::LittlefsConfig, spiffs::SpiffsConfig},
+    firmware::FirmwareLoader,
+    image::ImageBuilder,
+    lock::LayoutLock,
+    nvs, otadata,
+    partition::{
+        self, PartitionGenerator,
+        binary::{self, Md5Status},
+    },
+    util::hex,
 };
 use log::LevelFilter;
 use std::fs;
-use std::io::Write;
+use std::io;
 use std::process;
 
 fn main() {
@@ -28,25 +41,70 @@ fn main() {
 }
 
 fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
-    let config = Config {
+    let mut config = Config {
         flash_size: args.get_flash_size_enum(),
         firmware_dir: args.firmware_dir.clone(),
         output_file: args.output.clone(),
         max_ota_partitions: args.max_ota_partitions,
+        extra_ota_slots: args.extra_ota_slots,
+        extra_ota_size: args.extra_ota_size,
+        fill_partition: args.fill_partition.clone(),
+        partition_table_offset: args.partition_table_offset,
+        partition_table_md5: !args.no_partition_table_md5,
+        layout: args.layout,
+        allocation: args.allocation,
+        partitions: match &args.partitions {
+            Some(path) => PartitionSpec::load_from_file(path)?,
+            None => Vec::new(),
+        },
+        layout_lock: None,
+        boot_slot: args.boot_slot,
+        boot_state: args.boot_state,
+        nvs_csv: args.nvs.clone(),
+        nvs_keys: args.nvs_keys.clone(),
+        spiffs_dir: args.spiffs.clone(),
+        spiffs: SpiffsConfig {
+            page_size: args.spiffs_page_size,
+            block_size: args.spiffs_block_size,
+            obj_name_len: args.spiffs_obj_name_len,
+            meta_len: args.spiffs_meta_len,
+        },
+        littlefs_dir: args.littlefs.clone(),
+        littlefs: LittlefsConfig {
+            name_max: args.littlefs_name_max,
+            page_size: args.littlefs_page_size,
+        },
+        fat_dir: args.fat.clone(),
+        fat: FatConfig {
+            wear_levelling: args.fat_wear_levelling,
+        },
         verbose: args.verbose,
         pad_flash: args.pad_flash,
     };
 
     match args.command {
-        Some(Commands::PartitionTable { output, csv }) => {
-            generate_partition_table(&config, &output, csv, args.dry_run)?;
+        Some(Commands::PartitionTable {
+            output,
+            csv,
+            template,
+        }) => {
+            load_layout_lock(&mut config, &args.lock_file, args.relayout)?;
+            generate_partition_table(&config, &output, csv, template, args.dry_run)?;
         }
         Some(Commands::Validate { detailed }) => {
+            load_layout_lock(&mut config, &args.lock_file, args.relayout)?;
             validate_firmwares(&config, detailed)?;
         }
         Some(Commands::Info { show_sizes }) => {
             show_firmware_info(&config, show_sizes)?;
         }
+        Some(Commands::Convert {
+            input,
+            output,
+            format,
+        }) => {
+            convert_partition_table(&config, &input, output.as_deref(), format.as_deref())?;
+        }
         Some(Commands::Inspect {
             image_file,
             detailed,
@@ -54,19 +112,85 @@ fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
         }) => {
             inspect_flash_image(&image_file, detailed, verify_checksums)?;
         }
+        Some(Commands::Batch {
+            devices,
+            template,
+            output_dir,
+        }) => {
+            load_layout_lock(&mut config, &args.lock_file, args.relayout)?;
+            generate_batch(
+                &config,
+                &devices,
+                &template,
+                &output_dir,
+                &args.lock_file,
+                args.dry_run,
+            )?;
+        }
+        Some(Commands::NvsDump {
+            image_file,
+            partition,
+            keys,
+            json,
+        }) => {
+            dump_nvs(&image_file, &partition, keys.as_deref(), json)?;
+        }
+        Some(Commands::FsList {
+            image_file,
+            partition,
+            json,
+        }) => {
+            list_filesystems(&image_file, partition.as_deref(), &config.spiffs, json)?;
+        }
+        Some(Commands::FsExtract {
+            image_file,
+            partition,
+            output_dir,
+        }) => {
+            extract_filesystems(
+                &image_file,
+                partition.as_deref(),
+                &config.spiffs,
+                &output_dir,
+            )?;
+        }
         None => {
-            generate_flash_image(&config, args.dry_run)?;
+            load_layout_lock(&mut config, &args.lock_file, args.relayout)?;
+            generate_flash_image(&config, &args.lock_file, args.dry_run)?;
         }
     }
 
     Ok(())
 }
 
+/// Reuse the layout recorded in the lock file; only the commands that
+/// allocate partitions read it, so a stale lock can't break the others
+fn load_layout_lock(
+    config: &mut Config,
+    lock_file: &std::path::Path,
+    relayout: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !relayout && lock_file.exists() {
+        config.layout_lock = Some(LayoutLock::load_from_file(lock_file)?);
+    }
+    Ok(())
+}
+
 use esp32_image_composer_rs::cli::Commands;
 
-fn generate_flash_image(config: &Config, dry_run: bool) -> Result<(), Box<dyn std::error::Error>> {
+fn generate_flash_image(
+    config: &Config,
+    lock_file: &std::path::Path,
+    dry_run: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
     println!("{}", "🚀 ESP32 Image Composer".green().bold());
     println!("Flash size: {}\n", config.flash_size.size_bytes());
+    if config.layout_lock.is_some() {
+        println!(
+            "Reusing partition layout from {}\n",
+            lock_file.display().to_string().cyan()
+        );
+    }
 
     // Load firmware files
     println!("{} firmware directory...", "Loading".blue());
@@ -92,43 +216,187 @@ fn generate_flash_image(config: &Config, dry_run: bool) -> Result<(), Box<dyn st
 
     // Build flash image
     println!("{} flash image...", "Building".blue());
-    let flash_image = ImageBuilder::build_flash_image(&firmwares, config)?;
+    let partition_table = PartitionGenerator::generate_table(&firmwares, config)?;
+    print_layout_waste(&firmwares, &partition_table, config, dry_run);
 
     if !dry_run {
-        // Write to output file
+        // Stream to the output file; the gaps are never held in memory
         println!(
             "{} to {}...",
             "Writing".blue(),
             config.output_file.display()
         );
-        let mut file = fs::File::create(&config.output_file)?;
-        file.write_all(&flash_image)?;
-        file.sync_all()?;
+        let mut writer = io::BufWriter::new(fs::File::create(&config.output_file)?);
+        let written =
+            ImageBuilder::write_flash_image(&firmwares, &partition_table, config, &mut writer);
+        let len = match written {
+            Ok(len) => len,
+            Err(e) => {
+                // Don't leave a truncated image behind
+                drop(writer);
+                let _ = fs::remove_file(&config.output_file);
+                return Err(e.into());
+            }
+        };
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
 
         println!(
             "✅ {} created successfully! ({})",
             config.output_file.display().to_string().green(),
-            format_size(flash_image.len() as u32)
+            format_size(len as u32)
+        );
+
+        LayoutLock::from_table(&partition_table, config.partition_table_offset)
+            .save_to_file(lock_file)?;
+        println!(
+            "🔒 Partition layout recorded in {}",
+            lock_file.display().to_string().green()
         );
     } else {
         println!(
             "📄 Would create flash image: {} ({})",
             config.output_file.display(),
-            format_size(flash_image.len() as u32)
+            format_size(ImageBuilder::write_flash_image(
+                &firmwares,
+                &partition_table,
+                config,
+                &mut io::sink()
+            )? as u32)
         );
     }
 
     Ok(())
 }
 
+fn generate_batch(
+    config: &Config,
+    devices: &std::path::Path,
+    template: &std::path::Path,
+    output_dir: &std::path::Path,
+    lock_file: &std::path::Path,
+    dry_run: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!("{}", "🏭 ESP32 Image Composer - Batch".green().bold());
+
+    let devices = batch::load_devices(devices)?;
+    let template = BatchBuilder::load_template(template)?;
+    println!("Found {} devices", devices.len());
+
+    println!("{} firmware directory...", "Loading".blue());
+    let firmwares = FirmwareLoader::load_from_directory(&config.firmware_dir)?;
+    println!("Found {} firmware files", firmwares.len());
+
+    let partition_table = PartitionGenerator::generate_table(&firmwares, config)?;
+    let builder = BatchBuilder::new(&firmwares, &partition_table, config, template)?;
+
+    if dry_run {
+        // Lay out every device once so template errors surface without writing files
+        for device in &devices {
+            builder.device_layout(device)?;
+        }
+        println!(
+            "📄 Would create {} images and {} in {}",
+            devices.len(),
+            batch::INDEX_FILE,
+            output_dir.display()
+        );
+        return Ok(());
+    }
+
+    println!(
+        "{} {} images to {}...",
+        "Writing".blue(),
+        devices.len(),
+        output_dir.display()
+    );
+    let images = builder.write_all(&devices, output_dir)?;
+    println!(
+        "✅ {} images created, index in {}",
+        images.len(),
+        output_dir
+            .join(batch::INDEX_FILE)
+            .display()
+            .to_string()
+            .green()
+    );
+
+    LayoutLock::from_table(&partition_table, config.partition_table_offset)
+        .save_to_file(lock_file)?;
+    println!(
+        "🔒 Partition layout recorded in {}",
+        lock_file.display().to_string().green()
+    );
+
+    Ok(())
+}
+
+/// Show flash lost to alignment gaps; dry runs compare every allocation strategy
+fn print_layout_waste(
+    firmwares: &[esp32_image_composer_rs::FirmwareBinary],
+    partition_table: &esp_idf_part::PartitionTable,
+    config: &Config,
+    dry_run: bool,
+) {
+    let wasted = PartitionGenerator::wasted_bytes(partition_table, config.partition_table_offset);
+    println!(
+        "Layout: {} allocation, {} unused between partitions",
+        config.allocation.to_string().cyan(),
+        format_size(wasted)
+    );
+
+    if dry_run {
+        for allocation in [AllocationStrategy::Sequential, AllocationStrategy::Packed] {
+            if allocation == config.allocation {
+                continue;
+            }
+            let alternative = Config {
+                allocation,
+                ..config.clone()
+            };
+            match PartitionGenerator::generate_table(firmwares, &alternative) {
+                Ok(table) => println!(
+                    "  {} {} allocation would leave {} unused",
+                    "▸".yellow(),
+                    allocation,
+                    format_size(PartitionGenerator::wasted_bytes(
+                        &table,
+                        config.partition_table_offset
+                    ))
+                ),
+                Err(e) => println!("  {} {} allocation fails: {}", "▸".yellow(), allocation, e),
+            }
+        }
+    }
+    println!();
+}
+
 fn generate_partition_table(
     config: &Config,
     output: &std::path::Path,
     csv: bool,
+    template: bool,
     dry_run: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
     println!("{}", "🗂️  Partition Table Generator".green().bold());
 
+    let partition_table = if template {
+        println!(
+            "{} template table for the {} layout...",
+            "Generating".blue(),
+            config.layout
+        );
+        PartitionGenerator::generate_template(config)?
+    } else {
+        // Same firmware set and table as the flash image build
+        println!("{} firmware directory...", "Loading".blue());
+        let firmwares = FirmwareLoader::load_from_directory(&config.firmware_dir)?;
+        println!("Found {} firmware files", firmwares.len());
+        PartitionGenerator::generate_table(&firmwares, config)?
+    };
+
     if dry_run {
         println!(
             "{}",
@@ -136,34 +404,24 @@ fn generate_partition_table(
                 .yellow()
                 .bold()
         );
+        for partition in partition_table.partitions() {
+            println!(
+                "  {} {} @ 0x{:X} ({})",
+                "▸".yellow(),
+                partition.name().cyan(),
+                partition.offset(),
+                format_size(partition.size())
+            );
+        }
         return Ok(());
     }
 
     let partition_table_data = if csv {
-        // Generate CSV format
-        println!("{} CSV partition table...", "Generating".blue());
-        let dummy_bootloader = esp32_image_composer_rs::firmware::FirmwareBinary::new(
-            "bootloader".to_string(),
-            config.firmware_dir.join("dummy-bootloader.bin"),
-            vec![0; 32 * 1024],
-            1,
-        );
-        let dummy_factory = esp32_image_composer_rs::firmware::FirmwareBinary::new(
-            "factory".to_string(),
-            config.firmware_dir.join("dummy-factory.bin"),
-            vec![0; 1 * 1024 * 1024],
-            2,
-        );
-        let partition_table =
-            esp32_image_composer_rs::partition::PartitionGenerator::generate_table(
-                &[dummy_bootloader, dummy_factory],
-                config,
-            )?;
+        println!("{} CSV partition table...", "Writing".blue());
         partition_table.to_csv()?.into_bytes()
     } else {
-        // Generate binary format
-        println!("{} binary partition table...", "Generating".blue());
-        ImageBuilder::build_partition_table_only(config)?
+        println!("{} binary partition table...", "Writing".blue());
+        binary::to_bytes(&partition_table, config.partition_table_md5)?
     };
 
     fs::write(output, partition_table_data)?;
@@ -175,6 +433,60 @@ fn generate_partition_table(
     Ok(())
 }
 
+fn convert_partition_table(
+    config: &Config,
+    input: &std::path::Path,
+    output: Option<&std::path::Path>,
+    format: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (table, table_offset) = partition::load_table_file(input, config.partition_table_offset)?;
+    PartitionGenerator::validate_partition_table(&table, config.flash_size.size_bytes())
+        .map_err(|e| format!("Partition table {} is invalid: {}", input.display(), e))?;
+
+    let format = format
+        .map(str::to_string)
+        .or_else(|| {
+            output
+                .and_then(|path| path.extension())
+                .and_then(|e| e.to_str())
+                .map(str::to_ascii_lowercase)
+        })
+        .unwrap_or_else(|| "csv".to_string());
+
+    let data = match format.as_str() {
+        "csv" => table.to_csv()?.into_bytes(),
+        "bin" => binary::to_bytes(&table, config.partition_table_md5)?,
+        "json" => {
+            let lock = LayoutLock::from_table(
+                &table,
+                table_offset.unwrap_or(config.partition_table_offset),
+            );
+            let mut json = serde_json::to_string_pretty(&lock)?;
+            json.push('\n');
+            json.into_bytes()
+        }
+        other => return Err(format!("Unsupported output format '{}'", other).into()),
+    };
+
+    match output {
+        Some(path) => {
+            fs::write(path, data)?;
+            println!(
+                "✅ Converted {} ({} partitions) to {}",
+                input.display(),
+                table.partitions().len(),
+                path.display().to_string().green()
+            );
+        }
+        None if format == "bin" => {
+            return Err("Binary output needs --output".into());
+        }
+        None => print!("{}", String::from_utf8_lossy(&data)),
+    }
+
+    Ok(())
+}
+
 fn validate_firmwares(config: &Config, detailed: bool) -> Result<(), Box<dyn std::error::Error>> {
     println!("{}", "✅ Firmware Validator".green().bold());
 
@@ -208,21 +520,23 @@ fn validate_firmwares(config: &Config, detailed: bool) -> Result<(), Box<dyn std
             )?;
 
         for partition in partition_table.partitions() {
+            let flags = format_flags(partition.flags());
             println!(
-                "  {} {} @ 0x{:X} ({} bytes) [{}]",
+                "  {} {} @ 0x{:X} ({} bytes) [{}]{}",
                 "▸".yellow(),
                 partition.name().cyan(),
                 partition.offset(),
                 format_size(partition.size()),
-                format!("{:?}", partition.subtype()).dimmed()
+                format!("{:?}", partition.subtype()).dimmed(),
+                if flags.is_empty() {
+                    String::new()
+                } else {
+                    format!(" {}", flags.magenta())
+                }
             );
         }
 
-        let total_used: u32 = partition_table
-            .partitions()
-            .into_iter()
-            .map(|p| p.size())
-            .sum();
+        let total_used: u32 = partition_table.partitions().iter().map(|p| p.size()).sum();
         let flash_size = config.flash_size.size_bytes();
         let usage_percent = (total_used as f64 / flash_size as f64) * 100.0;
 
@@ -293,7 +607,7 @@ fn format_size(bytes: u32) -> String {
 }
 
 fn align_size(size: u32, alignment: u32) -> u32 {
-    ((size + alignment - 1) / alignment) * alignment
+    size.div_ceil(alignment) * alignment
 }
 
 fn inspect_flash_image(
@@ -317,16 +631,28 @@ fn inspect_flash_image(
     // Analyze key components
     println!("\n{}", "🧩 Component Analysis:".blue().bold());
 
+    // Everything after the bootloader is located through the partition table
+    let found = binary::find_in_image(&image_data);
+    let table_offset = found
+        .as_ref()
+        .map_or(defaults::PARTITION_TABLE_OFFSET, |(offset, _)| *offset);
+    let partitions: Vec<Partition> = found
+        .as_ref()
+        .map(|(_, table)| table.partitions.clone())
+        .unwrap_or_default();
+
     // Check bootloader at 0x2000
     if image_size > 0x2000 {
         println!("\n  🚀 Bootloader (offset 0x2000):");
-        if let Some(bootloader_data) = get_component_at_offset(&image_data, 0x2000, 0x8000) {
+        if let Some(bootloader_data) =
+            get_component_at_offset(&image_data, 0x2000, table_offset as usize)
+        {
             println!(
                 "    Size: {} bytes",
                 format_size(bootloader_data.len() as u32)
             );
 
-            if bootloader_data.len() > 0 {
+            if !bootloader_data.is_empty() {
                 println!(
                     "    Magic: 0x{:02X} {}",
                     bootloader_data[0],
@@ -340,23 +666,22 @@ fn inspect_flash_image(
                 if verify_checksums {
                     if let Ok(verified) =
                         esp32_image_composer_rs::esp32::EspChecksum::verify_checksum(
-                            &bootloader_data,
+                            bootloader_data,
                         )
                     {
                         println!(
-                            "    Checksum: {} {}",
+                            "    Checksum: {} (0x{:02X})",
                             if verified { "✅".green() } else { "❌".red() },
-                            format!("(0x{:02X})", bootloader_data[bootloader_data.len() - 1])
+                            bootloader_data[bootloader_data.len() - 1]
                         );
 
-                        if !verified {
-                            if let Ok(calculated) =
+                        if !verified
+                            && let Ok(calculated) =
                                 esp32_image_composer_rs::esp32::EspChecksum::calculate_checksum(
                                     &bootloader_data[..bootloader_data.len() - 1],
                                 )
-                            {
-                                println!("    Calculated: 0x{:02X}", calculated);
-                            }
+                        {
+                            println!("    Calculated: 0x{:02X}", calculated);
                         }
                     } else {
                         println!("    Checksum: ⚠️  Unable to verify");
@@ -373,62 +698,64 @@ fn inspect_flash_image(
         }
     }
 
-    // Check partition table at 0x8000
-    if image_size > 0x8000 {
-        println!("\n  📋 Partition Table (offset 0x8000):");
-        if let Some(pt_data) = get_component_at_offset(&image_data, 0x8000, 0x9000) {
-            println!("    Size: {} bytes", format_size(pt_data.len() as u32));
-
-            if pt_data.len() > 0 {
+    // Locate the partition table and check its MD5 row
+    match found {
+        Some((offset, table)) => {
+            println!("\n  📋 Partition Table (offset 0x{:X}):", offset);
+            for (index, partition) in table.partitions.iter().enumerate() {
+                let flags = format_flags(partition.flags());
                 println!(
-                    "    Magic: 0x{:02X}{:02X} {}",
-                    pt_data[0],
-                    pt_data[1],
-                    if pt_data[0] == 0xAA && pt_data[1] == 0x50 {
-                        "(valid MD5)"
+                    "      📦 Partition {}: {} {}/{} at 0x{:X} ({}){}",
+                    index + 1,
+                    partition.name().cyan(),
+                    partition.ty(),
+                    partition.subtype(),
+                    partition.offset(),
+                    format_size(partition.size()),
+                    if flags.is_empty() {
+                        String::new()
                     } else {
-                        "(invalid)"
+                        format!(" {}", flags.magenta())
                     }
                 );
+            }
+            println!("    Total partitions: {}", table.partitions.len());
 
-                // Count partitions
-                let mut partition_count = 0;
-                for chunk in pt_data.chunks(32) {
-                    if chunk.len() >= 2 && chunk[0] == 0xAA && chunk[1] == 0x50 {
-                        partition_count += 1;
-                        // Extract partition name if valid
-                        if chunk.len() >= 16 {
-                            let name_bytes = &chunk[8..24];
-                            if let Ok(name) = std::str::from_utf8(name_bytes) {
-                                let name_clean = name.trim_end_matches('\0');
-                                if !name_clean.is_empty() {
-                                    println!(
-                                        "      📦 Partition {}: {}",
-                                        partition_count,
-                                        name_clean.cyan()
-                                    );
-                                }
-                            }
-                        }
-                    } else if chunk.len() >= 2 && chunk[0] == 0xEB && chunk[1] == 0xEB {
-                        // MD5 magic - end of partitions
-                        break;
-                    }
+            match table.md5 {
+                Md5Status::Valid => println!("    MD5: {}", "✅ valid".green()),
+                Md5Status::Missing => {
+                    println!(
+                        "    MD5: {}",
+                        "none (legacy table without MD5 row)".yellow()
+                    )
                 }
-                println!("    Total partitions: {}", partition_count);
+                Md5Status::Mismatch { stored, computed } => println!(
+                    "    MD5: {} (stored {}, computed {})",
+                    "❌ mismatch".red(),
+                    hex(&stored),
+                    hex(&computed)
+                ),
             }
-        } else {
-            println!("    ❌ Not found or invalid");
+
+            print_otadata(&image_data, &PartitionTable::new(table.partitions));
         }
+        None => println!("\n  📋 Partition Table: ❌ Not found"),
     }
 
-    // Check factory app at 0x10000
-    if image_size > 0x10000 {
-        println!("\n  🏭 Factory App (offset 0x10000):");
-        if let Some(factory_data) = get_component_at_offset(&image_data, 0x10000, 0x20000) {
+    // Check the factory app in the partition the table names
+    let factory = partitions
+        .iter()
+        .find(|p| p.subtype() == SubType::App(AppType::Factory) && p.name() != "bootloader");
+    if let Some(factory) = factory {
+        println!("\n  🏭 Factory App (offset 0x{:X}):", factory.offset());
+        if let Some(factory_data) = get_component_at_offset(
+            &image_data,
+            factory.offset() as usize,
+            factory.offset() as usize + factory.size() as usize,
+        ) {
             println!("    Size: {} bytes", format_size(factory_data.len() as u32));
 
-            if factory_data.len() > 0 {
+            if !factory_data.is_empty() {
                 println!(
                     "    Magic: 0x{:02X} {}",
                     factory_data[0],
@@ -441,22 +768,21 @@ fn inspect_flash_image(
 
                 if verify_checksums {
                     if let Ok(verified) =
-                        esp32_image_composer_rs::esp32::EspChecksum::verify_checksum(&factory_data)
+                        esp32_image_composer_rs::esp32::EspChecksum::verify_checksum(factory_data)
                     {
                         println!(
-                            "    Checksum: {} {}",
+                            "    Checksum: {} (0x{:02X})",
                             if verified { "✅".green() } else { "❌".red() },
-                            format!("(0x{:02X})", factory_data[factory_data.len() - 1])
+                            factory_data[factory_data.len() - 1]
                         );
 
-                        if !verified {
-                            if let Ok(calculated) =
+                        if !verified
+                            && let Ok(calculated) =
                                 esp32_image_composer_rs::esp32::EspChecksum::calculate_checksum(
                                     &factory_data[..factory_data.len() - 1],
                                 )
-                            {
-                                println!("    Calculated: 0x{:02X}", calculated);
-                            }
+                        {
+                            println!("    Calculated: 0x{:02X}", calculated);
                         }
                     } else {
                         println!("    Checksum: ⚠️  Unable to verify");
@@ -476,33 +802,39 @@ fn inspect_flash_image(
     if detailed {
         println!("\n{}", "🔬 Detailed Analysis:".blue().bold());
 
-        // Look for OTA partitions
+        // Look for OTA partitions in the table
         let mut ota_count = 0;
-        for i in 0..16 {
-            let ota_offset = 0x110000 + (i * 0x100000);
-            if image_size > ota_offset {
-                if let Some(ota_data) =
-                    get_component_at_offset(&image_data, ota_offset, ota_offset + 0x100000)
+        for partition in &partitions {
+            let SubType::App(app) = partition.subtype() else {
+                continue;
+            };
+            let Some(i) = (app as usize)
+                .checked_sub(AppType::Ota_0 as usize)
+                .filter(|&i| i < defaults::MAX_OTA_SLOTS)
+            else {
+                continue;
+            };
+            let ota_offset = partition.offset() as usize;
+            if let Some(ota_data) = get_component_at_offset(
+                &image_data,
+                ota_offset,
+                ota_offset + partition.size() as usize,
+            ) && ota_data.len() > 1000
+                && ota_data[0] == 0xE9
+            {
+                // Valid ESP32 app
+                ota_count += 1;
+                println!("  🔄 OTA Partition {} (offset 0x{:X}):", i, ota_offset);
+                println!("    Size: {} bytes", format_size(ota_data.len() as u32));
+
+                if verify_checksums
+                    && let Ok(verified) =
+                        esp32_image_composer_rs::esp32::EspChecksum::verify_checksum(ota_data)
                 {
-                    if ota_data.len() > 1000 && ota_data[0] == 0xE9 {
-                        // Valid ESP32 app
-                        ota_count += 1;
-                        println!("  🔄 OTA Partition {} (offset 0x{:X}):", i, ota_offset);
-                        println!("    Size: {} bytes", format_size(ota_data.len() as u32));
-
-                        if verify_checksums {
-                            if let Ok(verified) =
-                                esp32_image_composer_rs::esp32::EspChecksum::verify_checksum(
-                                    &ota_data,
-                                )
-                            {
-                                println!(
-                                    "    Checksum: {}",
-                                    if verified { "✅".green() } else { "❌".red() }
-                                );
-                            }
-                        }
-                    }
+                    println!(
+                        "    Checksum: {}",
+                        if verified { "✅".green() } else { "❌".red() }
+                    );
                 }
             }
         }
@@ -531,6 +863,300 @@ fn inspect_flash_image(
     Ok(())
 }
 
+/// Decode the NVS partition found through the image's partition table
+fn dump_nvs(
+    image_file: &std::path::Path,
+    partition_name: &str,
+    keys: Option<&std::path::Path>,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let image_data = std::fs::read(image_file)?;
+    let (_, table) = binary::find_in_image(&image_data)
+        .ok_or_else(|| format!("No partition table found in {}", image_file.display()))?;
+    let partition = table
+        .partitions
+        .iter()
+        .find(|p| p.name() == partition_name)
+        .ok_or_else(|| format!("Partition '{}' not found in the table", partition_name))?;
+
+    let mut data = partition_data(&image_data, partition)?;
+    if let Some(keys) = keys {
+        nvs::crypt::NvsKeys::load_from_file(keys)?.decrypt_partition(&mut data);
+    }
+    let pages = nvs::dump::decode(&data);
+
+    if json {
+        let dump = nvs::dump::NvsDump {
+            partition: partition.name(),
+            offset: partition.offset(),
+            size: partition.size(),
+            pages,
+        };
+        println!("{}", serde_json::to_string_pretty(&dump)?);
+        return Ok(());
+    }
+
+    println!(
+        "{} '{}' at 0x{:X} ({})\n",
+        "🗄️  NVS partition".green().bold(),
+        partition.name().cyan(),
+        partition.offset(),
+        format_size(partition.size())
+    );
+    let mut entries = pages.iter().flat_map(|page| &page.entries).peekable();
+    if keys.is_none() && entries.peek().is_some() && entries.all(|entry| !entry.crc_valid) {
+        println!(
+            "{}\n",
+            "⚠️  No entry passes its CRC; if NVS encryption is enabled, pass --keys".yellow()
+        );
+    }
+    for page in &pages {
+        if page.state == nvs::dump::PageState::Empty {
+            println!("Page {}: {}", page.index, "empty".dimmed());
+            continue;
+        }
+        println!(
+            "Page {}: {:?}, seq {}, version 0x{:02X}, header CRC {}, {} free entries",
+            page.index,
+            page.state,
+            page.sequence,
+            page.version,
+            if page.header_crc_valid {
+                "✅".green()
+            } else {
+                "❌".red()
+            },
+            page.empty_entries
+        );
+        for entry in &page.entries {
+            let namespace = match (&entry.namespace, entry.ns_index) {
+                (Some(name), _) => name.clone(),
+                (None, 0) => "-".to_string(),
+                (None, index) => format!("#{}", index),
+            };
+            let chunk = entry
+                .chunk_index
+                .map(|chunk| format!(" [chunk {}]", chunk))
+                .unwrap_or_default();
+            let line = format!(
+                "  {:>3} {:<12} {:<16} {:<10} {}{}",
+                entry.index, namespace, entry.key, entry.ty, entry.value, chunk
+            );
+            let mut notes = Vec::new();
+            if entry.state != nvs::dump::EntryState::Written {
+                notes.push(format!("{:?}", entry.state).to_lowercase());
+            }
+            if !entry.crc_valid {
+                notes.push("CRC mismatch".to_string());
+            }
+            if notes.is_empty() {
+                println!("{}", line);
+            } else if entry.state == nvs::dump::EntryState::Erased && entry.crc_valid {
+                println!("{} ({})", line.dimmed(), notes.join(", "));
+            } else {
+                println!("{} {}", line, format!("({})", notes.join(", ")).red());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Bytes of a partition; minimal images end at their last component, and the
+/// missing flash reads as erased up to the largest supported flash
+fn partition_data(
+    image_data: &[u8],
+    partition: &Partition,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let start = partition.offset() as usize;
+    let end = start + partition.size() as usize;
+    let flash_end = image_data
+        .len()
+        .max(FlashSize::Size128MB.size_bytes() as usize);
+    if end > flash_end {
+        return Err(format!(
+            "Partition '{}' at 0x{:X} ({} bytes) ends beyond the image and the largest flash",
+            partition.name(),
+            partition.offset(),
+            partition.size()
+        )
+        .into());
+    }
+    let mut data = image_data
+        .get(start..end.min(image_data.len()))
+        .unwrap_or_default()
+        .to_vec();
+    data.resize(partition.size() as usize, 0xFF);
+    Ok(data)
+}
+
+/// Filesystem partitions of the image's table, or only the named one
+fn filesystem_partitions(
+    image_data: &[u8],
+    image_file: &std::path::Path,
+    partition_name: Option<&str>,
+) -> Result<Vec<Partition>, Box<dyn std::error::Error>> {
+    let (_, table) = binary::find_in_image(image_data)
+        .ok_or_else(|| format!("No partition table found in {}", image_file.display()))?;
+    let partitions: Vec<Partition> = match partition_name {
+        Some(name) => vec![
+            table
+                .partitions
+                .iter()
+                .find(|p| p.name() == name)
+                .cloned()
+                .ok_or_else(|| format!("Partition '{}' not found in the table", name))?,
+        ],
+        None => table
+            .partitions
+            .iter()
+            .filter(|p| filesystem::is_readable(p))
+            .cloned()
+            .collect(),
+    };
+    if partitions.is_empty() {
+        return Err("No spiffs, littlefs or fat partition in the table".into());
+    }
+    Ok(partitions)
+}
+
+/// List the files of the filesystem partitions found through the image's partition table
+fn list_filesystems(
+    image_file: &std::path::Path,
+    partition_name: Option<&str>,
+    spiffs_config: &SpiffsConfig,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let image_data = std::fs::read(image_file)?;
+    let mut listings = Vec::new();
+    for partition in filesystem_partitions(&image_data, image_file, partition_name)? {
+        let data = partition_data(&image_data, &partition)?;
+        let contents = filesystem::read_partition(&partition, &data, spiffs_config)?;
+        listings.push(filesystem::FsListing::new(&partition, &contents));
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&listings)?);
+        return Ok(());
+    }
+
+    for listing in &listings {
+        println!(
+            "{} '{}' ({}) at 0x{:X} ({})",
+            "📁 Filesystem".green().bold(),
+            listing.partition.cyan(),
+            listing.filesystem,
+            listing.offset,
+            format_size(listing.size)
+        );
+        for dir in &listing.dirs {
+            println!("  {:>10}  {}/", "", dir.blue());
+        }
+        for file in &listing.files {
+            println!(
+                "  {:>10}  {}  {}",
+                file.size,
+                file.path,
+                file.sha256.dimmed()
+            );
+        }
+        let total: usize = listing.files.iter().map(|f| f.size).sum();
+        println!(
+            "  {} files, {} directories, {} bytes\n",
+            listing.files.len(),
+            listing.dirs.len(),
+            total
+        );
+    }
+
+    Ok(())
+}
+
+/// Extract the filesystem partitions into `<output_dir>/<partition name>/`
+fn extract_filesystems(
+    image_file: &std::path::Path,
+    partition_name: Option<&str>,
+    spiffs_config: &SpiffsConfig,
+    output_dir: &std::path::Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let image_data = std::fs::read(image_file)?;
+    for partition in filesystem_partitions(&image_data, image_file, partition_name)? {
+        let data = partition_data(&image_data, &partition)?;
+        let contents = filesystem::read_partition(&partition, &data, spiffs_config)?;
+        let target = output_dir.join(partition.name());
+        contents.extract(&target)?;
+        println!(
+            "✅ Extracted {} files from '{}' to {}",
+            contents.files.len(),
+            partition.name().cyan(),
+            target.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Show both otadata copies and the app the bootloader will start
+fn print_otadata(image_data: &[u8], table: &PartitionTable) {
+    let Some(partition) = otadata::find_otadata(table) else {
+        return;
+    };
+    println!("\n  🔀 OTA Data (offset 0x{:X}):", partition.offset());
+
+    let start = partition.offset() as usize;
+    let end = start + partition.size() as usize;
+    let Some(data) = image_data.get(start..end) else {
+        println!("    ⚠️  Beyond the end of the image (erased, boots factory)");
+        return;
+    };
+
+    let report = match otadata::decode_otadata(data, table) {
+        Ok(report) => report,
+        Err(e) => {
+            println!("    ❌ {}", e);
+            return;
+        }
+    };
+
+    for (index, entry) in report.entries.iter().enumerate() {
+        let status = if entry.is_erased() {
+            "erased".dimmed().to_string()
+        } else {
+            let state = entry
+                .state()
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| format!("unknown (0x{:X})", entry.ota_state));
+            let crc = if entry.crc_valid() {
+                "CRC ✅".green()
+            } else {
+                "CRC ❌".red()
+            };
+            format!("seq {}, state {}, {}", entry.ota_seq, state, crc)
+        };
+        let marker = if report.active == Some(index) {
+            " ◀ active"
+        } else {
+            ""
+        };
+        println!("    Sector {}: {}{}", index, status, marker.green());
+    }
+
+    match (&report.boot_partition, report.active) {
+        (Some(name), Some(_)) => println!("    Boots: {}", name.cyan()),
+        (Some(name), None) => println!("    Boots: {} (no valid otadata copy)", name.cyan()),
+        (None, _) => println!("    Boots: {}", "❌ no bootable app partition".red()),
+    }
+    if let Some(index) = report.active
+        && report.entries[index].state() == Some(otadata::OtaImageState::PendingVerify)
+    {
+        println!(
+            "    {}",
+            "⚠️  pending_verify: with rollback enabled the bootloader aborts this slot and falls back"
+                .yellow()
+        );
+    }
+}
+
 fn get_component_at_offset(
     image_data: &[u8],
     start_offset: usize,
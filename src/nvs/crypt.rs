@@ -0,0 +1,228 @@
+//! NVS encryption: XTS-AES-256 per 32-byte entry, keys from the `nvs_keys` partition
+
+use super::{ENTRIES_OFFSET, ENTRIES_PER_PAGE, ENTRY_SIZE, PAGE_SIZE, PAGE_STATE_EMPTY, crc32};
+use crate::Result;
+use aes::Aes256;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit, generic_array::GenericArray};
+use anyhow::anyhow;
+use std::path::Path;
+
+pub const KEY_SIZE: usize = 32;
+/// Both keys plus their CRC, as `nvs_flash_read_security_cfg` reads them
+const KEYS_RECORD_SIZE: usize = 2 * KEY_SIZE + 4;
+
+/// `nvs_sec_cfg_t`: XTS data key (`eky`) and tweak key (`tky`)
+#[derive(Clone, PartialEq, Eq)]
+pub struct NvsKeys {
+    pub eky: [u8; KEY_SIZE],
+    pub tky: [u8; KEY_SIZE],
+}
+
+impl std::fmt::Debug for NvsKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("NvsKeys { .. }")
+    }
+}
+
+impl NvsKeys {
+    /// Read 64 raw key bytes, or an `nvs_keys` partition image as written by
+    /// `nvs_partition_gen.py generate-key` (CRC checked)
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)
+            .map_err(|e| anyhow!("Failed to read NVS key file {:?}: {}", path, e))?;
+        Self::from_bytes(&data).map_err(|e| anyhow!("NVS key file {:?}: {}", path, e))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() != 2 * KEY_SIZE && data.len() < KEYS_RECORD_SIZE {
+            return Err(anyhow!(
+                "expected 64 key bytes or an nvs_keys partition image, found {} bytes",
+                data.len()
+            ));
+        }
+
+        let mut keys = Self {
+            eky: [0; KEY_SIZE],
+            tky: [0; KEY_SIZE],
+        };
+        keys.eky.copy_from_slice(&data[..KEY_SIZE]);
+        keys.tky.copy_from_slice(&data[KEY_SIZE..2 * KEY_SIZE]);
+
+        if data[..2 * KEY_SIZE].iter().all(|&b| b == 0xFF) {
+            return Err(anyhow!("keys are erased (all 0xFF)"));
+        }
+        if data.len() >= KEYS_RECORD_SIZE {
+            let stored = u32::from_le_bytes(data[64..68].try_into().unwrap());
+            if stored != keys.crc() {
+                return Err(anyhow!(
+                    "key CRC mismatch (stored 0x{:08X}, computed 0x{:08X})",
+                    stored,
+                    keys.crc()
+                ));
+            }
+        }
+
+        Ok(keys)
+    }
+
+    fn crc(&self) -> u32 {
+        crc32(&[&self.eky[..], &self.tky[..]].concat())
+    }
+
+    /// Content of the `nvs_keys` partition: both keys, their CRC, then erased flash
+    pub fn to_partition(&self, partition_size: u32) -> Vec<u8> {
+        let mut data = vec![0xFF; partition_size as usize];
+        data[..KEY_SIZE].copy_from_slice(&self.eky);
+        data[KEY_SIZE..2 * KEY_SIZE].copy_from_slice(&self.tky);
+        data[64..68].copy_from_slice(&self.crc().to_le_bytes());
+        data
+    }
+
+    /// Encrypt every used entry; page headers and state bitmaps stay in plain text
+    pub fn encrypt_partition(&self, data: &mut [u8]) {
+        self.for_each_used_entry(data, |address, entry| self.xts(address, entry, true));
+    }
+
+    pub fn decrypt_partition(&self, data: &mut [u8]) {
+        self.for_each_used_entry(data, |address, entry| self.xts(address, entry, false));
+    }
+
+    fn for_each_used_entry(&self, data: &mut [u8], mut apply: impl FnMut(u32, &mut [u8])) {
+        for (page_index, page) in data.chunks_exact_mut(PAGE_SIZE).enumerate() {
+            if u32::from_le_bytes(page[0..4].try_into().unwrap()) == PAGE_STATE_EMPTY {
+                continue;
+            }
+            let (header, entries) = page.split_at_mut(ENTRIES_OFFSET);
+            for (index, entry) in entries
+                .chunks_exact_mut(ENTRY_SIZE)
+                .take(ENTRIES_PER_PAGE)
+                .enumerate()
+            {
+                let state = (header[32 + index / 4] >> ((index % 4) * 2)) & 0b11;
+                if state != 0b11 {
+                    let address = page_index * PAGE_SIZE + ENTRIES_OFFSET + index * ENTRY_SIZE;
+                    apply(address as u32, entry);
+                }
+            }
+        }
+    }
+
+    /// XTS-AES-256 over one 32-byte data unit; the tweak is the entry's
+    /// offset within the partition
+    fn xts(&self, address: u32, unit: &mut [u8], encrypt: bool) {
+        let data_cipher = Aes256::new(GenericArray::from_slice(&self.eky));
+        let tweak_cipher = Aes256::new(GenericArray::from_slice(&self.tky));
+
+        let mut tweak = GenericArray::clone_from_slice(&(address as u128).to_le_bytes());
+        tweak_cipher.encrypt_block(&mut tweak);
+
+        for block in unit.chunks_exact_mut(16) {
+            let mut buffer = GenericArray::clone_from_slice(block);
+            buffer
+                .iter_mut()
+                .zip(tweak.iter())
+                .for_each(|(b, t)| *b ^= t);
+            if encrypt {
+                data_cipher.encrypt_block(&mut buffer);
+            } else {
+                data_cipher.decrypt_block(&mut buffer);
+            }
+            buffer
+                .iter_mut()
+                .zip(tweak.iter())
+                .for_each(|(b, t)| *b ^= t);
+            block.copy_from_slice(&buffer);
+
+            // Multiply the tweak by x in GF(2^128), little-endian
+            let carry = tweak[15] >> 7;
+            for i in (1..16).rev() {
+                tweak[i] = (tweak[i] << 1) | (tweak[i - 1] >> 7);
+            }
+            tweak[0] = (tweak[0] << 1) ^ (0x87 * carry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nvs::{NvsItem, NvsValue, build_partition, dump};
+    use crate::util::hex;
+
+    fn keys() -> NvsKeys {
+        let bytes: Vec<u8> = (0..64).collect();
+        NvsKeys::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn test_xts_matches_reference() {
+        // cryptography's AES-XTS with the 64-byte key and the entry offset as tweak
+        let keys = keys();
+        let mut unit: Vec<u8> = (0..32).collect();
+        keys.xts(0x40, &mut unit, true);
+        assert_eq!(
+            hex(&unit),
+            "7635fec7e95803eb7a706849d6014f4ac533b30d1b284960557fe8d6b0dd9ebb"
+        );
+
+        let mut unit = vec![0xFF; 32];
+        keys.xts(0x1060, &mut unit, true);
+        assert_eq!(
+            hex(&unit),
+            "2575bd0da1cfacfa7b6289b5e174b659cb7c64fa266baf8838a4e7b9284f37e1"
+        );
+        keys.xts(0x1060, &mut unit, false);
+        assert_eq!(unit, vec![0xFF; 32]);
+    }
+
+    #[test]
+    fn test_keys_partition() -> Result<()> {
+        let keys = keys();
+        let partition = keys.to_partition(0x1000);
+        assert_eq!(&partition[64..68], &0x9A7C_5245u32.to_le_bytes());
+        assert!(partition[68..].iter().all(|&b| b == 0xFF));
+        assert_eq!(NvsKeys::from_bytes(&partition)?, keys);
+
+        let mut corrupt = partition.clone();
+        corrupt[0] ^= 1;
+        assert!(NvsKeys::from_bytes(&corrupt).is_err());
+        assert!(NvsKeys::from_bytes(&[0xFF; 64]).is_err());
+        assert!(NvsKeys::from_bytes(&[0; 32]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_partition_round_trip() -> Result<()> {
+        let plain = build_partition(
+            &[NvsItem {
+                namespace: "wifi".to_string(),
+                key: "ssid".to_string(),
+                value: NvsValue::Str("factory-ap".to_string()),
+            }],
+            0x3000,
+        )?;
+
+        let keys = keys();
+        let mut encrypted = plain.clone();
+        keys.encrypt_partition(&mut encrypted);
+
+        // Header and bitmap stay readable, used entries are encrypted, free ones stay erased
+        assert_eq!(&encrypted[..ENTRIES_OFFSET], &plain[..ENTRIES_OFFSET]);
+        assert_ne!(&encrypted[64..160], &plain[64..160]);
+        assert!(encrypted[160..].iter().all(|&b| b == 0xFF));
+
+        let mut decrypted = encrypted;
+        keys.decrypt_partition(&mut decrypted);
+        assert_eq!(decrypted, plain);
+        assert!(
+            dump::decode(&decrypted)[0]
+                .entries
+                .iter()
+                .all(|e| e.crc_valid)
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+//! `nvs_partition_gen.py` CSV (`key,type,encoding,value`)
+
+use super::{NvsItem, NvsValue};
+use crate::Result;
+use anyhow::anyhow;
+use base64::Engine;
+use std::collections::HashMap;
+
+/// Parse the CSV into items; `file` rows are read relative to the working
+/// directory, as `nvs_partition_gen.py` does
+pub fn parse(text: &str) -> Result<Vec<NvsItem>> {
+    parse_with(text, |field| Ok(field.to_string()))
+}
+
+/// Parse a CSV whose fields contain `${column}` placeholders, filled from `values`
+pub fn parse_template(text: &str, values: &HashMap<String, String>) -> Result<Vec<NvsItem>> {
+    parse_with(text, |field| expand(field, values))
+}
+
+fn parse_with(text: &str, expand: impl Fn(&str) -> Result<String>) -> Result<Vec<NvsItem>> {
+    let mut items = Vec::new();
+    let mut namespace: Option<String> = None;
+
+    for (index, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+
+        let line_error = |message: String| anyhow!("Line {}: {}", index + 1, message);
+        let fields = split_fields(line)
+            .map_err(line_error)?
+            .iter()
+            .map(|field| expand(field))
+            .collect::<Result<Vec<_>>>()
+            .map_err(|e| line_error(e.to_string()))?;
+        let field = |i: usize| fields.get(i).map(String::as_str).unwrap_or_default();
+        let (key, ty, encoding, value) = (field(0), field(1), field(2), field(3));
+
+        if key == "key" && ty == "type" {
+            continue;
+        }
+
+        match ty {
+            "namespace" => {
+                namespace = Some(key.to_string());
+                continue;
+            }
+            "data" | "file" => {}
+            other => {
+                return Err(line_error(format!(
+                    "unknown type '{}' (expected namespace, data or file)",
+                    other
+                )));
+            }
+        }
+
+        let Some(namespace) = &namespace else {
+            return Err(line_error(format!(
+                "key '{}' appears before the first namespace row",
+                key
+            )));
+        };
+
+        let value = if ty == "file" {
+            let content = std::fs::read(value)
+                .map_err(|e| line_error(format!("failed to read {:?}: {}", value, e)))?;
+            parse_file_value(encoding, content)
+        } else {
+            parse_value(encoding, value)
+        }
+        .map_err(|e| line_error(format!("key '{}': {}", key, e)))?;
+
+        items.push(NvsItem {
+            namespace: namespace.clone(),
+            key: key.to_string(),
+            value,
+        });
+    }
+
+    Ok(items)
+}
+
+/// Replace every `${name}` in a field
+fn expand(field: &str, values: &HashMap<String, String>) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = field;
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("unterminated placeholder in '{}'", field))?;
+        let name = &rest[start + 2..start + end];
+        let value = values
+            .get(name)
+            .ok_or_else(|| anyhow!("no column '{}' for placeholder '${{{}}}'", name, name))?;
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(value);
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Split a line on commas, honouring double-quoted fields
+pub(crate) fn split_fields(line: &str) -> std::result::Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                current.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    if quoted {
+        return Err("unterminated quoted field".to_string());
+    }
+    fields.push(current);
+
+    // Values keep their spaces; the other columns are trimmed
+    for field in fields.iter_mut().take(3) {
+        *field = field.trim().to_string();
+    }
+    Ok(fields)
+}
+
+fn parse_value(encoding: &str, value: &str) -> Result<NvsValue> {
+    Ok(match encoding {
+        "u8" => NvsValue::U8(parse_int(value)?),
+        "i8" => NvsValue::I8(parse_int(value)?),
+        "u16" => NvsValue::U16(parse_int(value)?),
+        "i16" => NvsValue::I16(parse_int(value)?),
+        "u32" => NvsValue::U32(parse_int(value)?),
+        "i32" => NvsValue::I32(parse_int(value)?),
+        "u64" => NvsValue::U64(parse_int(value)?),
+        "i64" => NvsValue::I64(parse_int(value)?),
+        "string" => NvsValue::Str(value.to_string()),
+        "hex2bin" => NvsValue::Blob(decode_hex(value)?),
+        "base64" => NvsValue::Blob(decode_base64(value)?),
+        other => {
+            return Err(anyhow!(
+                "unknown encoding '{}' for data (expected u8, i8, u16, i16, u32, i32, u64, i64, string, hex2bin or base64)",
+                other
+            ));
+        }
+    })
+}
+
+fn parse_file_value(encoding: &str, content: Vec<u8>) -> Result<NvsValue> {
+    let text = || String::from_utf8(content.clone()).map_err(|_| anyhow!("file is not UTF-8"));
+    Ok(match encoding {
+        "string" => NvsValue::Str(text()?),
+        "hex2bin" => NvsValue::Blob(decode_hex(&text()?)?),
+        "base64" => NvsValue::Blob(decode_base64(&text()?)?),
+        "binary" => NvsValue::Blob(content),
+        other => {
+            return Err(anyhow!(
+                "unknown encoding '{}' for file (expected string, hex2bin, base64 or binary)",
+                other
+            ));
+        }
+    })
+}
+
+/// Integer in Python `int(value, 0)` notation: decimal, `0x`, `0o` or `0b`, optionally negative
+fn parse_int<T: TryFrom<i128>>(value: &str) -> Result<T> {
+    let value = value.trim();
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, value),
+    };
+    let lower = digits.to_ascii_lowercase();
+    let (radix, digits) = if let Some(hex) = lower.strip_prefix("0x") {
+        (16, hex)
+    } else if let Some(octal) = lower.strip_prefix("0o") {
+        (8, octal)
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        (2, binary)
+    } else {
+        (10, lower.as_str())
+    };
+
+    let magnitude =
+        i128::from_str_radix(digits, radix).map_err(|_| anyhow!("invalid integer '{}'", value))?;
+    let number = if negative { -magnitude } else { magnitude };
+    T::try_from(number).map_err(|_| anyhow!("{} is out of range for the encoding", value))
+}
+
+fn decode_hex(value: &str) -> Result<Vec<u8>> {
+    let digits: Vec<u8> = value.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(anyhow!("hex2bin value has an odd number of digits"));
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| anyhow!("invalid hex digits in hex2bin value"))
+        })
+        .collect()
+}
+
+fn decode_base64(value: &str) -> Result<Vec<u8>> {
+    let compact: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+    base64::engine::general_purpose::STANDARD
+        .decode(compact)
+        .map_err(|e| anyhow!("invalid base64 value: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_nvs_csv() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let cert = temp_dir.path().join("cert.der");
+        std::fs::write(&cert, [0x30, 0x82, 0x01])?;
+
+        let csv = format!(
+            "\
+key,type,encoding,value
+# provisioning data
+config,namespace,,
+serial,data,string,\"SN-001, rev B\"
+count,data,u16,0x1F
+delta,data,i32,-5
+mac,data,hex2bin,0011 2233
+token,data,base64,aGVsbG8=
+cert,file,binary,{}
+",
+            cert.display()
+        );
+
+        let items = parse(&csv)?;
+        let values: Vec<_> = items.iter().map(|i| i.value.clone()).collect();
+        assert!(items.iter().all(|i| i.namespace == "config"));
+        assert_eq!(
+            values,
+            vec![
+                NvsValue::Str("SN-001, rev B".to_string()),
+                NvsValue::U16(0x1F),
+                NvsValue::I32(-5),
+                NvsValue::Blob(vec![0x00, 0x11, 0x22, 0x33]),
+                NvsValue::Blob(b"hello".to_vec()),
+                NvsValue::Blob(vec![0x30, 0x82, 0x01]),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_nvs_template() -> Result<()> {
+        let values = HashMap::from([
+            ("serial".to_string(), "SN, 42".to_string()),
+            ("port".to_string(), "8080".to_string()),
+        ]);
+        let items = parse_template(
+            "dev,namespace,,\nserial,data,string,unit-${serial}\nport,data,u16,${port}\n",
+            &values,
+        )?;
+        assert_eq!(items[0].value, NvsValue::Str("unit-SN, 42".to_string()));
+        assert_eq!(items[1].value, NvsValue::U16(8080));
+
+        let error = parse_template("dev,namespace,,\nmac,data,string,${mac}\n", &values);
+        assert!(error.unwrap_err().to_string().contains("no column 'mac'"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_nvs_csv_errors() {
+        let error = parse("key,type,encoding,value\nvalue,data,u8,1\n").unwrap_err();
+        assert!(error.to_string().contains("Line 2"));
+
+        assert!(parse("ns,namespace,,\nbig,data,u8,256\n").is_err());
+        assert!(parse("ns,namespace,,\nneg,data,u32,-1\n").is_err());
+        assert!(parse("ns,namespace,,\nodd,data,hex2bin,abc\n").is_err());
+        assert!(parse("ns,namespace,,\nx,data,float,1.0\n").is_err());
+    }
+}
@@ -0,0 +1,350 @@
+//! Decoder for NVS partitions read from images and flash dumps
+
+use super::{
+    CHUNK_ANY, ENTRIES_OFFSET, ENTRIES_PER_PAGE, ENTRY_SIZE, ItemType, PAGE_SIZE,
+    PAGE_STATE_ACTIVE, PAGE_STATE_CORRUPT, PAGE_STATE_EMPTY, PAGE_STATE_FREEING, PAGE_STATE_FULL,
+    crc32,
+};
+use crate::util::hex;
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PageState {
+    Empty,
+    Active,
+    Full,
+    Freeing,
+    Corrupt,
+    /// State word that isn't one NVS writes
+    Invalid,
+}
+
+impl PageState {
+    fn from_raw(raw: u32) -> Self {
+        match raw {
+            PAGE_STATE_EMPTY => Self::Empty,
+            PAGE_STATE_ACTIVE => Self::Active,
+            PAGE_STATE_FULL => Self::Full,
+            PAGE_STATE_FREEING => Self::Freeing,
+            PAGE_STATE_CORRUPT => Self::Corrupt,
+            _ => Self::Invalid,
+        }
+    }
+}
+
+/// Two-bit state of an entry in the page bitmap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryState {
+    Written,
+    Erased,
+    /// `0b01`, never written by NVS
+    Illegal,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum DumpValue {
+    Unsigned(u64),
+    Signed(i64),
+    Text(String),
+    /// Hex encoded blob data
+    Bytes(String),
+    BlobIndex {
+        size: u32,
+        chunk_count: u8,
+        chunk_start: u8,
+    },
+}
+
+impl std::fmt::Display for DumpValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unsigned(v) => write!(f, "{}", v),
+            Self::Signed(v) => write!(f, "{}", v),
+            Self::Text(text) => write!(f, "\"{}\"", text.escape_debug()),
+            Self::Bytes(hex) if hex.len() > 64 => {
+                write!(f, "{}… ({} bytes)", &hex[..64], hex.len() / 2)
+            }
+            Self::Bytes(hex) => write!(f, "{}", hex),
+            Self::BlobIndex {
+                size,
+                chunk_count,
+                chunk_start,
+            } => write!(
+                f,
+                "{} bytes in chunks {}..{}",
+                size,
+                chunk_start,
+                *chunk_start as u32 + *chunk_count as u32
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DumpedEntry {
+    pub index: usize,
+    pub state: EntryState,
+    /// Namespace name, `None` for namespace definitions and unknown indices
+    pub namespace: Option<String>,
+    pub ns_index: u8,
+    pub key: String,
+    /// Item type name, `namespace` for namespace definitions
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub span: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunk_index: Option<u8>,
+    /// Header CRC and, for strings and blob chunks, the data CRC
+    pub crc_valid: bool,
+    pub value: DumpValue,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DumpedPage {
+    pub index: usize,
+    pub state: PageState,
+    pub sequence: u32,
+    pub version: u8,
+    pub header_crc_valid: bool,
+    pub entries: Vec<DumpedEntry>,
+    pub empty_entries: usize,
+}
+
+/// Decoded NVS partition, as printed by `nvs-dump --json`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NvsDump {
+    pub partition: String,
+    pub offset: u32,
+    pub size: u32,
+    pub pages: Vec<DumpedPage>,
+}
+
+/// Decode every page of an NVS partition
+pub fn decode(data: &[u8]) -> Vec<DumpedPage> {
+    let mut pages: Vec<DumpedPage> = data
+        .chunks_exact(PAGE_SIZE)
+        .enumerate()
+        .map(|(index, page)| decode_page(index, page))
+        .collect();
+
+    let namespaces: HashMap<u8, String> = pages
+        .iter()
+        .flat_map(|page| &page.entries)
+        .filter(|entry| entry.ty == "namespace" && entry.state == EntryState::Written)
+        .filter_map(|entry| match entry.value {
+            DumpValue::Unsigned(index) => Some((index as u8, entry.key.clone())),
+            _ => None,
+        })
+        .collect();
+
+    for entry in pages.iter_mut().flat_map(|page| &mut page.entries) {
+        if entry.ns_index != 0 {
+            entry.namespace = namespaces.get(&entry.ns_index).cloned();
+        }
+    }
+
+    pages
+}
+
+fn decode_page(index: usize, page: &[u8]) -> DumpedPage {
+    let word = |at: usize| u32::from_le_bytes(page[at..at + 4].try_into().unwrap());
+    let state = PageState::from_raw(word(0));
+    let mut dumped = DumpedPage {
+        index,
+        state,
+        sequence: word(4),
+        version: page[8],
+        header_crc_valid: word(28) == crc32(&page[4..28]),
+        entries: Vec::new(),
+        empty_entries: 0,
+    };
+    if state == PageState::Empty {
+        dumped.empty_entries = ENTRIES_PER_PAGE;
+        return dumped;
+    }
+
+    let mut index = 0;
+    while index < ENTRIES_PER_PAGE {
+        let bits = (page[32 + index / 4] >> ((index % 4) * 2)) & 0b11;
+        let state = match bits {
+            0b11 => {
+                dumped.empty_entries += 1;
+                index += 1;
+                continue;
+            }
+            0b10 => EntryState::Written,
+            0b00 => EntryState::Erased,
+            _ => EntryState::Illegal,
+        };
+
+        let entry = decode_entry(page, index, state);
+        index += entry.span.max(1) as usize;
+        dumped.entries.push(entry);
+    }
+
+    dumped
+}
+
+fn decode_entry(page: &[u8], index: usize, state: EntryState) -> DumpedEntry {
+    let start = ENTRIES_OFFSET + index * ENTRY_SIZE;
+    let entry = &page[start..start + ENTRY_SIZE];
+    let field = &entry[24..32];
+
+    let key_len = entry[8..24].iter().position(|&b| b == 0).unwrap_or(16);
+    let key = String::from_utf8_lossy(&entry[8..8 + key_len]).into_owned();
+    let header_crc = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+    let mut crc_valid = header_crc == crc32(&[&entry[0..4], &entry[8..32]].concat());
+
+    let ty = ItemType::from_raw(entry[1]);
+    // Spans past the end of the page are corrupt; step over the header only
+    let span = if entry[2] >= 1 && index + entry[2] as usize <= ENTRIES_PER_PAGE {
+        entry[2]
+    } else {
+        crc_valid = false;
+        1
+    };
+
+    let le = |len: usize| {
+        let mut bytes = [0u8; 8];
+        bytes[..len].copy_from_slice(&field[..len]);
+        u64::from_le_bytes(bytes)
+    };
+    let value = match ty {
+        Some(ItemType::U8) => DumpValue::Unsigned(le(1)),
+        Some(ItemType::U16) => DumpValue::Unsigned(le(2)),
+        Some(ItemType::U32) => DumpValue::Unsigned(le(4)),
+        Some(ItemType::U64) => DumpValue::Unsigned(le(8)),
+        Some(ItemType::I8) => DumpValue::Signed(le(1) as u8 as i8 as i64),
+        Some(ItemType::I16) => DumpValue::Signed(le(2) as u16 as i16 as i64),
+        Some(ItemType::I32) => DumpValue::Signed(le(4) as u32 as i32 as i64),
+        Some(ItemType::I64) => DumpValue::Signed(le(8) as i64),
+        Some(ItemType::Str | ItemType::Blob | ItemType::BlobData) => {
+            let size = u16::from_le_bytes([field[0], field[1]]) as usize;
+            let data_start = start + ENTRY_SIZE;
+            let available = (span as usize - 1) * ENTRY_SIZE;
+            let data = &page[data_start..data_start + size.min(available)];
+            let data_crc = u32::from_le_bytes(field[4..8].try_into().unwrap());
+            crc_valid &= size <= available && data_crc == crc32(data);
+
+            if ty == Some(ItemType::Str) {
+                let text = data.strip_suffix(&[0]).unwrap_or(data);
+                DumpValue::Text(String::from_utf8_lossy(text).into_owned())
+            } else {
+                DumpValue::Bytes(hex(data))
+            }
+        }
+        Some(ItemType::BlobIndex) => DumpValue::BlobIndex {
+            size: le(4) as u32,
+            chunk_count: field[4],
+            chunk_start: field[5],
+        },
+        None => DumpValue::Bytes(hex(field)),
+    };
+
+    let ty = match ty {
+        Some(ItemType::U8) if entry[0] == 0 => "namespace".to_string(),
+        Some(ty) => ty.to_string(),
+        None => format!("0x{:02X}", entry[1]),
+    };
+
+    DumpedEntry {
+        index,
+        state,
+        namespace: None,
+        ns_index: entry[0],
+        key,
+        ty,
+        span,
+        chunk_index: (entry[3] != CHUNK_ANY).then_some(entry[3]),
+        crc_valid,
+        value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nvs::{NvsItem, NvsValue, build_partition};
+
+    #[test]
+    fn test_decode_generated_partition() -> crate::Result<()> {
+        let item = |namespace: &str, key: &str, value| NvsItem {
+            namespace: namespace.to_string(),
+            key: key.to_string(),
+            value,
+        };
+        let image = build_partition(
+            &[
+                item("config", "offset", NvsValue::I16(-300)),
+                item("config", "name", NvsValue::Str("unit 7".to_string())),
+                item("certs", "ca", NvsValue::Blob(vec![0xAB; 5000])),
+            ],
+            0x4000,
+        )?;
+
+        let pages = decode(&image);
+        assert_eq!(pages.len(), 4);
+        assert_eq!(pages[0].state, PageState::Full);
+        assert_eq!(pages[1].state, PageState::Active);
+        assert_eq!(pages[2].state, PageState::Empty);
+        assert!(pages[0].header_crc_valid && pages[1].header_crc_valid);
+
+        let entries: Vec<_> = pages.iter().flat_map(|p| &p.entries).collect();
+        assert!(entries.iter().all(|e| e.crc_valid));
+        let find = |key: &str, ty: &str| {
+            entries
+                .iter()
+                .find(|e| e.key == key && e.ty == ty)
+                .unwrap_or_else(|| panic!("{} {}", key, ty))
+        };
+
+        assert_eq!(find("config", "namespace").value, DumpValue::Unsigned(1));
+        let offset = find("offset", "i16");
+        assert_eq!(offset.namespace.as_deref(), Some("config"));
+        assert_eq!(offset.value, DumpValue::Signed(-300));
+        assert_eq!(
+            find("name", "string").value,
+            DumpValue::Text("unit 7".to_string())
+        );
+        assert_eq!(find("ca", "blob_data").chunk_index, Some(0));
+        assert_eq!(
+            find("ca", "blob_index").value,
+            DumpValue::BlobIndex {
+                size: 5000,
+                chunk_count: 2,
+                chunk_start: 0
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_erased_and_corrupt_entries() -> crate::Result<()> {
+        let mut image = build_partition(
+            &[NvsItem {
+                namespace: "ns".to_string(),
+                key: "count".to_string(),
+                value: NvsValue::U32(5),
+            }],
+            0x3000,
+        )?;
+
+        // Erase the value entry in the bitmap and flip a bit of its data
+        image[32] &= !0b1100;
+        image[ENTRIES_OFFSET + ENTRY_SIZE + 24] ^= 0x01;
+
+        let pages = decode(&image);
+        let entry = &pages[0].entries[1];
+        assert_eq!(entry.state, EntryState::Erased);
+        assert!(!entry.crc_valid);
+        assert_eq!(entry.value, DumpValue::Unsigned(4));
+        assert_eq!(pages[0].empty_entries, ENTRIES_PER_PAGE - 2);
+
+        Ok(())
+    }
+}
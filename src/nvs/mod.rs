@@ -0,0 +1,516 @@
+//! ESP-IDF NVS partition images (format version 2, multi-page blobs)
+
+pub mod crypt;
+pub mod csv;
+pub mod dump;
+
+use crate::Result;
+use anyhow::anyhow;
+use std::path::Path;
+
+pub const PAGE_SIZE: usize = 0x1000;
+pub const ENTRY_SIZE: usize = 32;
+pub const ENTRIES_PER_PAGE: usize = 126;
+/// Page header plus entry state bitmap
+const ENTRIES_OFFSET: usize = 64;
+const MAX_KEY_LEN: usize = 15;
+/// Longest string, including its NUL terminator
+pub const MAX_STRING_SIZE: usize = 4000;
+pub const MAX_BLOB_SIZE: usize = 508_000;
+const MAX_NAMESPACES: usize = 254;
+
+const PAGE_STATE_EMPTY: u32 = 0xFFFF_FFFF;
+const PAGE_STATE_ACTIVE: u32 = 0xFFFF_FFFE;
+const PAGE_STATE_FULL: u32 = 0xFFFF_FFFC;
+const PAGE_STATE_FREEING: u32 = 0xFFFF_FFF8;
+const PAGE_STATE_CORRUPT: u32 = 0xFFFF_FFF0;
+const PAGE_VERSION_2: u8 = 0xFE;
+const CHUNK_ANY: u8 = 0xFF;
+/// Entry state `WRITTEN` (0b10); erased flash reads as `EMPTY` (0b11)
+const ENTRY_STATE_WRITTEN_MASK: u8 = 0b01;
+
+/// `nvs::ItemType`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ItemType {
+    U8 = 0x01,
+    I8 = 0x11,
+    U16 = 0x02,
+    I16 = 0x12,
+    U32 = 0x04,
+    I32 = 0x14,
+    U64 = 0x08,
+    I64 = 0x18,
+    Str = 0x21,
+    /// Single-page blob of format version 1
+    Blob = 0x41,
+    BlobData = 0x42,
+    BlobIndex = 0x48,
+}
+
+impl ItemType {
+    pub fn from_raw(raw: u8) -> Option<Self> {
+        [
+            Self::U8,
+            Self::I8,
+            Self::U16,
+            Self::I16,
+            Self::U32,
+            Self::I32,
+            Self::U64,
+            Self::I64,
+            Self::Str,
+            Self::Blob,
+            Self::BlobData,
+            Self::BlobIndex,
+        ]
+        .into_iter()
+        .find(|ty| *ty as u8 == raw)
+    }
+}
+
+impl std::fmt::Display for ItemType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::U8 => "u8",
+            Self::I8 => "i8",
+            Self::U16 => "u16",
+            Self::I16 => "i16",
+            Self::U32 => "u32",
+            Self::I32 => "i32",
+            Self::U64 => "u64",
+            Self::I64 => "i64",
+            Self::Str => "string",
+            Self::Blob => "blob",
+            Self::BlobData => "blob_data",
+            Self::BlobIndex => "blob_index",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Value of one key, as written by `nvs_set_*`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NvsValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    Str(String),
+    Blob(Vec<u8>),
+}
+
+impl NvsValue {
+    /// Primitive type and its little-endian bytes, `None` for strings and blobs
+    fn primitive(&self) -> Option<(ItemType, Vec<u8>)> {
+        let (ty, bytes) = match self {
+            Self::U8(v) => (ItemType::U8, v.to_le_bytes().to_vec()),
+            Self::I8(v) => (ItemType::I8, v.to_le_bytes().to_vec()),
+            Self::U16(v) => (ItemType::U16, v.to_le_bytes().to_vec()),
+            Self::I16(v) => (ItemType::I16, v.to_le_bytes().to_vec()),
+            Self::U32(v) => (ItemType::U32, v.to_le_bytes().to_vec()),
+            Self::I32(v) => (ItemType::I32, v.to_le_bytes().to_vec()),
+            Self::U64(v) => (ItemType::U64, v.to_le_bytes().to_vec()),
+            Self::I64(v) => (ItemType::I64, v.to_le_bytes().to_vec()),
+            Self::Str(_) | Self::Blob(_) => return None,
+        };
+        Some((ty, bytes))
+    }
+}
+
+/// One key of the CSV, with the namespace it was declared in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NvsItem {
+    pub namespace: String,
+    pub key: String,
+    pub value: NvsValue,
+}
+
+/// Read the items of a `key,type,encoding,value` CSV file
+pub fn load_csv<P: AsRef<Path>>(path: P) -> Result<Vec<NvsItem>> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read NVS CSV {:?}: {}", path, e))?;
+    csv::parse(&text).map_err(|e| anyhow!("NVS CSV {:?}: {}", path, e))
+}
+
+/// Build an NVS partition image from a `key,type,encoding,value` CSV file
+pub fn build_from_csv<P: AsRef<Path>>(path: P, partition_size: u32) -> Result<Vec<u8>> {
+    build_partition(&load_csv(path)?, partition_size)
+}
+
+/// Build an NVS partition image of `partition_size` bytes
+///
+/// Pages are filled in order; the last one holding data stays `ACTIVE` and
+/// the others are `FULL`. At least one page is left erased, since NVS needs a
+/// free page for garbage collection.
+pub fn build_partition(items: &[NvsItem], partition_size: u32) -> Result<Vec<u8>> {
+    let partition_size = partition_size as usize;
+    let page_count = partition_size / PAGE_SIZE;
+    if page_count < 3 {
+        return Err(anyhow!(
+            "NVS partition of {} bytes is too small; ESP-IDF needs at least 3 pages (0x3000 bytes)",
+            partition_size
+        ));
+    }
+
+    let mut writer = PageWriter::default();
+    let mut namespaces: Vec<&str> = Vec::new();
+    let mut keys: Vec<(&str, &str)> = Vec::new();
+
+    for item in items {
+        check_key(&item.namespace)?;
+        check_key(&item.key)?;
+        if keys.contains(&(&item.namespace, &item.key)) {
+            return Err(anyhow!(
+                "Duplicate key '{}' in namespace '{}'",
+                item.key,
+                item.namespace
+            ));
+        }
+        keys.push((&item.namespace, &item.key));
+
+        let ns_index = match namespaces.iter().position(|ns| *ns == item.namespace) {
+            Some(index) => index + 1,
+            None => {
+                if namespaces.len() == MAX_NAMESPACES {
+                    return Err(anyhow!("Too many namespaces (at most {})", MAX_NAMESPACES));
+                }
+                namespaces.push(&item.namespace);
+                let index = namespaces.len();
+                writer.write_item(0, &item.namespace, &NvsValue::U8(index as u8))?;
+                index
+            }
+        } as u8;
+
+        writer.write_item(ns_index, &item.key, &item.value)?;
+    }
+
+    let pages = writer.finish();
+    if pages.len() > page_count - 1 {
+        return Err(anyhow!(
+            "NVS data needs {} pages but the partition ({} bytes) holds {} plus one free page for garbage collection",
+            pages.len(),
+            partition_size,
+            page_count - 1
+        ));
+    }
+
+    let mut image = pages.concat();
+    image.resize(partition_size, 0xFF);
+    Ok(image)
+}
+
+fn check_key(key: &str) -> Result<()> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        return Err(anyhow!(
+            "NVS key '{}' must be 1 to {} bytes long",
+            key,
+            MAX_KEY_LEN
+        ));
+    }
+    Ok(())
+}
+
+/// CRC used throughout NVS: `esp_rom_crc32_le(UINT32_MAX, ...)`
+fn crc32(data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new_with_initial(0xFFFF_FFFF);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Appends entries to pages, starting a new page when an item doesn't fit
+#[derive(Default)]
+struct PageWriter {
+    pages: Vec<Vec<u8>>,
+    /// Entries used on the last page
+    used: usize,
+}
+
+impl PageWriter {
+    fn free_entries(&self) -> usize {
+        if self.pages.is_empty() {
+            0
+        } else {
+            ENTRIES_PER_PAGE - self.used
+        }
+    }
+
+    /// Make sure the current page has `entries` free entries
+    fn reserve(&mut self, entries: usize) {
+        if self.free_entries() < entries {
+            self.pages.push(vec![0xFF; PAGE_SIZE]);
+            self.used = 0;
+        }
+    }
+
+    fn write_item(&mut self, ns_index: u8, key: &str, value: &NvsValue) -> Result<()> {
+        match value {
+            NvsValue::Str(text) => {
+                let mut data = text.as_bytes().to_vec();
+                data.push(0);
+                if data.len() > MAX_STRING_SIZE {
+                    return Err(anyhow!(
+                        "String '{}' is {} bytes, at most {} including the terminator",
+                        key,
+                        data.len(),
+                        MAX_STRING_SIZE
+                    ));
+                }
+                self.write_varlen(ns_index, ItemType::Str, key, CHUNK_ANY, &data);
+            }
+            NvsValue::Blob(data) => {
+                if data.len() > MAX_BLOB_SIZE {
+                    return Err(anyhow!(
+                        "Blob '{}' is {} bytes, at most {}",
+                        key,
+                        data.len(),
+                        MAX_BLOB_SIZE
+                    ));
+                }
+                self.write_blob(ns_index, key, data);
+            }
+            primitive => {
+                let (ty, bytes) = primitive.primitive().expect("primitive value");
+                let mut field = [0xFF; 8];
+                field[..bytes.len()].copy_from_slice(&bytes);
+                self.reserve(1);
+                self.write_header(ns_index, ty, 1, CHUNK_ANY, key, field);
+            }
+        }
+        Ok(())
+    }
+
+    /// String or blob chunk: header entry with size and CRC, data in the following entries
+    fn write_varlen(&mut self, ns_index: u8, ty: ItemType, key: &str, chunk: u8, data: &[u8]) {
+        let data_entries = data.len().div_ceil(ENTRY_SIZE);
+        self.reserve(1 + data_entries);
+
+        let mut field = [0xFF; 8];
+        field[0..2].copy_from_slice(&(data.len() as u16).to_le_bytes());
+        field[4..8].copy_from_slice(&crc32(data).to_le_bytes());
+        self.write_header(ns_index, ty, 1 + data_entries as u8, chunk, key, field);
+
+        let page = self.pages.last_mut().expect("page reserved");
+        let start = ENTRIES_OFFSET + self.used * ENTRY_SIZE;
+        page[start..start + data.len()].copy_from_slice(data);
+        for _ in 0..data_entries {
+            Self::mark_written(page, self.used);
+            self.used += 1;
+        }
+    }
+
+    /// Blob split into per-page `BLOB_DATA` chunks followed by a `BLOB_IDX` entry
+    fn write_blob(&mut self, ns_index: u8, key: &str, data: &[u8]) {
+        let mut chunk_count = 0u8;
+        let mut remaining = data;
+        loop {
+            if self.free_entries() < 2 {
+                self.reserve(ENTRIES_PER_PAGE);
+            }
+            let room = (self.free_entries() - 1) * ENTRY_SIZE;
+            let (chunk, rest) = remaining.split_at(remaining.len().min(room));
+            self.write_varlen(ns_index, ItemType::BlobData, key, chunk_count, chunk);
+            chunk_count += 1;
+            remaining = rest;
+            if remaining.is_empty() {
+                break;
+            }
+        }
+
+        let mut field = [0xFF; 8];
+        field[0..4].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        field[4] = chunk_count;
+        // chunk_start: version 0 of the blob
+        field[5] = 0;
+        self.reserve(1);
+        self.write_header(ns_index, ItemType::BlobIndex, 1, CHUNK_ANY, key, field);
+    }
+
+    fn write_header(
+        &mut self,
+        ns_index: u8,
+        ty: ItemType,
+        span: u8,
+        chunk: u8,
+        key: &str,
+        field: [u8; 8],
+    ) {
+        let mut entry = [0xFF; ENTRY_SIZE];
+        entry[0] = ns_index;
+        entry[1] = ty as u8;
+        entry[2] = span;
+        entry[3] = chunk;
+        entry[8..24].fill(0);
+        entry[8..8 + key.len()].copy_from_slice(key.as_bytes());
+        entry[24..32].copy_from_slice(&field);
+        let crc = crc32(&[&entry[0..4], &entry[8..32]].concat());
+        entry[4..8].copy_from_slice(&crc.to_le_bytes());
+
+        let page = self.pages.last_mut().expect("page reserved");
+        let start = ENTRIES_OFFSET + self.used * ENTRY_SIZE;
+        page[start..start + ENTRY_SIZE].copy_from_slice(&entry);
+        Self::mark_written(page, self.used);
+        self.used += 1;
+    }
+
+    fn mark_written(page: &mut [u8], entry: usize) {
+        let bit = entry * 2;
+        page[32 + bit / 8] &= !(ENTRY_STATE_WRITTEN_MASK << (bit % 8));
+    }
+
+    /// Write page headers: sequence numbers from 0, the last page `ACTIVE`
+    fn finish(mut self) -> Vec<Vec<u8>> {
+        let last = self.pages.len().saturating_sub(1);
+        for (index, page) in self.pages.iter_mut().enumerate() {
+            let state = if index == last {
+                PAGE_STATE_ACTIVE
+            } else {
+                PAGE_STATE_FULL
+            };
+            page[0..4].copy_from_slice(&state.to_le_bytes());
+            page[4..8].copy_from_slice(&(index as u32).to_le_bytes());
+            page[8] = PAGE_VERSION_2;
+            let crc = crc32(&page[4..28]);
+            page[28..32].copy_from_slice(&crc.to_le_bytes());
+        }
+        self.pages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(namespace: &str, key: &str, value: NvsValue) -> NvsItem {
+        NvsItem {
+            namespace: namespace.to_string(),
+            key: key.to_string(),
+            value,
+        }
+    }
+
+    fn entry(image: &[u8], page: usize, index: usize) -> &[u8] {
+        let start = page * PAGE_SIZE + ENTRIES_OFFSET + index * ENTRY_SIZE;
+        &image[start..start + ENTRY_SIZE]
+    }
+
+    fn entry_written(image: &[u8], page: usize, index: usize) -> bool {
+        let bits = image[page * PAGE_SIZE + 32 + index / 4] >> ((index % 4) * 2) & 0b11;
+        bits == 0b10
+    }
+
+    #[test]
+    fn test_primitive_and_string_entries() -> Result<()> {
+        let image = build_partition(
+            &[
+                item("config", "count", NvsValue::U32(0x1234_5678)),
+                item("config", "offset", NvsValue::I8(-2)),
+                item("config", "name", NvsValue::Str("device-01".to_string())),
+            ],
+            0x3000,
+        )?;
+        assert_eq!(image.len(), 0x3000);
+
+        // Page header: ACTIVE, sequence 0, version 2, CRC over bytes 4..28
+        assert_eq!(&image[0..4], &PAGE_STATE_ACTIVE.to_le_bytes());
+        assert_eq!(&image[4..8], &0u32.to_le_bytes());
+        assert_eq!(image[8], PAGE_VERSION_2);
+        assert_eq!(&image[28..32], &crc32(&image[4..28]).to_le_bytes());
+
+        // Namespace entry maps "config" to index 1
+        let ns = entry(&image, 0, 0);
+        assert_eq!(&ns[0..4], &[0, ItemType::U8 as u8, 1, CHUNK_ANY]);
+        assert_eq!(&ns[8..15], b"config\0");
+        assert_eq!(ns[24], 1);
+        assert_eq!(
+            &ns[4..8],
+            &crc32(&[&ns[0..4], &ns[8..32]].concat()).to_le_bytes()
+        );
+
+        let count = entry(&image, 0, 1);
+        assert_eq!(&count[0..3], &[1, ItemType::U32 as u8, 1]);
+        assert_eq!(
+            &count[24..32],
+            &[0x78, 0x56, 0x34, 0x12, 0xFF, 0xFF, 0xFF, 0xFF]
+        );
+        assert_eq!(entry(&image, 0, 2)[24], 0xFE);
+
+        // String: header with size (including NUL) and data CRC, then one data entry
+        let name = entry(&image, 0, 3);
+        assert_eq!(&name[0..3], &[1, ItemType::Str as u8, 2]);
+        assert_eq!(&name[24..26], &10u16.to_le_bytes());
+        assert_eq!(&name[28..32], &crc32(b"device-01\0").to_le_bytes());
+        assert_eq!(&entry(&image, 0, 4)[..10], b"device-01\0");
+
+        assert!((0..5).all(|i| entry_written(&image, 0, i)));
+        assert!(!entry_written(&image, 0, 5));
+        assert!(image[PAGE_SIZE..].iter().all(|&b| b == 0xFF));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_blob_spans_pages() -> Result<()> {
+        let blob: Vec<u8> = (0..6000u32).map(|i| i as u8).collect();
+        let image = build_partition(&[item("certs", "ca", NvsValue::Blob(blob.clone()))], 0x4000)?;
+
+        assert_eq!(&image[0..4], &PAGE_STATE_FULL.to_le_bytes());
+        assert_eq!(
+            &image[PAGE_SIZE..PAGE_SIZE + 4],
+            &PAGE_STATE_ACTIVE.to_le_bytes()
+        );
+        assert_eq!(&image[PAGE_SIZE + 4..PAGE_SIZE + 8], &1u32.to_le_bytes());
+
+        // First chunk fills page 0 after the namespace entry: 124 data entries
+        let first = entry(&image, 0, 1);
+        assert_eq!(&first[1..4], &[ItemType::BlobData as u8, 125, 0]);
+        assert_eq!(&first[24..26], &(124 * 32u16).to_le_bytes());
+        let second = entry(&image, 1, 0);
+        assert_eq!(second[3], 1);
+        assert_eq!(&second[24..26], &(6000 - 124 * 32u16).to_le_bytes());
+
+        let chunk_entries = (6000 - 124 * 32usize).div_ceil(32);
+        let index = entry(&image, 1, 1 + chunk_entries);
+        assert_eq!(&index[1..4], &[ItemType::BlobIndex as u8, 1, CHUNK_ANY]);
+        assert_eq!(&index[24..28], &6000u32.to_le_bytes());
+        assert_eq!(&index[28..30], &[2, 0]);
+
+        let start = ENTRIES_OFFSET + 2 * ENTRY_SIZE;
+        assert_eq!(&image[start..start + 124 * 32], &blob[..124 * 32]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_partition_limits() {
+        assert!(build_partition(&[], 0x2000).is_err());
+        assert!(
+            build_partition(
+                &[item("ns", "a_key_that_is_too_long", NvsValue::U8(1))],
+                0x3000
+            )
+            .is_err()
+        );
+        assert!(
+            build_partition(
+                &[
+                    item("ns", "key", NvsValue::U8(1)),
+                    item("ns", "key", NvsValue::U8(2))
+                ],
+                0x3000
+            )
+            .is_err()
+        );
+
+        // Two pages of blob data leave no free page in a 3-page partition
+        let blob = NvsValue::Blob(vec![0; 6000]);
+        assert!(build_partition(&[item("ns", "blob", blob.clone())], 0x3000).is_ok());
+        let big = NvsValue::Blob(vec![0; 9000]);
+        assert!(build_partition(&[item("ns", "blob", big)], 0x3000).is_err());
+    }
+}
@@ -0,0 +1,430 @@
+use crate::Result;
+use anyhow::anyhow;
+use esp_idf_part::{AppType, DataType, Partition, PartitionTable, SubType};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Size of one otadata copy; the partition holds two, one per flash sector
+pub const OTADATA_SECTOR_SIZE: usize = 0x1000;
+/// Size of `esp_ota_select_entry_t`
+pub const OTA_SELECT_ENTRY_SIZE: usize = 32;
+
+/// `esp_ota_img_states_t` used by app rollback
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OtaImageState {
+    New,
+    PendingVerify,
+    Valid,
+    Invalid,
+    Aborted,
+    /// Erased state field, as written by `otatool.py`
+    #[default]
+    Undefined,
+}
+
+impl OtaImageState {
+    pub fn to_raw(self) -> u32 {
+        match self {
+            Self::New => 0x0,
+            Self::PendingVerify => 0x1,
+            Self::Valid => 0x2,
+            Self::Invalid => 0x3,
+            Self::Aborted => 0x4,
+            Self::Undefined => 0xFFFF_FFFF,
+        }
+    }
+
+    pub fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            0x0 => Some(Self::New),
+            0x1 => Some(Self::PendingVerify),
+            0x2 => Some(Self::Valid),
+            0x3 => Some(Self::Invalid),
+            0x4 => Some(Self::Aborted),
+            0xFFFF_FFFF => Some(Self::Undefined),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for OtaImageState {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        match value.to_ascii_lowercase().replace('-', "_").as_str() {
+            "new" => Ok(Self::New),
+            "pending_verify" => Ok(Self::PendingVerify),
+            "valid" => Ok(Self::Valid),
+            "invalid" => Ok(Self::Invalid),
+            "aborted" => Ok(Self::Aborted),
+            "undefined" => Ok(Self::Undefined),
+            _ => Err(anyhow!(
+                "Unknown OTA image state '{}' (expected new, pending_verify, valid, invalid, aborted or undefined)",
+                value
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for OtaImageState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::New => "new",
+            Self::PendingVerify => "pending_verify",
+            Self::Valid => "valid",
+            Self::Invalid => "invalid",
+            Self::Aborted => "aborted",
+            Self::Undefined => "undefined",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// App slot the 2nd-stage bootloader should start: `factory` or `ota_N`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BootSlot {
+    Factory,
+    Ota(usize),
+}
+
+impl FromStr for BootSlot {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        if value.eq_ignore_ascii_case("factory") {
+            return Ok(Self::Factory);
+        }
+
+        value
+            .strip_prefix("ota_")
+            .and_then(|index| index.parse::<usize>().ok())
+            .map(Self::Ota)
+            .ok_or_else(|| anyhow!("Invalid boot slot '{}' (expected factory or ota_N)", value))
+    }
+}
+
+impl std::fmt::Display for BootSlot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Factory => write!(f, "factory"),
+            Self::Ota(index) => write!(f, "ota_{}", index),
+        }
+    }
+}
+
+/// `esp_ota_select_entry_t`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OtaSelectEntry {
+    pub ota_seq: u32,
+    pub seq_label: [u8; 20],
+    pub ota_state: u32,
+    pub crc: u32,
+}
+
+impl OtaSelectEntry {
+    pub fn new(ota_seq: u32, state: OtaImageState) -> Self {
+        Self {
+            ota_seq,
+            seq_label: [0xFF; 20],
+            ota_state: state.to_raw(),
+            crc: seq_crc(ota_seq),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8; OTA_SELECT_ENTRY_SIZE]) -> Self {
+        let word = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let mut seq_label = [0u8; 20];
+        seq_label.copy_from_slice(&bytes[4..24]);
+        Self {
+            ota_seq: word(0),
+            seq_label,
+            ota_state: word(24),
+            crc: word(28),
+        }
+    }
+
+    pub fn state(&self) -> Option<OtaImageState> {
+        OtaImageState::from_raw(self.ota_state)
+    }
+
+    pub fn is_erased(&self) -> bool {
+        self.ota_seq == u32::MAX
+    }
+
+    pub fn crc_valid(&self) -> bool {
+        self.crc == seq_crc(self.ota_seq)
+    }
+
+    /// Whether the bootloader considers this copy (`bootloader_common_ota_select_valid`)
+    pub fn is_valid(&self) -> bool {
+        !self.is_erased()
+            && self.crc_valid()
+            && !matches!(
+                self.state(),
+                Some(OtaImageState::Invalid | OtaImageState::Aborted)
+            )
+    }
+
+    pub fn to_bytes(&self) -> [u8; OTA_SELECT_ENTRY_SIZE] {
+        let mut bytes = [0u8; OTA_SELECT_ENTRY_SIZE];
+        bytes[0..4].copy_from_slice(&self.ota_seq.to_le_bytes());
+        bytes[4..24].copy_from_slice(&self.seq_label);
+        bytes[24..28].copy_from_slice(&self.ota_state.to_le_bytes());
+        bytes[28..32].copy_from_slice(&self.crc.to_le_bytes());
+        bytes
+    }
+}
+
+/// CRC the bootloader stores for an entry: CRC32 of `ota_seq` only,
+/// `esp_rom_crc32_le(UINT32_MAX, &ota_seq, 4)`
+pub fn seq_crc(ota_seq: u32) -> u32 {
+    let mut hasher = crc32fast::Hasher::new_with_initial(0xFFFF_FFFF);
+    hasher.update(&ota_seq.to_le_bytes());
+    hasher.finalize()
+}
+
+/// Count of OTA app slots in a table, which the bootloader uses to map sequence numbers to slots
+pub fn ota_app_count(table: &PartitionTable) -> usize {
+    table
+        .partitions()
+        .iter()
+        .filter(|p| {
+            matches!(p.subtype(), SubType::App(app) if app != AppType::Factory && app != AppType::Test)
+        })
+        .count()
+}
+
+/// The otadata partition of a table, if the layout has one
+pub fn find_otadata(table: &PartitionTable) -> Option<&Partition> {
+    table
+        .partitions()
+        .iter()
+        .find(|p| p.subtype() == SubType::Data(DataType::Ota))
+}
+
+/// otadata content that makes the bootloader start `slot`
+///
+/// `factory` leaves both copies erased; `ota_N` writes sequence N + 1 into the
+/// first copy, since the bootloader boots slot `(seq - 1) % ota_app_count`.
+pub fn build_otadata(
+    table: &PartitionTable,
+    slot: BootSlot,
+    state: OtaImageState,
+) -> Result<Vec<u8>> {
+    let otadata = find_otadata(table)
+        .ok_or_else(|| anyhow!("--boot-slot needs an otadata partition in the layout"))?;
+    if (otadata.size() as usize) < 2 * OTADATA_SECTOR_SIZE {
+        return Err(anyhow!(
+            "otadata partition '{}' ({} bytes) is too small for two copies",
+            otadata.name(),
+            otadata.size()
+        ));
+    }
+
+    let mut data = vec![0xFF; otadata.size() as usize];
+    if slot == BootSlot::Factory && table.find("factory").is_none() {
+        return Err(anyhow!("Boot slot 'factory' is not in the partition table"));
+    }
+    if let BootSlot::Ota(index) = slot {
+        let name = slot.to_string();
+        if table.find(&name).is_none() {
+            return Err(anyhow!(
+                "Boot slot '{}' is not in the partition table ({} OTA slots)",
+                name,
+                ota_app_count(table)
+            ));
+        }
+
+        let entry = OtaSelectEntry::new(index as u32 + 1, state);
+        data[..OTA_SELECT_ENTRY_SIZE].copy_from_slice(&entry.to_bytes());
+    }
+
+    Ok(data)
+}
+
+/// Both otadata copies and the app slot the bootloader picks from them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OtadataReport {
+    pub entries: [OtaSelectEntry; 2],
+    /// Copy with the highest valid sequence, or `None` when both are unusable
+    pub active: Option<usize>,
+    /// Partition the bootloader starts, `None` if the table has no app
+    pub boot_partition: Option<String>,
+}
+
+/// Decode otadata the way the 2nd-stage bootloader does
+///
+/// The valid copy with the highest sequence selects slot `(seq - 1) % ota_app_count`.
+/// Without a valid copy it boots factory, then test, then `ota_0`.
+pub fn decode_otadata(data: &[u8], table: &PartitionTable) -> Result<OtadataReport> {
+    if data.len() < 2 * OTADATA_SECTOR_SIZE {
+        return Err(anyhow!(
+            "otadata is {} bytes, expected two {} byte sectors",
+            data.len(),
+            OTADATA_SECTOR_SIZE
+        ));
+    }
+
+    let entry = |sector: usize| {
+        let start = sector * OTADATA_SECTOR_SIZE;
+        OtaSelectEntry::from_bytes(
+            data[start..start + OTA_SELECT_ENTRY_SIZE]
+                .try_into()
+                .unwrap(),
+        )
+    };
+    let entries = [entry(0), entry(1)];
+
+    let ota_count = ota_app_count(table);
+    let active = if ota_count == 0 {
+        None
+    } else {
+        (0..2)
+            .filter(|&i| entries[i].is_valid())
+            .max_by_key(|&i| entries[i].ota_seq)
+    };
+
+    // bootloader_utility_load_partition_table keeps the last entry of each subtype
+    let find_app = |app: AppType| {
+        table
+            .partitions()
+            .iter()
+            .rev()
+            .find(|p| p.subtype() == SubType::App(app))
+            .map(|p| p.name())
+    };
+    let boot_partition = match active {
+        Some(i) => {
+            let slot = (entries[i].ota_seq as usize - 1) % ota_count;
+            AppType::from_repr(AppType::Ota_0 as usize + slot).and_then(find_app)
+        }
+        None => find_app(AppType::Factory)
+            .or_else(|| find_app(AppType::Test))
+            .or_else(|| find_app(AppType::Ota_0)),
+    };
+
+    Ok(OtadataReport {
+        entries,
+        active,
+        boot_partition,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use esp_idf_part::{Flags, Type};
+
+    fn table(ota_slots: usize) -> PartitionTable {
+        let mut partitions = vec![
+            Partition::new(
+                "otadata",
+                Type::Data,
+                SubType::Data(DataType::Ota),
+                0x12000,
+                0x2000,
+                Flags::empty(),
+            ),
+            Partition::new(
+                "factory",
+                Type::App,
+                SubType::App(AppType::Factory),
+                0x20000,
+                0x100000,
+                Flags::empty(),
+            ),
+        ];
+        for i in 0..ota_slots {
+            partitions.push(Partition::new(
+                format!("ota_{}", i),
+                Type::App,
+                SubType::App(AppType::from_repr(AppType::Ota_0 as usize + i).unwrap()),
+                0x120000 + i as u32 * 0x100000,
+                0x100000,
+                Flags::empty(),
+            ));
+        }
+        PartitionTable::new(partitions)
+    }
+
+    #[test]
+    fn test_seq_crc_matches_otatool() {
+        // binascii.crc32(struct.pack('<I', seq), 0xFFFFFFFF)
+        assert_eq!(seq_crc(1), 0x4743_989A);
+        assert_eq!(seq_crc(2), 0x55F6_3774);
+        assert_eq!(seq_crc(5), 0xC821_0FCD);
+    }
+
+    #[test]
+    fn test_parse_slot_and_state() {
+        assert_eq!("factory".parse::<BootSlot>().unwrap(), BootSlot::Factory);
+        assert_eq!("ota_3".parse::<BootSlot>().unwrap(), BootSlot::Ota(3));
+        assert!("ota_x".parse::<BootSlot>().is_err());
+
+        assert_eq!(
+            "pending-verify".parse::<OtaImageState>().unwrap(),
+            OtaImageState::PendingVerify
+        );
+        for state in [
+            OtaImageState::New,
+            OtaImageState::Valid,
+            OtaImageState::Aborted,
+            OtaImageState::Undefined,
+        ] {
+            assert_eq!(OtaImageState::from_raw(state.to_raw()), Some(state));
+        }
+    }
+
+    #[test]
+    fn test_build_otadata() -> Result<()> {
+        let table = table(2);
+
+        let data = build_otadata(&table, BootSlot::Ota(1), OtaImageState::PendingVerify)?;
+        assert_eq!(data.len(), 0x2000);
+        assert_eq!(&data[0..4], &2u32.to_le_bytes());
+        assert!(data[4..24].iter().all(|&b| b == 0xFF));
+        assert_eq!(&data[24..28], &1u32.to_le_bytes());
+        assert_eq!(&data[28..32], &seq_crc(2).to_le_bytes());
+        assert!(data[32..].iter().all(|&b| b == 0xFF));
+
+        let factory = build_otadata(&table, BootSlot::Factory, OtaImageState::Undefined)?;
+        assert!(factory.iter().all(|&b| b == 0xFF));
+
+        assert!(build_otadata(&table, BootSlot::Ota(2), OtaImageState::Valid).is_err());
+        assert_eq!(ota_app_count(&table), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_otadata() -> Result<()> {
+        let table = table(2);
+
+        let data = build_otadata(&table, BootSlot::Ota(1), OtaImageState::Valid)?;
+        let report = decode_otadata(&data, &table)?;
+        assert_eq!(report.active, Some(0));
+        assert!(report.entries[1].is_erased());
+        assert_eq!(report.boot_partition.as_deref(), Some("ota_1"));
+
+        // Higher sequence in the second sector wins and wraps around the slot count
+        let mut data = data;
+        let newer = OtaSelectEntry::new(5, OtaImageState::New);
+        data[OTADATA_SECTOR_SIZE..OTADATA_SECTOR_SIZE + OTA_SELECT_ENTRY_SIZE]
+            .copy_from_slice(&newer.to_bytes());
+        let report = decode_otadata(&data, &table)?;
+        assert_eq!(report.active, Some(1));
+        assert_eq!(report.boot_partition.as_deref(), Some("ota_0"));
+
+        // A bad CRC or a rolled back state makes the bootloader ignore a copy
+        data[OTADATA_SECTOR_SIZE + 28] ^= 0xFF;
+        let aborted = OtaSelectEntry::new(2, OtaImageState::Aborted);
+        data[..OTA_SELECT_ENTRY_SIZE].copy_from_slice(&aborted.to_bytes());
+        let report = decode_otadata(&data, &table)?;
+        assert!(!report.entries[1].crc_valid());
+        assert_eq!(report.active, None);
+        assert_eq!(report.boot_partition.as_deref(), Some("factory"));
+
+        Ok(())
+    }
+}
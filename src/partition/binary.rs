@@ -0,0 +1,253 @@
+//! ESP-IDF binary partition table format: 32-byte entries, an optional MD5 row
+//! and 0xFF padding up to the 0xC00 bytes the bootloader reads.
+
+use crate::Result;
+use anyhow::anyhow;
+use esp_idf_part::{AppType, DataType, Flags, Partition, PartitionTable, SubType, Type};
+use md5::{Digest, Md5};
+
+pub const ENTRY_SIZE: usize = 32;
+pub const MAX_TABLE_SIZE: usize = 0xC00;
+const ENTRY_MAGIC: [u8; 2] = [0xAA, 0x50];
+const MD5_MAGIC: [u8; 2] = [0xEB, 0xEB];
+const NAME_LEN: usize = 16;
+
+/// MD5 row of a parsed table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Md5Status {
+    /// No MD5 row (CONFIG_PARTITION_TABLE_MD5 disabled or pre-4.x table)
+    Missing,
+    Valid,
+    Mismatch {
+        stored: [u8; 16],
+        computed: [u8; 16],
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct BinaryTable {
+    pub partitions: Vec<Partition>,
+    pub md5: Md5Status,
+}
+
+/// Serialize a table, with or without the MD5 row
+pub fn to_bytes(table: &PartitionTable, with_md5: bool) -> Result<Vec<u8>> {
+    let entries = table.partitions().len() + usize::from(with_md5);
+    if entries * ENTRY_SIZE > MAX_TABLE_SIZE {
+        return Err(anyhow!(
+            "Partition table has {} entries but at most {} fit into 0x{:X} bytes",
+            table.partitions().len(),
+            MAX_TABLE_SIZE / ENTRY_SIZE - usize::from(with_md5),
+            MAX_TABLE_SIZE
+        ));
+    }
+
+    let mut data = Vec::with_capacity(MAX_TABLE_SIZE);
+    for partition in table.partitions() {
+        let name = partition.name();
+        if name.len() > NAME_LEN {
+            return Err(anyhow!(
+                "Partition name '{}' is longer than {} bytes",
+                name,
+                NAME_LEN
+            ));
+        }
+
+        data.extend_from_slice(&ENTRY_MAGIC);
+        data.push(u8::from(partition.ty()));
+        data.push(u8::from(partition.subtype()));
+        data.extend_from_slice(&partition.offset().to_le_bytes());
+        data.extend_from_slice(&partition.size().to_le_bytes());
+        let mut name_field = [0u8; NAME_LEN];
+        name_field[..name.len()].copy_from_slice(name.as_bytes());
+        data.extend_from_slice(&name_field);
+        data.extend_from_slice(&partition.flags().bits().to_le_bytes());
+    }
+
+    if with_md5 {
+        let digest = Md5::digest(&data);
+        data.extend_from_slice(&MD5_MAGIC);
+        data.extend_from_slice(&[0xFF; 14]);
+        data.extend_from_slice(&digest);
+    }
+
+    data.resize(MAX_TABLE_SIZE, 0xFF);
+    Ok(data)
+}
+
+/// Parse a binary table, checking the MD5 row when present
+pub fn parse(data: &[u8]) -> Result<BinaryTable> {
+    let data = &data[..data.len().min(MAX_TABLE_SIZE)];
+    let mut partitions = Vec::new();
+    let mut md5 = Md5Status::Missing;
+
+    for (index, entry) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        let position = index * ENTRY_SIZE;
+        match [entry[0], entry[1]] {
+            ENTRY_MAGIC => partitions.push(parse_entry(entry, position)?),
+            MD5_MAGIC => {
+                let computed: [u8; 16] = Md5::digest(&data[..position]).into();
+                let stored: [u8; 16] = entry[16..32].try_into().expect("16-byte slice");
+                md5 = if stored == computed {
+                    Md5Status::Valid
+                } else {
+                    Md5Status::Mismatch { stored, computed }
+                };
+                break;
+            }
+            [0xFF, 0xFF] => break,
+            magic => {
+                return Err(anyhow!(
+                    "Invalid partition table entry at 0x{:X} (magic 0x{:02X}{:02X})",
+                    position,
+                    magic[0],
+                    magic[1]
+                ));
+            }
+        }
+    }
+
+    if partitions.is_empty() {
+        return Err(anyhow!("Partition table contains no entries"));
+    }
+
+    Ok(BinaryTable { partitions, md5 })
+}
+
+/// Locate a partition table at a 4KB boundary of a flash image
+pub fn find_in_image(image: &[u8]) -> Option<(u32, BinaryTable)> {
+    (0x1000..image.len())
+        .step_by(0x1000)
+        .filter(|&offset| image[offset..].starts_with(&ENTRY_MAGIC))
+        .find_map(|offset| {
+            parse(&image[offset..])
+                .ok()
+                .map(|table| (offset as u32, table))
+        })
+}
+
+fn parse_entry(entry: &[u8], position: usize) -> Result<Partition> {
+    let ty = Type::from(entry[2]);
+    let raw_subtype = entry[3];
+    let subtype = match ty {
+        Type::App => AppType::from_repr(raw_subtype as usize)
+            .map(SubType::App)
+            .unwrap_or(SubType::Custom(raw_subtype)),
+        Type::Data => DataType::from_repr(raw_subtype as usize)
+            .map(SubType::Data)
+            .unwrap_or(SubType::Custom(raw_subtype)),
+        Type::Custom(_) => SubType::Custom(raw_subtype),
+    };
+    let offset = u32::from_le_bytes(entry[4..8].try_into().expect("4-byte slice"));
+    let size = u32::from_le_bytes(entry[8..12].try_into().expect("4-byte slice"));
+    let name_field = &entry[12..12 + NAME_LEN];
+    let name_len = name_field.iter().position(|&b| b == 0).unwrap_or(NAME_LEN);
+    let name = String::from_utf8_lossy(&name_field[..name_len]).into_owned();
+    if offset.checked_add(size).is_none() {
+        return Err(anyhow!(
+            "Partition '{}' in the entry at 0x{:X} ends beyond the 32-bit address space",
+            name,
+            position
+        ));
+    }
+    let flags = Flags::from_bits_truncate(u32::from_le_bytes(
+        entry[28..32].try_into().expect("4-byte slice"),
+    ));
+
+    Ok(Partition::new(name, ty, subtype, offset, size, flags))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_table() -> PartitionTable {
+        PartitionTable::new(vec![
+            Partition::new(
+                "nvs",
+                Type::Data,
+                SubType::Data(DataType::Nvs),
+                0x11000,
+                0x1000,
+                Flags::empty(),
+            ),
+            Partition::new(
+                "ota_0",
+                Type::App,
+                SubType::App(AppType::Ota_0),
+                0x20000,
+                0x100000,
+                Flags::ENCRYPTED,
+            ),
+            Partition::new(
+                "calib",
+                Type::Custom(0x40),
+                SubType::Custom(0x01),
+                0x120000,
+                0x2000,
+                Flags::READONLY,
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_to_bytes_matches_esp_idf_part() -> Result<()> {
+        let table = sample_table();
+        assert_eq!(to_bytes(&table, true)?, table.to_bin()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip_with_and_without_md5() -> Result<()> {
+        let table = sample_table();
+
+        let parsed = parse(&to_bytes(&table, true)?)?;
+        assert_eq!(parsed.md5, Md5Status::Valid);
+        assert_eq!(&parsed.partitions, table.partitions());
+
+        let legacy = to_bytes(&table, false)?;
+        assert_eq!(legacy.len(), MAX_TABLE_SIZE);
+        assert!(legacy[3 * ENTRY_SIZE..].iter().all(|&b| b == 0xFF));
+        let parsed = parse(&legacy)?;
+        assert_eq!(parsed.md5, Md5Status::Missing);
+        assert_eq!(parsed.partitions.len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_md5_mismatch() -> Result<()> {
+        let mut data = to_bytes(&sample_table(), true)?;
+        data[4] ^= 0x10; // move nvs
+        assert!(matches!(parse(&data)?.md5, Md5Status::Mismatch { .. }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_partition_past_4gb() -> Result<()> {
+        let mut data = to_bytes(&sample_table(), false)?;
+        data[ENTRY_SIZE + 8..ENTRY_SIZE + 12].copy_from_slice(&0xFFFF_0000u32.to_le_bytes());
+        let error = parse(&data).unwrap_err();
+        assert!(error.to_string().contains("ota_0"));
+
+        let mut image = vec![0xFF; 0x20000];
+        image[0x10000..0x10000 + data.len()].copy_from_slice(&data);
+        assert!(find_in_image(&image).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_in_image() -> Result<()> {
+        let mut image = vec![0xFF; 0x20000];
+        image[0x2000] = 0xE9;
+        let table = to_bytes(&sample_table(), false)?;
+        image[0x10000..0x10000 + table.len()].copy_from_slice(&table);
+
+        let (offset, parsed) = find_in_image(&image).unwrap();
+        assert_eq!(offset, 0x10000);
+        assert_eq!(parsed.partitions[1].name(), "ota_0");
+
+        assert!(find_in_image(&vec![0xFF; 0x20000]).is_none());
+        Ok(())
+    }
+}
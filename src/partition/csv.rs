@@ -0,0 +1,163 @@
+//! ESP-IDF partition table CSV (`name, type, subtype, offset, size, flags`)
+
+use crate::Result;
+use crate::config::{
+    PartitionSpec, defaults::PARTITION_TABLE_SIZE, parse_flags, parse_partition_type, parse_size,
+};
+use crate::nvs::csv::split_fields;
+use anyhow::anyhow;
+use esp_idf_part::{Flags, Partition, PartitionTable};
+
+/// Parse a CSV table; empty offsets follow the previous partition as in
+/// `gen_esp32part.py`, starting right after the partition table
+pub fn parse(text: &str, partition_table_offset: u32) -> Result<PartitionTable> {
+    let mut partitions = Vec::new();
+    let mut next_offset = partition_table_offset + PARTITION_TABLE_SIZE;
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line_error = |message: String| anyhow!("Line {}: {}", index + 1, message);
+        let fields: Vec<String> = split_fields(line)
+            .map_err(line_error)?
+            .iter()
+            .map(|field| field.trim().to_string())
+            .collect();
+        if fields.len() < 5 {
+            return Err(line_error(format!(
+                "expected name, type, subtype, offset, size[, flags] but found {} fields",
+                fields.len()
+            )));
+        }
+
+        let name = fields[0].as_str();
+        let ty = parse_partition_type(&fields[1]).map_err(|e| line_error(e.to_string()))?;
+        let subtype = PartitionSpec {
+            name: name.to_string(),
+            ty: Some(ty),
+            subtype: Some(fields[2].clone()),
+            offset: None,
+            size: None,
+            file: None,
+            flags: Flags::empty(),
+        }
+        .resolve_subtype()
+        .map_err(|e| line_error(e.to_string()))?;
+
+        let alignment = super::PartitionGenerator::alignment_for(ty);
+        let offset = match fields[3].as_str() {
+            "" => next_offset
+                .checked_next_multiple_of(alignment)
+                .ok_or_else(|| line_error(format!("no offset left after 0x{:X}", next_offset)))?,
+            value => parse_size(value).map_err(|e| line_error(e.to_string()))?,
+        };
+        let size = parse_size(&fields[4]).map_err(|e| line_error(e.to_string()))?;
+        let flags = parse_flags(fields.get(5).map_or("", String::as_str))
+            .map_err(|e| line_error(e.to_string()))?;
+
+        next_offset = offset.checked_add(size).ok_or_else(|| {
+            line_error(format!(
+                "partition of {} bytes at 0x{:X} ends beyond the 32-bit address space",
+                size, offset
+            ))
+        })?;
+        partitions.push(Partition::new(name, ty, subtype, offset, size, flags));
+    }
+
+    if partitions.is_empty() {
+        return Err(anyhow!("Partition table CSV contains no entries"));
+    }
+
+    Ok(PartitionTable::new(partitions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use esp_idf_part::{AppType, DataType, SubType, Type};
+
+    #[test]
+    fn test_parse_esp_idf_csv() -> Result<()> {
+        let csv = "\
+# Name,   Type, SubType, Offset,  Size, Flags
+nvs,      data, nvs,     ,        0x6000,
+phy_init, data, phy,     ,        4K,
+factory,  app,  factory, ,        1M,
+ota_0,    app,  ota_0,   0x200000, 1M, encrypted
+calib,    0x40, 0x01,    ,        8K, readonly
+";
+        let table = parse(csv, 0x8000)?;
+        let partitions = table.partitions();
+        assert_eq!(partitions.len(), 5);
+        assert_eq!(partitions[0].offset(), 0x9000);
+        assert_eq!(partitions[1].offset(), 0xF000);
+        assert_eq!(partitions[1].subtype(), SubType::Data(DataType::Phy));
+        assert_eq!(partitions[2].offset(), 0x10000);
+        assert_eq!(partitions[2].size(), 0x100000);
+        assert_eq!(partitions[3].subtype(), SubType::App(AppType::Ota_0));
+        assert_eq!(partitions[3].flags(), Flags::ENCRYPTED);
+        assert_eq!(partitions[4].ty(), Type::Custom(0x40));
+        assert_eq!(partitions[4].offset(), 0x300000);
+        assert_eq!(partitions[4].flags(), Flags::READONLY);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_round_trips_generated_csv() -> Result<()> {
+        let table = parse(
+            "nvs, data, nvs, 0x11000, 4K\nota_0, app, ota_0, 0x20000, 1M, encrypted\n",
+            0x10000,
+        )?;
+        let reparsed = parse(&table.to_csv()?, 0x10000)?;
+        assert_eq!(reparsed.partitions(), table.partitions());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = parse("nvs, data, nvs\n", 0x8000).unwrap_err().to_string();
+        assert!(err.contains("Line 1"));
+
+        let err = parse("# header\nfactory, app, nvs, , 1M\n", 0x8000)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Line 2") && err.contains("Invalid subtype"));
+
+        assert!(parse("# only comments\n", 0x8000).is_err());
+
+        let err = parse("big, data, nvs, 0xFFFFF000, 0x2000\n", 0x8000)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("32-bit address space"));
+        let err = parse(
+            "a, data, nvs, 0xFFFF0000, 0x1000\nb, app, factory, , 1M\n",
+            0x8000,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("Line 2"));
+
+        let err = parse("nvs, data, nvs, \", 4K\n", 0x8000)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unterminated"));
+    }
+
+    #[test]
+    fn test_parse_quoted_fields() -> Result<()> {
+        let table = parse(
+            "\"nvs\", data, nvs, , 4K\ncalib, 0x40, 0x01, , 8K, \"encrypted:readonly\"\n",
+            0x8000,
+        )?;
+        assert_eq!(table.partitions()[0].name(), "nvs");
+        assert_eq!(
+            table.partitions()[1].flags(),
+            Flags::ENCRYPTED | Flags::READONLY
+        );
+        Ok(())
+    }
+}
@@ -1,141 +1,484 @@
 use crate::Result;
-use crate::config::{Config, defaults::*};
+use crate::config::{AllocationStrategy, Config, LayoutPreset, PartitionSpec, defaults::*};
 use crate::firmware::FirmwareBinary;
+use crate::lock::LayoutLock;
 use anyhow::anyhow;
 use esp_idf_part::{AppType, DataType, Flags, Partition, PartitionTable, SubType, Type};
-use log::info;
+use log::{info, warn};
+use std::path::Path;
+
+pub mod binary;
+pub mod csv;
 
 pub struct PartitionGenerator;
 
+/// Read a partition table from a CSV file, a lock file, a binary table or the
+/// table embedded in a flash image, along with the table offset when the input records it
+pub fn load_table_file(
+    path: &Path,
+    partition_table_offset: u32,
+) -> Result<(PartitionTable, Option<u32>)> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+
+    match extension.as_deref() {
+        Some("csv") => {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Failed to read {:?}: {}", path, e))?;
+            let table = csv::parse(&text, partition_table_offset)
+                .map_err(|e| anyhow!("Invalid partition table CSV {:?}: {}", path, e))?;
+            Ok((table, None))
+        }
+        Some("json" | "lock") => {
+            let lock = LayoutLock::load_from_file(path)?;
+            Ok((lock.to_table()?, Some(lock.partition_table_offset)))
+        }
+        _ => {
+            let data =
+                std::fs::read(path).map_err(|e| anyhow!("Failed to read {:?}: {}", path, e))?;
+            let (parsed, offset) = if data.len() <= binary::MAX_TABLE_SIZE {
+                (binary::parse(&data)?, None)
+            } else {
+                let (offset, parsed) = binary::find_in_image(&data)
+                    .ok_or_else(|| anyhow!("No partition table found in {:?}", path))?;
+                info!("Found partition table at 0x{:X} in {:?}", offset, path);
+                (parsed, Some(offset))
+            };
+            if let binary::Md5Status::Mismatch { .. } = parsed.md5 {
+                return Err(anyhow!("Partition table in {:?} fails its MD5 check", path));
+            }
+            Ok((PartitionTable::new(parsed.partitions), offset))
+        }
+    }
+}
+
 impl PartitionGenerator {
     pub fn generate_table(firmwares: &[FirmwareBinary], config: &Config) -> Result<PartitionTable> {
+        Self::generate(firmwares, config, false)
+    }
+
+    /// Table for the configured layout without any firmware: a `FACTORY_SIZE`
+    /// factory slot (`LAUNCHER_MENU_SIZE` for the launcher, none for A/B)
+    /// plus the reserved OTA slots
+    pub fn generate_template(config: &Config) -> Result<PartitionTable> {
+        Self::generate(&[], config, true)
+    }
+
+    fn generate(
+        firmwares: &[FirmwareBinary],
+        config: &Config,
+        template: bool,
+    ) -> Result<PartitionTable> {
         info!(
             "Generating partition table for {} firmwares",
             firmwares.len()
         );
 
-        let mut partitions = Vec::new();
-
-        // Add bootloader partition (ESP32-P4 specific offset)
-        partitions.push(Partition::new(
-            "bootloader".to_string(),
-            Type::App,
-            SubType::App(AppType::Factory),
-            BOOTLOADER_OFFSET,
-            BOOTLOADER_SIZE,
-            Flags::empty(),
-        ));
-
-        // Add partition table
-        partitions.push(Partition::new(
-            "partition-table".to_string(),
-            Type::Data,
-            SubType::Data(DataType::Phy),
-            PARTITION_TABLE_OFFSET,
-            PARTITION_TABLE_SIZE,
-            Flags::empty(),
-        ));
+        let layout = config.layout;
+        let assignments = Self::app_assignments(firmwares, layout);
+        let ota_firmwares: Vec<&FirmwareBinary> = assignments
+            .iter()
+            .filter(|(name, _)| name != "factory")
+            .map(|(_, firmware)| *firmware)
+            .collect();
+
+        let ota_firmware_count = ota_firmwares.len();
+        if layout == LayoutPreset::Minimal && ota_firmware_count > 0 {
+            return Err(anyhow!(
+                "Layout 'minimal' holds a single app but {} app firmwares were found",
+                ota_firmware_count + 1
+            ));
+        }
+        if ota_firmware_count > config.max_ota_partitions {
+            return Err(anyhow!(
+                "{} OTA firmwares found but only {} OTA partitions are allowed (--max-ota-partitions)",
+                ota_firmware_count,
+                config.max_ota_partitions
+            ));
+        }
+
+        // Presets like A/B always provide their slots, reserving the empty ones
+        let extra_ota_slots =
+            config.extra_ota_slots + layout.min_ota_slots().saturating_sub(ota_firmware_count);
+        let total_ota_slots = ota_firmware_count + extra_ota_slots;
+        if total_ota_slots > config.max_ota_partitions {
+            return Err(anyhow!(
+                "{} OTA firmwares plus {} reserved OTA slots exceed the limit of {} OTA partitions (--max-ota-partitions)",
+                ota_firmware_count,
+                extra_ota_slots,
+                config.max_ota_partitions
+            ));
+        }
+        if let Some(max_slots) = layout.max_ota_slots()
+            && total_ota_slots > max_slots
+        {
+            return Err(anyhow!(
+                "Layout '{}' has {} OTA slots but {} OTA firmwares and {} reserved slots were requested",
+                layout,
+                max_slots,
+                ota_firmware_count,
+                config.extra_ota_slots
+            ));
+        }
+
+        // Everything after the bootloader is placed relative to the partition table
+        let partition_table_offset = config.partition_table_offset;
+        let bootloader_region = Self::bootloader_region(firmwares, partition_table_offset)?;
+        let flash_size = config.flash_size.size_bytes();
 
-        // Add NVS partition
-        partitions.push(Partition::new(
-            "nvs".to_string(),
+        let mut requests = vec![PartitionRequest::new(
+            "nvs",
             Type::Data,
             SubType::Data(DataType::Nvs),
-            NVS_OFFSET,
             NVS_SIZE,
-            Flags::empty(),
-        ));
+        )];
+        if layout.has_otadata() {
+            requests.push(PartitionRequest::new(
+                "otadata",
+                Type::Data,
+                SubType::Data(DataType::Ota),
+                OTADATA_SIZE,
+            ));
+        }
 
-        // Add OTA data partition
-        partitions.push(Partition::new(
-            "otadata".to_string(),
-            Type::Data,
-            SubType::Data(DataType::Ota),
-            OTADATA_OFFSET,
-            OTADATA_SIZE,
-            Flags::empty(),
-        ));
+        // Encrypted NVS reads its keys from an encrypted nvs_keys partition
+        let has_nvs_keys = config.partitions.iter().any(|spec| {
+            spec.is_new_partition()
+                && matches!(spec.resolve_subtype(), Ok(SubType::Data(DataType::NvsKeys)))
+        });
+        if config.nvs_keys.is_some() && !has_nvs_keys {
+            requests.push(PartitionRequest {
+                flags: Flags::ENCRYPTED,
+                ..PartitionRequest::new(
+                    "nvs_keys",
+                    Type::Data,
+                    SubType::Data(DataType::NvsKeys),
+                    NVS_KEYS_SIZE,
+                )
+            });
+        }
 
-        // Add factory partition (first firmware should be bootloader, second should be factory app)
-        if firmwares.len() >= 2 {
-            let factory_firmware = &firmwares[1]; // Second firmware is factory app
-            let factory_size = Self::align_up(factory_firmware.size, OTA_ALIGNMENT);
+        // Add factory partition (first app firmware, unless the layout has no factory slot)
+        let factory_firmware = assignments
+            .iter()
+            .find(|(name, _)| name == "factory")
+            .map(|(_, firmware)| *firmware);
+        if factory_firmware.is_some() || (template && layout.has_factory()) {
+            requests.push(PartitionRequest {
+                // Keep FACTORY_SIZE free so OTA offsets don't move when the factory app grows
+                min_span: if layout.factory_growth_room() {
+                    FACTORY_SIZE
+                } else {
+                    0
+                },
+                firmware: factory_firmware.map(|firmware| (firmware.name.clone(), firmware.size)),
+                ..PartitionRequest::new(
+                    "factory",
+                    Type::App,
+                    SubType::App(AppType::Factory),
+                    layout.factory_size(factory_firmware.map(|firmware| firmware.size)),
+                )
+            });
+        }
 
-            partitions.push(Partition::new(
-                "factory".to_string(),
-                Type::App,
-                SubType::App(AppType::Factory),
-                FACTORY_OFFSET,
-                factory_size,
-                Flags::empty(),
+        // Add OTA partitions for the remaining app firmwares,
+        // followed by the reserved empty slots for future updates
+        let reserved_size = Self::align_up(config.extra_ota_size, OTA_ALIGNMENT);
+        if extra_ota_slots > 0 && reserved_size < MIN_OTA_SIZE {
+            return Err(anyhow!(
+                "Reserved OTA slot size {} bytes is below the minimum of {} bytes",
+                reserved_size,
+                MIN_OTA_SIZE
             ));
         }
 
-        // Calculate remaining space for OTA partitions
-        let flash_size = config.flash_size.size_bytes();
-        let mut current_offset = FACTORY_OFFSET + FACTORY_SIZE;
+        // Uniform layouts size every slot for the largest app so any app fits any slot
+        let uniform_size = layout.uniform_ota_slots().then(|| {
+            ota_firmwares
+                .iter()
+                .map(|firmware| Self::align_up(firmware.size, OTA_ALIGNMENT))
+                .chain((extra_ota_slots > 0).then_some(reserved_size))
+                .max()
+                .unwrap_or(reserved_size)
+        });
 
-        // Add OTA partitions for remaining firmwares (starting from index 2)
-        let ota_partitions: Vec<_> = firmwares
+        let ota_slots = ota_firmwares
             .iter()
-            .skip(2) // Skip bootloader and factory
-            .take(config.max_ota_partitions)
-            .enumerate()
-            .map(|(i, firmware)| {
-                let ota_size = Self::align_up(firmware.size, OTA_ALIGNMENT);
-                let partition_name = format!("ota_{}", i);
-                let ota_subtype = match i {
-                    0 => AppType::Ota_0,
-                    1 => AppType::Ota_1,
-                    2 => AppType::Ota_2,
-                    3 => AppType::Ota_3,
-                    4 => AppType::Ota_4,
-                    5 => AppType::Ota_5,
-                    6 => AppType::Ota_6,
-                    7 => AppType::Ota_7,
-                    8 => AppType::Ota_8,
-                    9 => AppType::Ota_9,
-                    10 => AppType::Ota_10,
-                    11 => AppType::Ota_11,
-                    12 => AppType::Ota_12,
-                    13 => AppType::Ota_13,
-                    14 => AppType::Ota_14,
-                    15 => AppType::Ota_15,
-                    _ => AppType::Ota_0, // Default fallback
-                };
-
-                (partition_name, ota_subtype, ota_size, firmware.size)
+            .map(|firmware| {
+                (
+                    Self::align_up(firmware.size, OTA_ALIGNMENT),
+                    Some((firmware.name.clone(), firmware.size)),
+                )
             })
-            .collect();
+            .chain(std::iter::repeat_n((reserved_size, None), extra_ota_slots));
+
+        for (i, (aligned_size, firmware)) in ota_slots.enumerate() {
+            let subtype = Self::ota_app_type(i)?;
+            requests.push(PartitionRequest {
+                firmware,
+                ..PartitionRequest::new(
+                    &format!("ota_{}", i),
+                    Type::App,
+                    SubType::App(subtype),
+                    uniform_size.unwrap_or(aligned_size),
+                )
+            });
+        }
+
+        // Filesystem images go after the apps unless the partitions file or
+        // --fill-partition already provides a partition of their subtype
+        let provides =
+            |subtype: DataType| {
+                config.fill_partition.as_ref().is_some_and(|fill| fill.subtype == subtype)
+                || config.partitions.iter().any(|spec| {
+                    spec.is_new_partition()
+                        && matches!(spec.resolve_subtype(), Ok(SubType::Data(s)) if s == subtype)
+                })
+            };
+        if config.spiffs_dir.is_some() && !provides(DataType::Spiffs) {
+            requests.push(PartitionRequest::new(
+                "spiffs",
+                Type::Data,
+                SubType::Data(DataType::Spiffs),
+                SPIFFS_SIZE,
+            ));
+        }
+        if config.littlefs_dir.is_some() && !provides(DataType::Littlefs) {
+            requests.push(PartitionRequest::new(
+                "littlefs",
+                Type::Data,
+                SubType::Data(DataType::Littlefs),
+                LITTLEFS_SIZE,
+            ));
+        }
+        if config.fat_dir.is_some() && !provides(DataType::Fat) {
+            requests.push(PartitionRequest::new(
+                "fat",
+                Type::Data,
+                SubType::Data(DataType::Fat),
+                FAT_SIZE,
+            ));
+        }
+
+        // Add user-defined partitions from the partitions file
+        for spec in config.partitions.iter().filter(|s| s.is_new_partition()) {
+            let ty = spec.ty.expect("new partitions have a type");
+            let size = Self::spec_size(spec, Self::alignment_for(ty))?;
+            requests.push(PartitionRequest {
+                offset: spec.offset,
+                flags: spec.flags,
+                ..PartitionRequest::new(&spec.name, ty, spec.resolve_subtype()?, size)
+            });
+        }
+
+        // Apply pins and flags from entries that adjust generated partitions
+        let mut fill_flags = Flags::empty();
+        for spec in config.partitions.iter().filter(|s| !s.is_new_partition()) {
+            if let Some(fill) = &config.fill_partition
+                && fill.name == spec.name
+            {
+                if spec.is_pinned() {
+                    return Err(anyhow!(
+                        "Partition '{}' fills the remaining flash and cannot be pinned",
+                        spec.name
+                    ));
+                }
+                fill_flags |= spec.flags;
+                continue;
+            }
+
+            let request = requests
+                .iter_mut()
+                .find(|r| r.name == spec.name)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Partition '{}' from the partitions file has no type and is not a generated partition",
+                        spec.name
+                    )
+                })?;
+            request.pin(spec)?;
+        }
+
+        // Keep the partitions recorded in the lock file where they were
+        let locked = match &config.layout_lock {
+            Some(lock) => Self::apply_lock(&mut requests, lock, config)?,
+            None => LockedLayout::default(),
+        };
+
+        // The bootloader region and partition table are fixed, pinned partitions
+        // are reserved next and everything else is allocated around them. The
+        // whole space in front of the table is reserved, not just the bootloader:
+        // ESP-IDF's gen_esp32part.py rejects partitions below the table end, so
+        // the gap between the bootloader and the table can't hold data partitions
+        let mut allocator = FlashAllocator::new(flash_size);
+        allocator.reserve("bootloader", 0, partition_table_offset)?;
+        allocator.reserve(
+            "partition-table",
+            partition_table_offset,
+            PARTITION_TABLE_SIZE,
+        )?;
+        for (name, offset, size) in &locked.reserved {
+            allocator.reserve(name, *offset, *size)?;
+        }
+        if let (Some(fill), Some(offset)) = (&config.fill_partition, locked.fill_offset) {
+            allocator.reserve(&fill.name, offset, flash_size.saturating_sub(offset))?;
+        }
 
-        for (name, subtype, aligned_size, actual_size) in ota_partitions {
-            if current_offset + aligned_size > flash_size {
+        let mut offsets = vec![0; requests.len()];
+        for (request, slot) in requests.iter().zip(offsets.iter_mut()) {
+            if let Some(offset) = request.offset {
+                let alignment = Self::alignment_for(request.ty);
+                if !offset.is_multiple_of(alignment) {
+                    return Err(anyhow!(
+                        "Pinned offset 0x{:X} of partition '{}' is not aligned to 0x{:X}",
+                        offset,
+                        request.name,
+                        alignment
+                    ));
+                }
+                allocator.reserve(&request.name, offset, request.size)?;
+                *slot = offset;
+            }
+        }
+
+        let table_end = partition_table_offset + PARTITION_TABLE_SIZE;
+        match config.allocation {
+            AllocationStrategy::Sequential => {
+                // New partitions are appended after the locked layout
+                let mut cursor = table_end.max(locked.end);
+                for (request, slot) in requests.iter().zip(offsets.iter_mut()) {
+                    if request.offset.is_none() {
+                        let offset = allocator.allocate(
+                            &request.name,
+                            cursor,
+                            request.size,
+                            Self::alignment_for(request.ty),
+                        )?;
+                        cursor = offset + request.size.max(request.min_span);
+                        *slot = offset;
+                    }
+                }
+            }
+            AllocationStrategy::Packed => {
+                // First-fit decreasing: app slots largest first, then data partitions
+                // into the 4KB-granular gaps the 64KB app alignment leaves behind.
+                // Gaps inside a locked layout stay unused: new partitions are
+                // only appended after it
+                let start = table_end.max(locked.end);
+                let mut order: Vec<usize> = (0..requests.len())
+                    .filter(|&i| requests[i].offset.is_none())
+                    .collect();
+                order.sort_by_key(|&i| {
+                    (
+                        requests[i].ty != Type::App,
+                        std::cmp::Reverse(requests[i].size.max(requests[i].min_span)),
+                    )
+                });
+
+                // Like sequential, the factory slot keeps its growth room free
+                for i in order {
+                    let request = &requests[i];
+                    offsets[i] = allocator.allocate(
+                        &request.name,
+                        start,
+                        request.size.max(request.min_span),
+                        Self::alignment_for(request.ty),
+                    )?;
+                }
+            }
+        }
+
+        let mut partitions = vec![
+            // Add bootloader partition (ESP32-P4 specific offset)
+            Partition::new(
+                "bootloader".to_string(),
+                Type::App,
+                SubType::App(AppType::Factory),
+                BOOTLOADER_OFFSET,
+                bootloader_region,
+                Flags::empty(),
+            ),
+            // Add partition table
+            Partition::new(
+                "partition-table".to_string(),
+                Type::Data,
+                SubType::Data(DataType::Phy),
+                partition_table_offset,
+                PARTITION_TABLE_SIZE,
+                Flags::empty(),
+            ),
+        ];
+
+        for (request, offset) in requests.iter().zip(offsets) {
+            let pinned = if request.offset.is_some() {
+                ", pinned"
+            } else {
+                ""
+            };
+            match &request.firmware {
+                Some((_, firmware_size)) => info!(
+                    "Added partition '{}' at 0x{:X} ({} bytes, firmware: {} bytes{})",
+                    request.name, offset, request.size, firmware_size, pinned
+                ),
+                None => info!(
+                    "Added partition '{}' ({}/{}) at 0x{:X} ({} bytes{})",
+                    request.name, request.ty, request.subtype, offset, request.size, pinned
+                ),
+            }
+
+            partitions.push(Partition::new(
+                request.name.clone(),
+                request.ty,
+                request.subtype,
+                offset,
+                request.size,
+                request.flags,
+            ));
+        }
+
+        // Grow the trailing data partition over whatever flash is left
+        if let Some(fill) = &config.fill_partition {
+            let used_end = allocator.end();
+            let fill_offset = locked
+                .fill_offset
+                .unwrap_or_else(|| Self::align_up(used_end, DATA_ALIGNMENT));
+            let fill_size =
+                flash_size.saturating_sub(fill_offset) / DATA_ALIGNMENT * DATA_ALIGNMENT;
+
+            if fill_size == 0 {
                 return Err(anyhow!(
-                    "Not enough flash space for OTA partition '{}' ({} bytes needed, {} bytes available)",
-                    name,
-                    aligned_size,
-                    flash_size - current_offset
+                    "No flash space left for data partition '{}' (app partitions end at 0x{:X}, flash size: 0x{:X})",
+                    fill.name,
+                    used_end,
+                    flash_size
                 ));
             }
 
             partitions.push(Partition::new(
-                name.clone(),
-                Type::App,
-                SubType::App(subtype),
-                current_offset,
-                aligned_size,
-                Flags::empty(),
+                fill.name.clone(),
+                Type::Data,
+                SubType::Data(fill.subtype),
+                fill_offset,
+                fill_size,
+                fill_flags,
             ));
 
             info!(
-                "Added OTA partition '{}' at 0x{:X} ({} bytes, firmware: {} bytes)",
-                name, current_offset, aligned_size, actual_size
+                "Added data partition '{}' at 0x{:X} filling remaining flash ({} bytes)",
+                fill.name, fill_offset, fill_size
             );
-
-            current_offset += aligned_size;
         }
 
+        // List entries by offset, as ESP-IDF's gen_esp32part.py does
+        partitions.sort_by_key(|p| p.offset());
         let partition_table = PartitionTable::new(partitions);
+        info!(
+            "Layout ({} allocation) leaves {} bytes unused between partitions",
+            config.allocation,
+            Self::wasted_bytes(&partition_table, partition_table_offset)
+        );
 
         // Validate the partition table
         Self::validate_partition_table(&partition_table, flash_size)?;
@@ -144,28 +487,229 @@ impl PartitionGenerator {
         Ok(partition_table)
     }
 
-    fn validate_partition_table(table: &PartitionTable, flash_size: u32) -> Result<()> {
-        // Check if any partitions exceed flash size
+    /// Partition each app firmware (everything after the bootloader) is written to
+    pub fn app_assignments(
+        firmwares: &[FirmwareBinary],
+        layout: LayoutPreset,
+    ) -> Vec<(String, &FirmwareBinary)> {
+        let mut ota_index = 0;
+        firmwares
+            .iter()
+            .skip(1)
+            .enumerate()
+            .map(|(i, firmware)| {
+                if i == 0 && layout.has_factory() {
+                    ("factory".to_string(), firmware)
+                } else {
+                    ota_index += 1;
+                    (format!("ota_{}", ota_index - 1), firmware)
+                }
+            })
+            .collect()
+    }
+
+    /// Bytes left unused between the partition table and the end of the last
+    /// partition, i.e. flash lost to alignment and reserved growth room
+    pub fn wasted_bytes(table: &PartitionTable, partition_table_offset: u32) -> u32 {
+        let mut ranges: Vec<(u32, u32)> = table
+            .partitions()
+            .iter()
+            .filter(|p| p.offset() >= partition_table_offset)
+            .map(|p| (p.offset(), p.offset() + p.size()))
+            .collect();
+        ranges.sort_unstable();
+
+        let mut cursor = partition_table_offset;
+        let mut wasted = 0;
+        for (start, end) in ranges {
+            wasted += start.saturating_sub(cursor);
+            cursor = cursor.max(end);
+        }
+        wasted
+    }
+
+    /// Pin requests to their locked offsets and sizes, reporting every firmware
+    /// or partition that no longer fits its locked slot
+    fn apply_lock(
+        requests: &mut [PartitionRequest],
+        lock: &LayoutLock,
+        config: &Config,
+    ) -> Result<LockedLayout> {
+        if lock.partition_table_offset != config.partition_table_offset {
+            return Err(anyhow!(
+                "Lock file places the partition table at 0x{:X} but --partition-table-offset is 0x{:X}; use --relayout to regenerate the layout",
+                lock.partition_table_offset,
+                config.partition_table_offset
+            ));
+        }
+
+        let mut layout = LockedLayout::default();
+        let mut conflicts = Vec::new();
+
+        for locked in &lock.partitions {
+            if locked.name == "bootloader" || locked.name == "partition-table" {
+                continue;
+            }
+            if config
+                .fill_partition
+                .as_ref()
+                .is_some_and(|fill| fill.name == locked.name)
+            {
+                layout.fill_offset = Some(locked.offset);
+                continue;
+            }
+
+            layout.end = layout.end.max(locked.offset + locked.size);
+
+            let Some(request) = requests.iter_mut().find(|r| r.name == locked.name) else {
+                warn!(
+                    "Locked partition '{}' is no longer generated; keeping 0x{:X}-0x{:X} unused",
+                    locked.name,
+                    locked.offset,
+                    locked.offset + locked.size
+                );
+                layout
+                    .reserved
+                    .push((locked.name.clone(), locked.offset, locked.size));
+                continue;
+            };
+
+            let (ty, subtype) = (request.ty.to_string(), request.subtype.to_string());
+            if ty != locked.ty || subtype != locked.subtype {
+                conflicts.push(format!(
+                    "partition '{}' changed from {}/{} to {}/{}",
+                    locked.name, locked.ty, locked.subtype, ty, subtype
+                ));
+                continue;
+            }
+
+            // Offsets pinned in the partitions file take precedence
+            if request.offset.is_some() {
+                continue;
+            }
+
+            match &request.firmware {
+                Some((firmware, firmware_size)) if *firmware_size > locked.size => {
+                    conflicts.push(format!(
+                        "firmware '{}' ({} bytes) no longer fits locked slot '{}' ({} bytes at 0x{:X})",
+                        firmware, firmware_size, locked.name, locked.size, locked.offset
+                    ))
+                }
+                None if request.size > locked.size => conflicts.push(format!(
+                    "partition '{}' ({} bytes) no longer fits its locked slot ({} bytes at 0x{:X})",
+                    locked.name, request.size, locked.size, locked.offset
+                )),
+                _ => {
+                    request.offset = Some(locked.offset);
+                    request.size = locked.size;
+                    request.min_span = 0;
+                }
+            }
+        }
+
+        if !conflicts.is_empty() {
+            return Err(anyhow!(
+                "Layout no longer matches the lock file (use --relayout to regenerate it):\n  {}",
+                conflicts.join("\n  ")
+            ));
+        }
+
+        Ok(layout)
+    }
+
+    pub fn validate_partition_table(table: &PartitionTable, flash_size: u32) -> Result<()> {
+        // Check names: ESP-IDF stores at most 16 bytes and looks partitions up by name
+        let mut names = std::collections::HashSet::new();
+        for partition in table.partitions() {
+            let name = partition.name();
+            if name.is_empty() || name.len() > MAX_PARTITION_NAME_LEN {
+                return Err(anyhow!(
+                    "Partition name '{}' must be 1 to {} characters long",
+                    name,
+                    MAX_PARTITION_NAME_LEN
+                ));
+            }
+            if !names.insert(name.clone()) {
+                return Err(anyhow!("Duplicate partition name '{}'", name));
+            }
+        }
+
+        // Check app subtypes (factory, ota_N, test) and otadata are unique.
+        // The bootloader entry is a placeholder and is not an app slot.
+        let mut subtypes = std::collections::HashMap::new();
+        for partition in table.partitions() {
+            let unique_subtype = partition.ty() == Type::App && partition.name() != "bootloader"
+                || partition.subtype() == SubType::Data(DataType::Ota);
+            if unique_subtype
+                && let Some(other) = subtypes.insert(
+                    (u8::from(partition.ty()), u8::from(partition.subtype())),
+                    partition.name(),
+                )
+            {
+                return Err(anyhow!(
+                    "Partitions '{}' and '{}' both use subtype '{}'",
+                    other,
+                    partition.name(),
+                    partition.subtype()
+                ));
+            }
+        }
+
+        // Check if any partitions exceed flash size; tables read from dumps
+        // may end beyond the 32-bit address space
         for partition in table.partitions() {
-            if partition.offset() + partition.size() > flash_size {
+            let end = partition.offset() as u64 + partition.size() as u64;
+            if end > flash_size as u64 {
                 return Err(anyhow!(
                     "Partition '{}' exceeds flash size (ends at 0x{:X}, flash size: 0x{:X})",
                     partition.name(),
-                    partition.offset() + partition.size(),
+                    end,
                     flash_size
                 ));
             }
         }
 
+        // Check flags against what ESP-IDF supports
+        for partition in table.partitions() {
+            let flags = partition.flags();
+
+            if flags.contains(Flags::READONLY)
+                && (partition.ty() != Type::Data
+                    || matches!(
+                        partition.subtype(),
+                        SubType::Data(DataType::Ota | DataType::Coredump)
+                    ))
+            {
+                return Err(anyhow!(
+                    "Partition '{}' cannot be readonly: the flag is only supported for data partitions other than ota and coredump",
+                    partition.name()
+                ));
+            }
+
+            let alignment = Self::alignment_for(partition.ty());
+            if flags.contains(Flags::ENCRYPTED)
+                && (!partition.offset().is_multiple_of(alignment)
+                    || !partition.size().is_multiple_of(DATA_ALIGNMENT))
+            {
+                return Err(anyhow!(
+                    "Encrypted partition '{}' at 0x{:X} ({} bytes) must start on a {}KB boundary and span whole 4KB sectors",
+                    partition.name(),
+                    partition.offset(),
+                    partition.size(),
+                    alignment / 1024
+                ));
+            }
+        }
+
         // Check for overlapping partitions
-        let mut partitions: Vec<_> = table.partitions().into_iter().collect();
+        let mut partitions: Vec<_> = table.partitions().iter().collect();
         partitions.sort_by_key(|p| p.offset());
 
         for window in partitions.windows(2) {
             let current = window[0];
             let next = window[1];
 
-            if current.offset() + current.size() > next.offset() {
+            if current.offset() as u64 + current.size() as u64 > next.offset() as u64 {
                 return Err(anyhow!(
                     "Partition '{}' overlaps with partition '{}'",
                     current.name(),
@@ -177,8 +721,276 @@ impl PartitionGenerator {
         Ok(())
     }
 
-    fn align_up(size: u32, alignment: u32) -> u32 {
-        ((size + alignment - 1) / alignment) * alignment
+    /// Size of the region between the bootloader offset and the partition table,
+    /// checking that the actual bootloader image fits into it
+    fn bootloader_region(firmwares: &[FirmwareBinary], partition_table_offset: u32) -> Result<u32> {
+        if !partition_table_offset.is_multiple_of(DATA_ALIGNMENT)
+            || partition_table_offset <= BOOTLOADER_OFFSET
+        {
+            return Err(anyhow!(
+                "Invalid partition table offset 0x{:X} (must be 4KB aligned and above the bootloader offset 0x{:X})",
+                partition_table_offset,
+                BOOTLOADER_OFFSET
+            ));
+        }
+
+        let region = partition_table_offset - BOOTLOADER_OFFSET;
+        if let Some(bootloader) = firmwares.first()
+            && bootloader.size > region
+        {
+            return Err(anyhow!(
+                "Bootloader '{}' ({} bytes at 0x{:X}) overflows the partition table at 0x{:X} by {} bytes; increase --partition-table-offset",
+                bootloader.name,
+                bootloader.size,
+                BOOTLOADER_OFFSET,
+                partition_table_offset,
+                bootloader.size - region
+            ));
+        }
+
+        Ok(region)
+    }
+
+    /// Offset alignment ESP-IDF requires for a partition type
+    pub(crate) fn alignment_for(ty: Type) -> u32 {
+        match ty {
+            Type::App => OTA_ALIGNMENT,
+            _ => DATA_ALIGNMENT,
+        }
+    }
+
+    /// Size of a user-defined partition: the configured size, or its content rounded up
+    fn spec_size(spec: &PartitionSpec, alignment: u32) -> Result<u32> {
+        let content_size = match &spec.file {
+            Some(file) => Some(
+                std::fs::metadata(file)
+                    .map_err(|e| {
+                        anyhow!(
+                            "Failed to read content {:?} for partition '{}': {}",
+                            file,
+                            spec.name,
+                            e
+                        )
+                    })?
+                    .len() as u32,
+            ),
+            None => None,
+        };
+
+        match (spec.size, content_size) {
+            (Some(size), _) if size == 0 || !size.is_multiple_of(DATA_ALIGNMENT) => Err(anyhow!(
+                "Size of partition '{}' (0x{:X}) must be a non-zero multiple of 4KB",
+                spec.name,
+                size
+            )),
+            (Some(size), Some(content_size)) if content_size > size => Err(anyhow!(
+                "Content for partition '{}' ({} bytes) exceeds its size ({} bytes)",
+                spec.name,
+                content_size,
+                size
+            )),
+            (Some(size), _) => Ok(size),
+            (None, Some(content_size)) if content_size > 0 => {
+                Ok(Self::align_up(content_size, alignment))
+            }
+            _ => Err(anyhow!(
+                "Partition '{}' needs a size or a non-empty content file",
+                spec.name
+            )),
+        }
+    }
+
+    /// Map an OTA slot index to its ESP-IDF app subtype (ota_0..ota_15)
+    fn ota_app_type(index: usize) -> Result<AppType> {
+        if index >= MAX_OTA_SLOTS {
+            return Err(anyhow!(
+                "OTA slot {} exceeds the ESP-IDF limit of {} OTA partitions",
+                index,
+                MAX_OTA_SLOTS
+            ));
+        }
+
+        AppType::from_repr(AppType::Ota_0 as usize + index)
+            .ok_or_else(|| anyhow!("Invalid OTA slot index: {}", index))
+    }
+
+    pub(crate) fn align_up(size: u32, alignment: u32) -> u32 {
+        size.div_ceil(alignment) * alignment
+    }
+}
+
+/// A partition waiting for its place in flash
+struct PartitionRequest {
+    name: String,
+    ty: Type,
+    subtype: SubType,
+    size: u32,
+    /// Fixed offset from the partitions file
+    offset: Option<u32>,
+    flags: Flags,
+    /// Space kept after the partition start before the next unpinned partition
+    min_span: u32,
+    /// Name and size of the firmware stored in the partition
+    firmware: Option<(String, u32)>,
+}
+
+impl PartitionRequest {
+    fn new(name: &str, ty: Type, subtype: SubType, size: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            ty,
+            subtype,
+            size,
+            offset: None,
+            flags: Flags::empty(),
+            min_span: 0,
+            firmware: None,
+        }
+    }
+
+    /// Apply the offset, size and flags of a partitions file entry
+    fn pin(&mut self, spec: &PartitionSpec) -> Result<()> {
+        if let Some(size) = spec.size {
+            if size == 0 || !size.is_multiple_of(DATA_ALIGNMENT) {
+                return Err(anyhow!(
+                    "Pinned size of partition '{}' (0x{:X}) must be a non-zero multiple of 4KB",
+                    self.name,
+                    size
+                ));
+            }
+            if let Some((firmware, firmware_size)) = &self.firmware
+                && *firmware_size > size
+            {
+                return Err(anyhow!(
+                    "Firmware '{}' ({} bytes) outgrows its pinned partition '{}' ({} bytes) by {} bytes; shrink the firmware or move the pin in the partitions file",
+                    firmware,
+                    firmware_size,
+                    self.name,
+                    size,
+                    firmware_size - size
+                ));
+            }
+            self.size = size;
+            self.min_span = 0;
+        }
+
+        self.offset = spec.offset.or(self.offset);
+        self.flags |= spec.flags;
+        Ok(())
+    }
+}
+
+/// Regions kept from a lock file that aren't carried by a partition request
+#[derive(Default)]
+struct LockedLayout {
+    /// Locked partitions that are no longer generated
+    reserved: Vec<(String, u32, u32)>,
+    /// End of the highest locked partition; new partitions go after it
+    end: u32,
+    /// Locked start of the `--fill-partition`
+    fill_offset: Option<u32>,
+}
+
+/// Tracks occupied flash ranges while partitions are placed
+struct FlashAllocator {
+    flash_size: u32,
+    /// Start, end and name of each occupied range
+    used: Vec<(u32, u32, String)>,
+}
+
+impl FlashAllocator {
+    fn new(flash_size: u32) -> Self {
+        Self {
+            flash_size,
+            used: Vec::new(),
+        }
+    }
+
+    /// Claim a fixed range, failing if it leaves the flash or overlaps another partition
+    fn reserve(&mut self, name: &str, offset: u32, size: u32) -> Result<()> {
+        let end = offset as u64 + size as u64;
+        if end > self.flash_size as u64 {
+            return Err(anyhow!(
+                "Pinned partition '{}' at 0x{:X} ({} bytes) extends beyond flash size 0x{:X}",
+                name,
+                offset,
+                size,
+                self.flash_size
+            ));
+        }
+
+        let end = end as u32;
+        if let Some((other_start, other_end, other)) = self
+            .used
+            .iter()
+            .find(|(start, used_end, _)| offset < *used_end && *start < end)
+        {
+            return Err(anyhow!(
+                "Pinned partition '{}' (0x{:X}-0x{:X}) overlaps '{}' (0x{:X}-0x{:X})",
+                name,
+                offset,
+                end,
+                other,
+                other_start,
+                other_end
+            ));
+        }
+
+        self.used.push((offset, end, name.to_string()));
+        Ok(())
+    }
+
+    /// Place a partition at the first aligned free range at or after `from`
+    fn allocate(&mut self, name: &str, from: u32, size: u32, alignment: u32) -> Result<u32> {
+        let mut offset = PartitionGenerator::align_up(from, alignment);
+
+        while offset as u64 + size as u64 <= self.flash_size as u64 {
+            let end = offset + size;
+            let blocking_end = self
+                .used
+                .iter()
+                .filter(|(start, used_end, _)| offset < *used_end && *start < end)
+                .map(|(_, used_end, _)| *used_end)
+                .max();
+
+            match blocking_end {
+                Some(blocking_end) => {
+                    offset = PartitionGenerator::align_up(blocking_end, alignment)
+                }
+                None => {
+                    self.used.push((offset, end, name.to_string()));
+                    return Ok(offset);
+                }
+            }
+        }
+
+        Err(anyhow!(
+            "Not enough flash space for partition '{}' ({} bytes needed, largest free region after 0x{:X} is {} bytes)",
+            name,
+            size,
+            from,
+            self.largest_free_after(from)
+        ))
+    }
+
+    fn largest_free_after(&self, from: u32) -> u32 {
+        let mut ranges: Vec<(u32, u32)> = self.used.iter().map(|(s, e, _)| (*s, *e)).collect();
+        ranges.sort_unstable();
+
+        let mut cursor = from;
+        let mut largest = 0;
+        for (start, end) in ranges {
+            if start > cursor {
+                largest = largest.max(start - cursor);
+            }
+            cursor = cursor.max(end);
+        }
+        largest.max(self.flash_size.saturating_sub(cursor))
+    }
+
+    /// End of the highest occupied range
+    fn end(&self) -> u32 {
+        self.used.iter().map(|(_, end, _)| *end).max().unwrap_or(0)
     }
 }
 
@@ -186,15 +998,18 @@ impl PartitionGenerator {
 mod tests {
     use super::*;
     use crate::config::FlashSize;
-    use std::path::PathBuf;
+    use crate::test_support::create_test_firmware;
 
-    fn create_test_firmware(name: &str, size: u32, prefix: u32) -> FirmwareBinary {
-        FirmwareBinary::new(
-            name.to_string(),
-            PathBuf::from(format!("{}.bin", name)),
-            vec![0; size as usize],
-            prefix,
-        )
+    fn spec(name: &str, ty: &str, subtype: &str, size: Option<u32>) -> PartitionSpec {
+        PartitionSpec {
+            name: name.to_string(),
+            ty: Some(crate::config::parse_partition_type(ty).unwrap()),
+            subtype: Some(subtype.to_string()),
+            offset: None,
+            size,
+            file: None,
+            flags: Flags::empty(),
+        }
     }
 
     #[test]
@@ -226,7 +1041,7 @@ mod tests {
         // Should have bootloader, partition-table, nvs, otadata, factory
         assert_eq!(table.partitions().len(), 5);
 
-        let partition_names: Vec<_> = table.partitions().into_iter().map(|p| p.name()).collect();
+        let partition_names: Vec<_> = table.partitions().iter().map(|p| p.name()).collect();
         assert!(partition_names.iter().any(|s| s == "bootloader"));
         assert!(partition_names.iter().any(|s| s == "partition-table"));
         assert!(partition_names.iter().any(|s| s == "nvs"));
@@ -256,7 +1071,7 @@ mod tests {
         // Should have bootloader, partition-table, nvs, otadata, factory, ota_0, ota_1
         assert_eq!(table.partitions().len(), 7);
 
-        let partition_names: Vec<_> = table.partitions().into_iter().map(|p| p.name()).collect();
+        let partition_names: Vec<_> = table.partitions().iter().map(|p| p.name()).collect();
         assert!(partition_names.iter().any(|s| s == "ota_0"));
         assert!(partition_names.iter().any(|s| s == "ota_1"));
 
@@ -305,4 +1120,909 @@ mod tests {
         // This should work but won't have a factory partition
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_reserved_ota_slots() -> Result<()> {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 500 * 1024, 2),
+            create_test_firmware("ota_app_1", 800 * 1024, 3),
+            create_test_firmware("ota_app_2", 1_200 * 1024, 4),
+        ];
+
+        let config = Config {
+            flash_size: FlashSize::Size16MB,
+            max_ota_partitions: 4,
+            extra_ota_slots: 2,
+            extra_ota_size: 2 * 1024 * 1024,
+            ..Default::default()
+        };
+
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+
+        let ota_1 = table.find("ota_1").unwrap();
+        let ota_2 = table.find("ota_2").unwrap();
+        let ota_3 = table.find("ota_3").unwrap();
+        assert_eq!(ota_2.offset(), ota_1.offset() + ota_1.size());
+        assert_eq!(ota_2.size(), 2 * 1024 * 1024);
+        assert_eq!(ota_3.offset(), ota_2.offset() + ota_2.size());
+        assert_eq!(ota_3.subtype(), SubType::App(AppType::Ota_3));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_too_many_ota_firmwares() {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 500 * 1024, 2),
+            create_test_firmware("ota_app_1", 300 * 1024, 3),
+            create_test_firmware("ota_app_2", 300 * 1024, 4),
+        ];
+
+        let config = Config {
+            max_ota_partitions: 1,
+            ..Default::default()
+        };
+
+        let result = PartitionGenerator::generate_table(&firmwares, &config);
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("2 OTA firmwares found but only 1 OTA partitions are allowed")
+        );
+    }
+
+    #[test]
+    fn test_reserved_ota_slots_exceed_limit() {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 500 * 1024, 2),
+            create_test_firmware("ota_app_1", 300 * 1024, 3),
+        ];
+
+        let config = Config {
+            max_ota_partitions: 2,
+            extra_ota_slots: 2,
+            extra_ota_size: 1024 * 1024,
+            ..Default::default()
+        };
+
+        let result = PartitionGenerator::generate_table(&firmwares, &config);
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("reserved OTA slots")
+        );
+    }
+
+    #[test]
+    fn test_fill_partition_consumes_remaining_flash() -> Result<()> {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 500 * 1024, 2),
+            create_test_firmware("ota_app_1", 800 * 1024, 3),
+        ];
+
+        for flash_size in [FlashSize::Size8MB, FlashSize::Size16MB] {
+            let config = Config {
+                flash_size,
+                fill_partition: Some("storage:littlefs".parse()?),
+                ..Default::default()
+            };
+
+            let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+            let ota_0 = table.find("ota_0").unwrap();
+            let storage = table.find("storage").unwrap();
+
+            assert_eq!(storage.subtype(), SubType::Data(DataType::Littlefs));
+            assert_eq!(storage.offset(), ota_0.offset() + ota_0.size());
+            assert!(storage.offset().is_multiple_of(DATA_ALIGNMENT));
+            assert_eq!(storage.offset() + storage.size(), flash_size.size_bytes());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fill_partition_without_space() {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 500 * 1024, 2),
+        ];
+
+        let config = Config {
+            flash_size: FlashSize::Size8MB,
+            extra_ota_slots: 1,
+            extra_ota_size: 8 * 1024 * 1024 - (FACTORY_OFFSET + FACTORY_SIZE),
+            fill_partition: Some("storage:fat".parse().unwrap()),
+            ..Default::default()
+        };
+
+        let result = PartitionGenerator::generate_table(&firmwares, &config);
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("No flash space left for data partition 'storage'")
+        );
+    }
+
+    #[test]
+    fn test_default_layout_offsets() -> Result<()> {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 500 * 1024, 2),
+        ];
+
+        let table = PartitionGenerator::generate_table(&firmwares, &Config::default())?;
+
+        assert_eq!(
+            table.find("partition-table").unwrap().offset(),
+            PARTITION_TABLE_OFFSET
+        );
+        assert_eq!(table.find("nvs").unwrap().offset(), 0x11000);
+        assert_eq!(table.find("nvs").unwrap().size(), 0x6000);
+        assert_eq!(table.find("otadata").unwrap().offset(), 0x17000);
+        assert_eq!(table.find("factory").unwrap().offset(), FACTORY_OFFSET);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_moved_partition_table_offset() -> Result<()> {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 80 * 1024, 1),
+            create_test_firmware("factory_app", 500 * 1024, 2),
+            create_test_firmware("ota_app_1", 800 * 1024, 3),
+        ];
+
+        let config = Config {
+            partition_table_offset: 0x20000,
+            ..Default::default()
+        };
+
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+
+        assert_eq!(
+            table.find("bootloader").unwrap().size(),
+            0x20000 - BOOTLOADER_OFFSET
+        );
+        assert_eq!(table.find("partition-table").unwrap().offset(), 0x20000);
+        assert_eq!(table.find("nvs").unwrap().offset(), 0x21000);
+        assert_eq!(table.find("otadata").unwrap().offset(), 0x27000);
+        assert_eq!(table.find("factory").unwrap().offset(), 0x30000);
+        assert_eq!(
+            table.find("ota_0").unwrap().offset(),
+            0x30000 + FACTORY_SIZE
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bootloader_overflows_partition_table() {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 0xE000 + 0x100, 1),
+            create_test_firmware("factory_app", 500 * 1024, 2),
+        ];
+
+        let result = PartitionGenerator::generate_table(&firmwares, &Config::default());
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("overflows the partition table at 0x10000 by 256 bytes"));
+    }
+
+    #[test]
+    fn test_invalid_partition_table_offset() {
+        let config = Config {
+            partition_table_offset: 0x10800,
+            ..Default::default()
+        };
+
+        let result = PartitionGenerator::generate_table(&[], &config);
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("Invalid partition table offset 0x10800")
+        );
+    }
+
+    #[test]
+    fn test_custom_partitions() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let test_image = temp_dir.path().join("factory-test.bin");
+        std::fs::write(&test_image, vec![0xE9; 100 * 1024])?;
+
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 500 * 1024, 2),
+        ];
+
+        let config = Config {
+            partitions: vec![
+                spec("calib", "0x40", "0x01", Some(8 * 1024)),
+                PartitionSpec {
+                    file: Some(test_image),
+                    ..spec("test", "app", "test", None)
+                },
+            ],
+            fill_partition: Some("storage:fat".parse()?),
+            ..Default::default()
+        };
+
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+
+        let calib = table.find("calib").unwrap();
+        assert_eq!(calib.ty(), Type::Custom(0x40));
+        assert_eq!(calib.subtype(), SubType::Custom(0x01));
+        assert_eq!(calib.size(), 8 * 1024);
+
+        let test = table.find("test").unwrap();
+        assert_eq!(test.subtype(), SubType::App(AppType::Test));
+        assert_eq!(test.offset() % OTA_ALIGNMENT, 0);
+        assert_eq!(test.size(), 128 * 1024);
+
+        // Fill partition stays last
+        let storage = table.find("storage").unwrap();
+        assert_eq!(storage.offset(), test.offset() + test.size());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_partition_name_checks() {
+        let too_long = Config {
+            partitions: vec![spec("a_very_long_partition", "data", "nvs", Some(4096))],
+            ..Default::default()
+        };
+        let result = PartitionGenerator::generate_table(&[], &too_long);
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("1 to 16 characters")
+        );
+
+        let duplicate = Config {
+            partitions: vec![spec("nvs", "data", "nvs", Some(4096))],
+            ..Default::default()
+        };
+        let result = PartitionGenerator::generate_table(&[], &duplicate);
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("Duplicate partition name 'nvs'")
+        );
+    }
+
+    #[test]
+    fn test_custom_partition_subtype_conflict() {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 500 * 1024, 2),
+            create_test_firmware("ota_app_1", 300 * 1024, 3),
+        ];
+
+        let config = Config {
+            partitions: vec![spec("extra", "app", "ota_0", Some(1024 * 1024))],
+            ..Default::default()
+        };
+
+        let result = PartitionGenerator::generate_table(&firmwares, &config);
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("'ota_0' and 'extra' both use subtype 'ota_0'")
+        );
+    }
+
+    #[test]
+    fn test_custom_partition_requires_size() {
+        let config = Config {
+            partitions: vec![spec("calib", "0x40", "0x00", None)],
+            ..Default::default()
+        };
+        let result = PartitionGenerator::generate_table(&[], &config);
+        assert!(result.unwrap_err().to_string().contains("needs a size"));
+
+        let config = Config {
+            partitions: vec![spec("calib", "0x40", "0x00", Some(1000))],
+            ..Default::default()
+        };
+        let result = PartitionGenerator::generate_table(&[], &config);
+        assert!(result.unwrap_err().to_string().contains("multiple of 4KB"));
+    }
+
+    fn flags_override(name: &str, flags: Flags) -> PartitionSpec {
+        PartitionSpec {
+            name: name.to_string(),
+            ty: None,
+            subtype: None,
+            offset: None,
+            size: None,
+            file: None,
+            flags,
+        }
+    }
+
+    #[test]
+    fn test_partition_flags() -> Result<()> {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 500 * 1024, 2),
+            create_test_firmware("ota_app_1", 300 * 1024, 3),
+        ];
+
+        let config = Config {
+            partitions: vec![
+                PartitionSpec {
+                    flags: Flags::ENCRYPTED,
+                    ..spec("nvs_keys", "data", "nvs_keys", Some(4096))
+                },
+                PartitionSpec {
+                    flags: Flags::READONLY,
+                    ..spec("factory_nvs", "data", "nvs", Some(0x6000))
+                },
+                flags_override("ota_0", Flags::ENCRYPTED),
+                flags_override("factory", Flags::ENCRYPTED),
+            ],
+            ..Default::default()
+        };
+
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+
+        assert_eq!(table.find("nvs_keys").unwrap().flags(), Flags::ENCRYPTED);
+        assert_eq!(table.find("factory_nvs").unwrap().flags(), Flags::READONLY);
+        assert_eq!(table.find("ota_0").unwrap().flags(), Flags::ENCRYPTED);
+        assert_eq!(table.find("factory").unwrap().flags(), Flags::ENCRYPTED);
+        assert_eq!(table.find("nvs").unwrap().flags(), Flags::empty());
+
+        let csv = table.to_csv()?;
+        assert!(csv.contains("nvs_keys,data,nvs_keys,0x"));
+        assert!(csv.contains(",encrypted\n") || csv.contains(",encrypted\r\n"));
+        assert!(csv.contains(",readonly"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_partition_flags() {
+        let readonly_otadata = Config {
+            partitions: vec![flags_override("otadata", Flags::READONLY)],
+            ..Default::default()
+        };
+        let result = PartitionGenerator::generate_table(&[], &readonly_otadata);
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("'otadata' cannot be readonly")
+        );
+
+        let unknown = Config {
+            partitions: vec![flags_override("ota_7", Flags::ENCRYPTED)],
+            ..Default::default()
+        };
+        let result = PartitionGenerator::generate_table(&[], &unknown);
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("not a generated partition")
+        );
+    }
+
+    fn pin(name: &str, offset: Option<u32>, size: Option<u32>) -> PartitionSpec {
+        PartitionSpec {
+            offset,
+            size,
+            ..flags_override(name, Flags::empty())
+        }
+    }
+
+    #[test]
+    fn test_pinned_partition_keeps_offset() -> Result<()> {
+        let config = Config {
+            partitions: vec![
+                pin("ota_1", Some(0x400000), Some(1024 * 1024)),
+                spec("calib", "data", "0x40", Some(8 * 1024)),
+            ],
+            ..Default::default()
+        };
+        let firmwares = |ota_0_size| {
+            vec![
+                create_test_firmware("bootloader", 32 * 1024, 1),
+                create_test_firmware("factory_app", 500 * 1024, 2),
+                create_test_firmware("ota_app_1", ota_0_size, 3),
+                create_test_firmware("ota_app_2", 300 * 1024, 4),
+            ]
+        };
+
+        let table = PartitionGenerator::generate_table(&firmwares(300 * 1024), &config)?;
+        let ota_1 = table.find("ota_1").unwrap();
+        assert_eq!(ota_1.offset(), 0x400000);
+        assert_eq!(ota_1.size(), 1024 * 1024);
+        assert_eq!(table.find("ota_0").unwrap().offset(), 0x120000);
+        // The data partition fills the gap in front of the pinned slot
+        assert_eq!(table.find("calib").unwrap().offset(), 0x170000);
+
+        // A grown ota_0 no longer fits in front of ota_1 and moves past it
+        let table = PartitionGenerator::generate_table(&firmwares(3 * 1024 * 1024), &config)?;
+        assert_eq!(table.find("ota_1").unwrap().offset(), 0x400000);
+        assert_eq!(table.find("ota_0").unwrap().offset(), 0x500000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_firmware_outgrows_pinned_slot() {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 500 * 1024, 2),
+            create_test_firmware("ota_app_1", 1536 * 1024, 3),
+        ];
+
+        let config = Config {
+            partitions: vec![pin("ota_0", Some(0x120000), Some(1024 * 1024))],
+            ..Default::default()
+        };
+
+        let err = PartitionGenerator::generate_table(&firmwares, &config)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Firmware 'ota_app_1'"));
+        assert!(err.contains("outgrows its pinned partition 'ota_0'"));
+        assert!(err.contains("by 524288 bytes"));
+    }
+
+    #[test]
+    fn test_invalid_pins() {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 500 * 1024, 2),
+            create_test_firmware("ota_app_1", 300 * 1024, 3),
+        ];
+
+        let overlapping = Config {
+            partitions: vec![
+                pin("ota_0", Some(0x400000), Some(1024 * 1024)),
+                PartitionSpec {
+                    offset: Some(0x480000),
+                    ..spec("calib", "data", "0x40", Some(8 * 1024))
+                },
+            ],
+            ..Default::default()
+        };
+        let err = PartitionGenerator::generate_table(&firmwares, &overlapping)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("overlaps 'ota_0'"));
+
+        let misaligned = Config {
+            partitions: vec![pin("ota_0", Some(0x401000), None)],
+            ..Default::default()
+        };
+        let err = PartitionGenerator::generate_table(&firmwares, &misaligned)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("not aligned"));
+
+        let in_bootloader = Config {
+            partitions: vec![pin("nvs", Some(0x9000), None)],
+            ..Default::default()
+        };
+        let err = PartitionGenerator::generate_table(&firmwares, &in_bootloader)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("overlaps 'bootloader'"));
+    }
+
+    #[test]
+    fn test_layout_lock_keeps_offsets() -> Result<()> {
+        let firmwares = |ota_sizes: &[u32]| {
+            let mut firmwares = vec![
+                create_test_firmware("bootloader", 32 * 1024, 1),
+                create_test_firmware("factory_app", 500 * 1024, 2),
+            ];
+            for (i, size) in ota_sizes.iter().enumerate() {
+                firmwares.push(create_test_firmware(
+                    &format!("ota_app_{}", i + 1),
+                    *size as usize,
+                    i as u32 + 3,
+                ));
+            }
+            firmwares
+        };
+
+        let first = PartitionGenerator::generate_table(
+            &firmwares(&[300 * 1024, 600 * 1024]),
+            &Config::default(),
+        )?;
+        let config = Config {
+            layout_lock: Some(LayoutLock::from_table(&first, PARTITION_TABLE_OFFSET)),
+            ..Default::default()
+        };
+
+        // Shrinking ota_0 and adding ota_2 keeps every locked partition in place
+        let second = PartitionGenerator::generate_table(
+            &firmwares(&[200 * 1024, 600 * 1024, 100 * 1024]),
+            &config,
+        )?;
+        for name in ["nvs", "otadata", "factory", "ota_0", "ota_1"] {
+            let (a, b) = (first.find(name).unwrap(), second.find(name).unwrap());
+            assert_eq!((a.offset(), a.size()), (b.offset(), b.size()), "{}", name);
+        }
+        let ota_1 = first.find("ota_1").unwrap();
+        assert_eq!(
+            second.find("ota_2").unwrap().offset(),
+            ota_1.offset() + ota_1.size()
+        );
+
+        // A locked fill partition keeps its start, leaving no room for ota_2
+        let fill_config = Config {
+            fill_partition: Some("storage:fat".parse()?),
+            ..Default::default()
+        };
+        let filled = PartitionGenerator::generate_table(
+            &firmwares(&[300 * 1024, 600 * 1024]),
+            &fill_config,
+        )?;
+        let fill_config = Config {
+            layout_lock: Some(LayoutLock::from_table(&filled, PARTITION_TABLE_OFFSET)),
+            ..fill_config
+        };
+        let result = PartitionGenerator::generate_table(
+            &firmwares(&[300 * 1024, 600 * 1024, 100 * 1024]),
+            &fill_config,
+        );
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("Not enough flash space")
+        );
+
+        // Every firmware that outgrew its slot is reported
+        let err =
+            PartitionGenerator::generate_table(&firmwares(&[400 * 1024, 700 * 1024]), &config)
+                .unwrap_err()
+                .to_string();
+        assert!(err.contains("--relayout"));
+        assert!(
+            err.contains("firmware 'ota_app_1' (409600 bytes) no longer fits locked slot 'ota_0'")
+        );
+        assert!(
+            err.contains("firmware 'ota_app_2' (716800 bytes) no longer fits locked slot 'ota_1'")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layout_lock_partition_table_offset_mismatch() -> Result<()> {
+        let firmwares = vec![create_test_firmware("bootloader", 32 * 1024, 1)];
+        let table = PartitionGenerator::generate_table(&firmwares, &Config::default())?;
+
+        let config = Config {
+            partition_table_offset: 0x18000,
+            layout_lock: Some(LayoutLock::from_table(&table, 0x10000)),
+            ..Default::default()
+        };
+        let result = PartitionGenerator::generate_table(&firmwares, &config);
+        assert!(result.unwrap_err().to_string().contains("--relayout"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_packed_allocation_fills_gaps() -> Result<()> {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 500 * 1024, 2),
+            create_test_firmware("ota_app_1", 300 * 1024, 3),
+            create_test_firmware("ota_app_2", 700 * 1024, 4),
+        ];
+        let partitions = vec![
+            spec("coredump", "data", "coredump", Some(64 * 1024)),
+            spec("phy_init", "data", "phy", Some(4 * 1024)),
+        ];
+
+        let sequential = PartitionGenerator::generate_table(
+            &firmwares,
+            &Config {
+                partitions: partitions.clone(),
+                ..Default::default()
+            },
+        )?;
+        let packed = PartitionGenerator::generate_table(
+            &firmwares,
+            &Config {
+                allocation: AllocationStrategy::Packed,
+                partitions,
+                ..Default::default()
+            },
+        )?;
+
+        // Small data partitions land in the gap before the first app slot
+        for name in ["nvs", "otadata", "phy_init"] {
+            assert!(packed.find(name).unwrap().offset() < 0x20000, "{}", name);
+        }
+        // The factory slot and its growth room first, then the largest app and
+        // the rest packed behind it without gaps
+        assert_eq!(packed.find("factory").unwrap().offset(), 0x20000);
+        assert_eq!(
+            packed.find("ota_1").unwrap().offset(),
+            0x20000 + FACTORY_SIZE
+        );
+        assert_eq!(packed.find("ota_0").unwrap().offset(), 0x1D0000);
+        assert_eq!(packed.find("coredump").unwrap().offset(), 0x220000);
+
+        let offsets: Vec<u32> = packed.partitions().iter().map(|p| p.offset()).collect();
+        assert!(offsets.is_sorted());
+
+        let sequential_waste =
+            PartitionGenerator::wasted_bytes(&sequential, PARTITION_TABLE_OFFSET);
+        let packed_waste = PartitionGenerator::wasted_bytes(&packed, PARTITION_TABLE_OFFSET);
+        let growth_room = FACTORY_SIZE - packed.find("factory").unwrap().size();
+        assert_eq!(packed_waste, 0x20000 - 0x1A000 + growth_room);
+        assert!(packed_waste < sequential_waste);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_packed_allocation_appends_after_lock() -> Result<()> {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 500 * 1024, 2),
+            create_test_firmware("ota_app_1", 300 * 1024, 3),
+        ];
+        let packed = Config {
+            allocation: AllocationStrategy::Packed,
+            ..Default::default()
+        };
+        let first = PartitionGenerator::generate_table(&firmwares, &packed)?;
+
+        // Without a lock the new partition would land in a gap before the apps
+        let config = Config {
+            partitions: vec![spec("phy_init", "data", "phy", Some(4 * 1024))],
+            layout_lock: Some(LayoutLock::from_table(&first, PARTITION_TABLE_OFFSET)),
+            ..packed
+        };
+        let second = PartitionGenerator::generate_table(&firmwares, &config)?;
+
+        for partition in first.partitions() {
+            let locked = second.find(&partition.name()).unwrap();
+            assert_eq!(
+                (locked.offset(), locked.size()),
+                (partition.offset(), partition.size())
+            );
+        }
+        let locked_end = first
+            .partitions()
+            .iter()
+            .map(|p| p.offset() + p.size())
+            .max()
+            .unwrap();
+        assert_eq!(second.find("phy_init").unwrap().offset(), locked_end);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ab_layout() -> Result<()> {
+        let config = Config {
+            layout: LayoutPreset::Ab,
+            ..Default::default()
+        };
+
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("app_a", 1024 * 1024, 2),
+            create_test_firmware("app_b", 1536 * 1024, 3),
+        ];
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+        assert!(table.find("factory").is_none());
+        assert!(table.find("otadata").is_some());
+        let ota_0 = table.find("ota_0").unwrap();
+        let ota_1 = table.find("ota_1").unwrap();
+        assert_eq!(ota_0.offset(), 0x20000);
+        assert_eq!((ota_0.size(), ota_1.size()), (0x180000, 0x180000));
+
+        let assignments = PartitionGenerator::app_assignments(&firmwares, LayoutPreset::Ab);
+        assert_eq!(assignments[0].0, "ota_0");
+        assert_eq!(assignments[0].1.name, "app_a");
+
+        // A single app still gets both slots, sized for future updates
+        let table = PartitionGenerator::generate_table(&firmwares[..2], &config)?;
+        assert_eq!(table.find("ota_1").unwrap().size(), DEFAULT_OTA_SIZE);
+        assert_eq!(table.find("ota_0").unwrap().size(), DEFAULT_OTA_SIZE);
+
+        let mut too_many = firmwares.clone();
+        too_many.push(create_test_firmware("app_c", 100 * 1024, 4));
+        let result = PartitionGenerator::generate_table(&too_many, &config);
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("Layout 'ab' has 2 OTA slots")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_launcher_layout() -> Result<()> {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("menu", 200 * 1024, 2),
+            create_test_firmware("game_1", 300 * 1024, 3),
+            create_test_firmware("game_2", 900 * 1024, 4),
+            create_test_firmware("game_3", 100 * 1024, 5),
+        ];
+        let config = Config {
+            layout: LayoutPreset::Launcher,
+            ..Default::default()
+        };
+
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+        let factory = table.find("factory").unwrap();
+        assert_eq!(factory.size(), 200 * 1024);
+        // No growth room behind the menu and every game slot has the same size
+        assert_eq!(
+            table.find("ota_0").unwrap().offset(),
+            PartitionGenerator::align_up(factory.offset() + factory.size(), OTA_ALIGNMENT)
+        );
+        for name in ["ota_0", "ota_1", "ota_2"] {
+            assert_eq!(table.find(name).unwrap().size(), 960 * 1024, "{}", name);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_minimal_layout() -> Result<()> {
+        let config = Config {
+            layout: LayoutPreset::Minimal,
+            ..Default::default()
+        };
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("app", 700 * 1024, 2),
+        ];
+
+        let table = PartitionGenerator::generate_table(&firmwares, &config)?;
+        assert!(table.find("otadata").is_none());
+        assert_eq!(table.find("factory").unwrap().offset(), 0x20000);
+        assert!(table.find("ota_0").is_none());
+
+        let mut two_apps = firmwares.clone();
+        two_apps.push(create_test_firmware("second", 100 * 1024, 3));
+        let result = PartitionGenerator::generate_table(&two_apps, &config);
+        assert!(result.unwrap_err().to_string().contains("single app"));
+
+        let reserved = Config {
+            extra_ota_slots: 1,
+            ..config
+        };
+        assert!(PartitionGenerator::generate_table(&firmwares, &reserved).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_table_file_formats() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("factory_app", 500 * 1024, 2),
+        ];
+        let table = PartitionGenerator::generate_table(&firmwares, &Config::default())?;
+
+        let csv_path = temp_dir.path().join("partitions.csv");
+        std::fs::write(&csv_path, table.to_csv()?)?;
+        let bin_path = temp_dir.path().join("partitions.bin");
+        std::fs::write(&bin_path, binary::to_bytes(&table, true)?)?;
+        let lock_path = temp_dir.path().join("composer.lock");
+        LayoutLock::from_table(&table, 0x10000).save_to_file(&lock_path)?;
+        let image_path = temp_dir.path().join("image.img");
+        let mut image = vec![0xFF; 0x20000];
+        image[0x10000..0x10C00].copy_from_slice(&binary::to_bytes(&table, false)?);
+        std::fs::write(&image_path, &image)?;
+
+        for (path, offset) in [
+            (&csv_path, None),
+            (&bin_path, None),
+            (&lock_path, Some(0x10000)),
+            (&image_path, Some(0x10000)),
+        ] {
+            let (loaded, loaded_offset) = load_table_file(path, 0x10000)?;
+            assert_eq!(loaded.partitions(), table.partitions(), "{:?}", path);
+            assert_eq!(loaded_offset, offset, "{:?}", path);
+        }
+
+        // A corrupted MD5 row is rejected
+        let mut corrupted = binary::to_bytes(&table, true)?;
+        corrupted[4] ^= 0x01;
+        std::fs::write(&bin_path, corrupted)?;
+        assert!(load_table_file(&bin_path, 0x10000).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_template_table() -> Result<()> {
+        let config = Config {
+            extra_ota_slots: 2,
+            ..Default::default()
+        };
+        let table = PartitionGenerator::generate_template(&config)?;
+        let factory = table.find("factory").unwrap();
+        assert_eq!((factory.offset(), factory.size()), (0x20000, FACTORY_SIZE));
+        assert_eq!(table.find("ota_0").unwrap().offset(), 0x120000);
+        assert_eq!(table.find("ota_1").unwrap().size(), DEFAULT_OTA_SIZE);
+
+        let ab = PartitionGenerator::generate_template(&Config {
+            layout: LayoutPreset::Ab,
+            ..Default::default()
+        })?;
+        assert!(ab.find("factory").is_none());
+        assert_eq!(ab.find("ota_0").unwrap().size(), DEFAULT_OTA_SIZE);
+        assert_eq!(ab.find("ota_1").unwrap().size(), DEFAULT_OTA_SIZE);
+
+        // Without the template flag an empty firmware set has no app slots
+        let empty = PartitionGenerator::generate_table(&[], &Config::default())?;
+        assert!(empty.find("factory").is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_launcher_layout_differs_from_factory_ota() -> Result<()> {
+        let firmwares = vec![
+            create_test_firmware("bootloader", 32 * 1024, 1),
+            create_test_firmware("menu", 150 * 1024, 2),
+            create_test_firmware("app_1", 300 * 1024, 3),
+            create_test_firmware("app_2", 900 * 1024, 4),
+        ];
+        let table = |layout| {
+            PartitionGenerator::generate_table(
+                &firmwares,
+                &Config {
+                    layout,
+                    ..Default::default()
+                },
+            )
+        };
+        let factory_ota = table(LayoutPreset::FactoryOta)?;
+        let launcher = table(LayoutPreset::Launcher)?;
+
+        // factory-ota rounds the factory app to 64KB and keeps 1MB for it to grow
+        assert_eq!(factory_ota.find("factory").unwrap().size(), 192 * 1024);
+        assert_eq!(
+            factory_ota.find("ota_0").unwrap().offset(),
+            FACTORY_OFFSET + FACTORY_SIZE
+        );
+        assert_eq!(factory_ota.find("ota_0").unwrap().size(), 320 * 1024);
+
+        // The launcher's menu slot fits the menu to the 4KB sector, and the
+        // uniform app slots follow it
+        assert_eq!(launcher.find("factory").unwrap().size(), 152 * 1024);
+        assert_eq!(launcher.find("ota_0").unwrap().offset(), 0x50000);
+        assert_eq!(launcher.find("ota_0").unwrap().size(), 960 * 1024);
+
+        // Without firmware, the launcher template has a small menu slot
+        let template = PartitionGenerator::generate_template(&Config {
+            layout: LayoutPreset::Launcher,
+            ..Default::default()
+        })?;
+        assert_eq!(template.find("factory").unwrap().size(), LAUNCHER_MENU_SIZE);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,30 @@
+//! Fixtures shared by the unit tests.
+
+use crate::config::PartitionSpec;
+use crate::firmware::FirmwareBinary;
+use std::path::PathBuf;
+
+/// A firmware of `size` bytes starting with the ESP32 image magic.
+pub(crate) fn create_test_firmware(name: &str, size: usize, prefix: u32) -> FirmwareBinary {
+    let mut data: Vec<u8> = (0..size).map(|i| (i % 256) as u8).collect();
+    data[0] = 0xE9; // ESP32 image magic
+    FirmwareBinary::new(
+        name.to_string(),
+        PathBuf::from(format!("{}.bin", name)),
+        data,
+        prefix,
+    )
+}
+
+/// A partitions file entry resizing the generated `nvs` partition.
+pub(crate) fn nvs_spec(size: u32, file: Option<PathBuf>) -> PartitionSpec {
+    PartitionSpec {
+        name: "nvs".to_string(),
+        ty: None,
+        subtype: None,
+        offset: None,
+        size: Some(size),
+        file,
+        flags: esp_idf_part::Flags::empty(),
+    }
+}
@@ -0,0 +1,13 @@
+//! Small helpers shared by the library and the CLI
+
+use sha2::{Digest, Sha256};
+
+/// Lowercase hex of `bytes`, two digits per byte
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hex SHA-256 of `data`
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}